@@ -0,0 +1,285 @@
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use tracing::{info, warn};
+
+use crate::{lifecycle::ConnectorStates, metrics::metrics, shutdown::Shutdown};
+
+pub struct AdminState {
+    pub connector_states: ConnectorStates,
+    pub shutdown: Shutdown,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+/// Starts a minimal HTTP server that answers the admin endpoints on `interface:port`. Returns
+/// the address it listens on, which has the actual port if `port` is `0`.
+pub async fn start_admin_server(
+    interface: &str,
+    port: u16,
+    state: AdminState,
+) -> anyhow::Result<SocketAddr> {
+    let addr: SocketAddrV4 = format!("{}:{}", interface, port).parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+
+    info!("Admin endpoints listening on {}", addr);
+
+    let state = Arc::new(state);
+
+    tokio::spawn(async move {
+        let mut shutdown = state.shutdown.clone();
+
+        loop {
+            let stream = tokio::select! {
+                res = listener.accept() => res,
+                _ = shutdown.recv() => break,
+            };
+
+            match stream {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        let res = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            handle_connection(&state, stream),
+                        )
+                        .await;
+
+                        match res {
+                            Ok(Ok(_)) => {}
+                            Ok(Err(e)) => warn!("Failed to handle admin request: {}", e),
+                            Err(_) => warn!("Admin request timed out"),
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept admin connection: {}", e),
+            }
+        }
+    });
+
+    return Ok(addr);
+}
+
+async fn handle_connection(state: &AdminState, stream: TcpStream) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    // Skip the headers, none of the endpoints need them
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+
+    let response = match (parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(path), Some(_)) => route(state, path),
+        (Some(_), Some(_), Some(_)) => Response {
+            status: 405,
+            content_type: "text/plain",
+            body: "Method Not Allowed\n".to_string(),
+        },
+        _ => Response {
+            status: 400,
+            content_type: "text/plain",
+            body: "Bad Request\n".to_string(),
+        },
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    return Ok(());
+}
+
+fn route(state: &AdminState, path: &str) -> Response {
+    // Ignore query parameters
+    let path = path.split('?').next().unwrap_or_default();
+
+    match path {
+        "/healthz" => {
+            let healthy =
+                !state.shutdown.is_shutdown() && state.connector_states.required_running();
+
+            status_response(state, healthy)
+        }
+        "/readyz" => {
+            let ready = !state.shutdown.is_shutdown() && state.connector_states.required_ready();
+
+            status_response(state, ready)
+        }
+        "/metrics" => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics().render(),
+        },
+        _ => Response {
+            status: 404,
+            content_type: "text/plain",
+            body: "Not Found\n".to_string(),
+        },
+    }
+}
+
+fn status_response(state: &AdminState, ok: bool) -> Response {
+    let body = json!({
+        "status": if ok { "ok" } else { "unavailable" },
+        "shutting_down": state.shutdown.is_shutdown(),
+        "connectors": state.connector_states.all(),
+    });
+
+    Response {
+        status: if ok { 200 } else { 503 },
+        content_type: "application/json",
+        body: format!("{}\n", body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        connector::ConnectorOptions,
+        lifecycle::{LifeCycleHandler, LifeCycleMessage},
+        shutdown::ShutdownHandler,
+    };
+
+    /// Starts the server on a free port for one required connector that isn't ready yet
+    async fn start() -> (SocketAddr, LifeCycleHandler, ShutdownHandler) {
+        let lifecycle = LifeCycleHandler::start(vec![ConnectorOptions::default()]);
+        let shutdown = ShutdownHandler::new();
+
+        let addr = start_admin_server(
+            "127.0.0.1",
+            0,
+            AdminState {
+                connector_states: lifecycle.states.clone(),
+                shutdown: shutdown.subscribe(),
+            },
+        )
+        .await
+        .unwrap();
+
+        (addr, lifecycle, shutdown)
+    }
+
+    /// Sends `request_line` and returns the status and body of the response
+    async fn request(addr: SocketAddr, request_line: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{}\r\nHost: localhost\r\n\r\n", request_line).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+
+        (status, body.to_string())
+    }
+
+    fn json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn healthz_reports_running_connectors() {
+        let (addr, _lifecycle, _shutdown) = start().await;
+
+        // Starting connectors aren't given up, so postoffice is alive
+        let (status, body) = request(addr, "GET /healthz HTTP/1.1").await;
+        assert_eq!(status, 200);
+
+        let body = json(&body);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["shutting_down"], false);
+        assert_eq!(body["connectors"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn readyz_follows_the_connectors() {
+        let (addr, lifecycle, _shutdown) = start().await;
+
+        let (status, body) = request(addr, "GET /readyz HTTP/1.1").await;
+        assert_eq!(status, 503);
+        assert_eq!(json(&body)["status"], "unavailable");
+
+        lifecycle
+            .lifecycle_tx
+            .send(LifeCycleMessage::Ready { idx: 0 })
+            .await
+            .unwrap();
+
+        // The state is updated by the task of the handler
+        let mut status = 0;
+        for _ in 0..50 {
+            (status, _) = request(addr, "GET /readyz?verbose HTTP/1.1").await;
+            if status == 200 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(status, 200);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_rendered() {
+        let (addr, _lifecycle, _shutdown) = start().await;
+
+        let (status, body) = request(addr, "GET /metrics HTTP/1.1").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("# TYPE postoffice_source_messages_total counter"));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_requests() {
+        let (addr, _lifecycle, _shutdown) = start().await;
+
+        assert_eq!(request(addr, "GET /missing HTTP/1.1").await.0, 404);
+        assert_eq!(request(addr, "POST /healthz HTTP/1.1").await.0, 405);
+        assert_eq!(request(addr, "GET").await.0, 400);
+        assert_eq!(request(addr, "").await.0, 400);
+    }
+}
@@ -0,0 +1,77 @@
+use std::{sync::Arc, time::Instant};
+
+use tokio::task::JoinSet;
+
+use postoffice::{InternalMessage, InternalMessageData, Pipeline, block::BlockConfig};
+
+use crate::cli::BenchArgs;
+
+/// Sets `fresh_vm` in the sandbox of every Lua block
+pub fn use_fresh_vms(blocks: &mut [BlockConfig]) {
+    for block in blocks {
+        match block {
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaFilter { sandbox, .. } | BlockConfig::LuaTransform { sandbox, .. } => {
+                sandbox.fresh_vm = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sends `args.messages` messages into the block `args.block` and prints the throughput
+pub async fn run_bench(pipeline: Arc<Pipeline>, args: &BenchArgs) -> anyhow::Result<()> {
+    let message = InternalMessage {
+        source_connector_idx: 0,
+        topic: args.topic.clone(),
+        data: InternalMessageData::Json(serde_json::from_str(&args.data)?),
+        dead_letter: false,
+        hops: 0,
+    };
+
+    let concurrency = args.concurrency.max(1);
+    let mut tasks = JoinSet::new();
+
+    let start = Instant::now();
+
+    for worker in 0..concurrency {
+        let pipeline = pipeline.clone();
+        let message = message.clone();
+        let block = args.block;
+
+        // Spread the remainder over the first workers
+        let count = args.messages / concurrency + usize::from(worker < args.messages % concurrency);
+
+        tasks.spawn(async move {
+            let mut delivered = 0;
+
+            for _ in 0..count {
+                let mut collector = vec![];
+                pipeline
+                    .handle_message(block, message.clone(), &mut collector)
+                    .await?;
+                delivered += collector.len();
+            }
+
+            return anyhow::Ok(delivered);
+        });
+    }
+
+    let mut delivered = 0;
+
+    while let Some(res) = tasks.join_next().await {
+        delivered += res??;
+    }
+
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} messages in {:.3} s ({:.0} messages/s), {} delivered to sinks",
+        args.messages,
+        elapsed.as_secs_f64(),
+        args.messages as f64 / elapsed.as_secs_f64(),
+        delivered
+    );
+
+    return Ok(());
+}
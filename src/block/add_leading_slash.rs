@@ -8,7 +8,10 @@ pub struct AddLeadingSlashBlock {}
 
 #[async_trait]
 impl Block for AddLeadingSlashBlock {
-    async fn exec(self: &Self, mut message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+    async fn exec(
+        self: &Self,
+        mut message: InternalMessage,
+    ) -> anyhow::Result<Vec<InternalMessage>> {
         if !message.topic.starts_with("/") {
             message.topic = format!("/{}", message.topic)
         }
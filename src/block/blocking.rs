@@ -0,0 +1,26 @@
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::Span;
+
+/// Runs a script or plugin without holding up other tasks.
+///
+/// On the multi-threaded runtime it runs in place and the worker hands its other tasks to
+/// another thread. This is much cheaper than moving every run to the blocking thread pool, which
+/// is only done on the current-thread runtime.
+pub async fn run_blocking<R>(
+    run: impl FnOnce() -> anyhow::Result<R> + Send + 'static,
+) -> anyhow::Result<R>
+where
+    R: Send + 'static,
+{
+    // Keeps the message and block of the current span in the logs of the script
+    let span = Span::current();
+    let run = move || {
+        let _span = span.enter();
+        run()
+    };
+
+    return match Handle::current().runtime_flavor() {
+        RuntimeFlavor::MultiThread => tokio::task::block_in_place(run),
+        _ => tokio::task::spawn_blocking(run).await?,
+    };
+}
@@ -20,7 +20,10 @@ pub struct ConvertBodyBlock {
 
 #[async_trait]
 impl Block for ConvertBodyBlock {
-    async fn exec(self: &Self, mut message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+    async fn exec(
+        self: &Self,
+        mut message: InternalMessage,
+    ) -> anyhow::Result<Vec<InternalMessage>> {
         let data = match self.config {
             ConvertBodyConfig::Empty => message.data.to_empty(),
             ConvertBodyConfig::JSON => message.data.to_json(),
@@ -0,0 +1,326 @@
+use bytes::Bytes;
+use mlua::prelude::*;
+use rosc::{OscArray, OscColor, OscMidiMessage, OscTime, OscType};
+
+use crate::message::InternalMessageData;
+
+/// Converts a body to the value of the `data` global
+pub fn data_to_lua(lua: &Lua, data: &InternalMessageData) -> LuaResult<LuaValue> {
+    match data {
+        InternalMessageData::Empty => Ok(LuaValue::Nil),
+        InternalMessageData::String(value) => Ok(LuaValue::String(lua.create_string(value)?)),
+        InternalMessageData::Binary(bytes) => Ok(LuaValue::String(lua.create_string(bytes)?)),
+        InternalMessageData::Json(value) => lua.to_value(value),
+        InternalMessageData::OSC(args) => osc_args_to_lua(lua, args),
+    }
+}
+
+/// Converts the `data` global back to a body of the type `data_type`. `nil` is always `Empty`.
+pub fn data_from_lua(
+    lua: &Lua,
+    value: LuaValue,
+    data_type: &str,
+) -> LuaResult<InternalMessageData> {
+    if value.is_nil() {
+        return Ok(InternalMessageData::Empty);
+    }
+
+    match data_type {
+        "Empty" => Ok(InternalMessageData::Empty),
+        "String" => match value {
+            LuaValue::String(value) => Ok(InternalMessageData::String(value.to_str()?.to_string())),
+            value => Err(type_error("String", &value)),
+        },
+        "Binary" => match value {
+            LuaValue::String(value) => Ok(InternalMessageData::Binary(Bytes::from(
+                value.as_bytes().to_vec(),
+            ))),
+            value => Err(type_error("Binary", &value)),
+        },
+        "JSON" => Ok(InternalMessageData::Json(lua.from_value(value)?)),
+        "OSC" => match value {
+            LuaValue::Table(args) => Ok(InternalMessageData::OSC(osc_args_from_lua(args)?)),
+            value => Err(type_error("OSC", &value)),
+        },
+        data_type => Err(LuaError::runtime(format!(
+            "Unknown data_type {:?}, expected Empty, String, Binary, JSON or OSC",
+            data_type
+        ))),
+    }
+}
+
+fn type_error(data_type: &str, value: &LuaValue) -> LuaError {
+    LuaError::runtime(format!(
+        "data_type is {}, but data is a {}",
+        data_type,
+        value.type_name()
+    ))
+}
+
+/// OSC arguments become a list of tables like `{ type = "Int", value = 1 }`
+fn osc_args_to_lua(lua: &Lua, args: &[OscType]) -> LuaResult<LuaValue> {
+    let table = lua.create_table()?;
+
+    for arg in args {
+        table.push(osc_to_lua(lua, arg)?)?;
+    }
+
+    return Ok(LuaValue::Table(table));
+}
+
+fn osc_to_lua(lua: &Lua, arg: &OscType) -> LuaResult<LuaTable> {
+    let table = lua.create_table()?;
+
+    let (kind, value): (&str, LuaValue) = match arg {
+        OscType::Int(value) => ("Int", value.into_lua(lua)?),
+        OscType::Float(value) => ("Float", value.into_lua(lua)?),
+        OscType::String(value) => ("String", value.as_str().into_lua(lua)?),
+        OscType::Blob(value) => ("Blob", LuaValue::String(lua.create_string(value)?)),
+        OscType::Time(time) => {
+            let value = lua.create_table()?;
+            value.set("seconds", time.seconds)?;
+            value.set("fractional", time.fractional)?;
+            ("Time", LuaValue::Table(value))
+        }
+        OscType::Long(value) => ("Long", value.into_lua(lua)?),
+        OscType::Double(value) => ("Double", value.into_lua(lua)?),
+        OscType::Char(value) => ("Char", value.to_string().into_lua(lua)?),
+        OscType::Color(color) => {
+            let value = lua.create_table()?;
+            value.set("red", color.red)?;
+            value.set("green", color.green)?;
+            value.set("blue", color.blue)?;
+            value.set("alpha", color.alpha)?;
+            ("Color", LuaValue::Table(value))
+        }
+        OscType::Midi(midi) => {
+            let value = lua.create_table()?;
+            value.set("port", midi.port)?;
+            value.set("status", midi.status)?;
+            value.set("data1", midi.data1)?;
+            value.set("data2", midi.data2)?;
+            ("Midi", LuaValue::Table(value))
+        }
+        OscType::Bool(value) => ("Bool", value.into_lua(lua)?),
+        OscType::Array(array) => ("Array", osc_args_to_lua(lua, &array.content)?),
+        OscType::Nil => ("Nil", LuaValue::Nil),
+        OscType::Inf => ("Inf", LuaValue::Nil),
+    };
+
+    table.set("type", kind)?;
+    table.set("value", value)?;
+
+    return Ok(table);
+}
+
+fn osc_args_from_lua(args: LuaTable) -> LuaResult<Vec<OscType>> {
+    args.sequence_values::<LuaValue>()
+        .map(|arg| osc_from_lua(arg?))
+        .collect()
+}
+
+/// Accepts typed tables and, for convenience, plain numbers, strings and booleans
+fn osc_from_lua(arg: LuaValue) -> LuaResult<OscType> {
+    let table = match arg {
+        LuaValue::Table(table) => table,
+        LuaValue::Integer(value) => {
+            return i32::try_from(value).map(OscType::Int).map_err(|_| {
+                LuaError::runtime(format!(
+                    "{} is out of range for an OSC Int, use a Long instead",
+                    value
+                ))
+            });
+        }
+        LuaValue::Number(value) => return Ok(OscType::Float(value as f32)),
+        LuaValue::String(value) => return Ok(OscType::String(value.to_str()?.to_string())),
+        LuaValue::Boolean(value) => return Ok(OscType::Bool(value)),
+        arg => {
+            return Err(LuaError::runtime(format!(
+                "Can't convert a {} to an OSC argument",
+                arg.type_name()
+            )));
+        }
+    };
+
+    let kind: String = table.get("type")?;
+
+    match kind.as_str() {
+        "Int" => Ok(OscType::Int(table.get("value")?)),
+        "Float" => Ok(OscType::Float(table.get("value")?)),
+        "String" => Ok(OscType::String(table.get("value")?)),
+        "Blob" => Ok(OscType::Blob(
+            table.get::<LuaString>("value")?.as_bytes().to_vec(),
+        )),
+        "Time" => {
+            let value: LuaTable = table.get("value")?;
+            Ok(OscType::Time(OscTime {
+                seconds: value.get("seconds")?,
+                fractional: value.get("fractional")?,
+            }))
+        }
+        "Long" => Ok(OscType::Long(table.get("value")?)),
+        "Double" => Ok(OscType::Double(table.get("value")?)),
+        "Char" => {
+            let value: String = table.get("value")?;
+            let mut chars = value.chars();
+
+            match (chars.next(), chars.next()) {
+                (Some(value), None) => Ok(OscType::Char(value)),
+                _ => Err(LuaError::runtime(format!(
+                    "OSC Char must be a single character, got {:?}",
+                    value
+                ))),
+            }
+        }
+        "Color" => {
+            let value: LuaTable = table.get("value")?;
+            Ok(OscType::Color(OscColor {
+                red: value.get("red")?,
+                green: value.get("green")?,
+                blue: value.get("blue")?,
+                alpha: value.get("alpha")?,
+            }))
+        }
+        "Midi" => {
+            let value: LuaTable = table.get("value")?;
+            Ok(OscType::Midi(OscMidiMessage {
+                port: value.get("port")?,
+                status: value.get("status")?,
+                data1: value.get("data1")?,
+                data2: value.get("data2")?,
+            }))
+        }
+        "Bool" => Ok(OscType::Bool(table.get("value")?)),
+        "Array" => Ok(OscType::Array(OscArray {
+            content: osc_args_from_lua(table.get("value")?)?,
+        })),
+        "Nil" => Ok(OscType::Nil),
+        "Inf" => Ok(OscType::Inf),
+        kind => Err(LuaError::runtime(format!("Unknown OSC type {:?}", kind))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn osc_from_script(script: &str) -> LuaResult<Vec<OscType>> {
+        let lua = Lua::new();
+        let value = lua.load(script).eval()?;
+
+        match data_from_lua(&lua, value, "OSC")? {
+            InternalMessageData::OSC(args) => Ok(args),
+            data => panic!("Expected OSC, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn osc_args_round_trip() {
+        let args = vec![
+            OscType::Int(-1),
+            OscType::Float(0.5),
+            OscType::String("a".to_string()),
+            OscType::Blob(vec![0, 255]),
+            OscType::Time(OscTime {
+                seconds: 1,
+                fractional: 2,
+            }),
+            OscType::Long(i64::MAX),
+            OscType::Double(0.25),
+            OscType::Char('x'),
+            OscType::Color(OscColor {
+                red: 1,
+                green: 2,
+                blue: 3,
+                alpha: 4,
+            }),
+            OscType::Midi(OscMidiMessage {
+                port: 1,
+                status: 2,
+                data1: 3,
+                data2: 4,
+            }),
+            OscType::Bool(true),
+            OscType::Array(OscArray {
+                content: vec![OscType::Int(1), OscType::Nil],
+            }),
+            OscType::Nil,
+            OscType::Inf,
+        ];
+
+        let lua = Lua::new();
+        let value = data_to_lua(&lua, &InternalMessageData::OSC(args.clone())).unwrap();
+
+        match data_from_lua(&lua, value, "OSC").unwrap() {
+            InternalMessageData::OSC(converted) => assert_eq!(converted, args),
+            data => panic!("Expected OSC, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn osc_args_from_typed_tables() {
+        let args = osc_from_script(
+            "return {
+                { type = 'Long', value = 5 },
+                { type = 'Double', value = 1.5 },
+                { type = 'Array', value = { { type = 'String', value = 'a' } } },
+                { type = 'Color', value = { red = 1, green = 2, blue = 3, alpha = 4 } },
+            }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            args,
+            vec![
+                OscType::Long(5),
+                OscType::Double(1.5),
+                OscType::Array(OscArray {
+                    content: vec![OscType::String("a".to_string())],
+                }),
+                OscType::Color(OscColor {
+                    red: 1,
+                    green: 2,
+                    blue: 3,
+                    alpha: 4,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn osc_args_from_plain_values() {
+        let args = osc_from_script("return { 1, 0.5, 'a', false }").unwrap();
+
+        assert_eq!(
+            args,
+            vec![
+                OscType::Int(1),
+                OscType::Float(0.5),
+                OscType::String("a".to_string()),
+                OscType::Bool(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn osc_ints_must_fit() {
+        let err = osc_from_script("return { 2147483648 }").unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+
+        assert!(osc_from_script("return { { type = 'Int', value = -2147483649 } }").is_err());
+        assert_eq!(
+            osc_from_script("return { -2147483648, { type = 'Long', value = 2147483648 } }")
+                .unwrap(),
+            vec![OscType::Int(i32::MIN), OscType::Long(2147483648)]
+        );
+    }
+
+    #[test]
+    fn invalid_osc_args_fail() {
+        assert!(osc_from_script("return { { type = 'Char', value = 'ab' } }").is_err());
+        assert!(osc_from_script("return { { type = 'Unknown' } }").is_err());
+        assert!(osc_from_script("return { { type = 'Int', value = 'a' } }").is_err());
+        assert!(osc_from_script("return { function() end }").is_err());
+        assert!(osc_from_script("return 'a'").is_err());
+    }
+}
@@ -1,10 +1,19 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
 use async_trait::async_trait;
 use mlua::prelude::*;
 use serde::Deserialize;
 
 use crate::message::InternalMessage;
 
-use super::Block;
+use super::{
+    Block,
+    lua_data::data_to_lua,
+    lua_sandbox::LuaSandboxConfig,
+    lua_vm::{LuaScript, LuaSource},
+    script_state::StateConfig,
+};
 
 #[derive(Debug, Deserialize)]
 pub enum LuaFilterConfig {
@@ -12,65 +21,75 @@ pub enum LuaFilterConfig {
     File(String),
 }
 
-pub struct LuaFilterBlock {
-    pub script: String,
-}
-
-impl LuaFilterBlock {
-    pub fn new(config: LuaFilterConfig) -> anyhow::Result<LuaFilterBlock> {
-        match config {
-            LuaFilterConfig::Inline(script) => Ok(LuaFilterBlock { script }),
+impl LuaFilterConfig {
+    /// Reads the script from disk for `File`
+    pub fn load(self: Self) -> anyhow::Result<LuaSource> {
+        match self {
+            LuaFilterConfig::Inline(script) => Ok(LuaSource {
+                name: "=inline".to_string(),
+                script,
+                dir: None,
+            }),
             LuaFilterConfig::File(path) => {
-                let script = std::fs::read_to_string(path)?;
-                Ok(LuaFilterBlock { script })
+                let script = std::fs::read_to_string(&path)
+                    .context(format!("Failed to read Lua script {:?}", path))?;
+
+                Ok(LuaSource {
+                    name: format!("@{}", path),
+                    script,
+                    dir: Path::new(&path)
+                        .parent()
+                        .filter(|dir| !dir.as_os_str().is_empty())
+                        .map(Path::to_path_buf),
+                })
             }
         }
     }
 }
 
-#[async_trait]
-impl Block for LuaFilterBlock {
-    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
-        let matches = match message.data {
-            crate::message::InternalMessageData::Json(ref value) => {
-                let lua = Lua::new();
-
-                lua.globals()
-                    .set(
-                        "topic",
-                        lua.to_value(&message.topic)
-                            .map_err(|e| anyhow::Error::msg(e.to_string()))?,
-                    )
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+pub struct LuaFilterBlock {
+    pub script: Arc<LuaScript>,
+}
 
-                lua.globals()
-                    .set(
-                        "data",
-                        lua.to_value(value)
-                            .map_err(|e| anyhow::Error::msg(e.to_string()))?,
-                    )
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+impl LuaFilterBlock {
+    pub fn new(
+        config: LuaFilterConfig,
+        state: Option<StateConfig>,
+        sandbox: LuaSandboxConfig,
+    ) -> anyhow::Result<LuaFilterBlock> {
+        Ok(LuaFilterBlock {
+            script: Arc::new(LuaScript::new(config.load()?, sandbox, setup)?.with_state(state)?),
+        })
+    }
+}
 
-                let finish = lua
-                    .create_function(move |lua, matches: bool| {
-                        return lua.globals().set("_INTERNAL_MATCHES_", matches);
-                    })
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+fn setup(lua: &Lua) -> LuaResult<()> {
+    let finish = lua.create_function(move |lua, matches: bool| {
+        return lua.set_named_registry_value("_INTERNAL_MATCHES_", matches);
+    })?;
 
-                lua.globals()
-                    .set("finish", finish)
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    lua.globals().set("finish", finish)
+}
 
-                lua.load(&self.script)
-                    .exec()
-                    .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+#[async_trait]
+impl Block for LuaFilterBlock {
+    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let (message, matches) = self
+            .script
+            .run(
+                message,
+                |lua, env, message| {
+                    lua.set_named_registry_value("_INTERNAL_MATCHES_", false)?;
 
-                let matches: bool = lua.globals().get("_INTERNAL_MATCHES_").unwrap_or(false);
+                    env.set("topic", lua.to_value(&message.topic)?)?;
+                    env.set("data", data_to_lua(lua, &message.data)?)?;
+                    env.set("data_type", message.data.type_name())?;
 
-                matches
-            }
-            _ => false,
-        };
+                    Ok(())
+                },
+                |lua, _, _| lua.named_registry_value::<bool>("_INTERNAL_MATCHES_"),
+            )
+            .await?;
 
         if matches {
             Ok(vec![message])
@@ -78,4 +97,8 @@ impl Block for LuaFilterBlock {
             Ok(vec![])
         }
     }
+
+    async fn shutdown(self: &Self) -> anyhow::Result<()> {
+        self.script.snapshot()
+    }
 }
@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use mlua::prelude::*;
+use regex::Regex;
+use tracing::{debug, error, info, warn};
+
+/// Compiled patterns are kept per VM, the cache is cleared once it holds this many
+const REGEX_CACHE_SIZE: usize = 64;
+
+/// Installs the `postoffice` module and lets `require` find modules next to the script.
+/// `dir` is the directory of the script file, inline scripts resolve modules relative to the
+/// working directory.
+pub fn install(lua: &Lua, dir: Option<&Path>) -> LuaResult<()> {
+    let package: LuaTable = lua.globals().get("package")?;
+
+    let dir = dir
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or(".".to_string());
+    let path: String = package.get("path")?;
+    package.set("path", format!("{dir}/?.lua;{dir}/?/init.lua;{path}"))?;
+
+    let module = lua.create_table()?;
+    module.set("json", json(lua)?)?;
+    module.set("log", log(lua)?)?;
+    module.set("base64", base64(lua)?)?;
+    module.set("hex", hex(lua)?)?;
+    module.set("regex", regex(lua)?)?;
+    module.set(
+        "now",
+        lua.create_function(|_, ()| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(LuaError::external)?;
+            Ok(now.as_millis() as i64)
+        })?,
+    )?;
+
+    let loaded: LuaTable = package.get("loaded")?;
+    loaded.set("postoffice", &module)?;
+    lua.globals().set("postoffice", module)?;
+
+    return Ok(());
+}
+
+fn json(lua: &Lua) -> LuaResult<LuaTable> {
+    let json = lua.create_table()?;
+
+    json.set(
+        "encode",
+        lua.create_function(|lua, value: LuaValue| {
+            let value: serde_json::Value = lua.from_value(value)?;
+            serde_json::to_string(&value).map_err(LuaError::external)
+        })?,
+    )?;
+    json.set(
+        "decode",
+        lua.create_function(|lua, text: LuaString| {
+            let value: serde_json::Value =
+                serde_json::from_slice(&text.as_bytes()).map_err(LuaError::external)?;
+            lua.to_value(&value)
+        })?,
+    )?;
+
+    return Ok(json);
+}
+
+fn log(lua: &Lua) -> LuaResult<LuaTable> {
+    let log = lua.create_table()?;
+
+    log.set(
+        "debug",
+        lua.create_function(|_, message: String| {
+            debug!("{}", message);
+            Ok(())
+        })?,
+    )?;
+    log.set(
+        "info",
+        lua.create_function(|_, message: String| {
+            info!("{}", message);
+            Ok(())
+        })?,
+    )?;
+    log.set(
+        "warn",
+        lua.create_function(|_, message: String| {
+            warn!("{}", message);
+            Ok(())
+        })?,
+    )?;
+    log.set(
+        "error",
+        lua.create_function(|_, message: String| {
+            error!("{}", message);
+            Ok(())
+        })?,
+    )?;
+
+    return Ok(log);
+}
+
+fn base64(lua: &Lua) -> LuaResult<LuaTable> {
+    let base64 = lua.create_table()?;
+
+    base64.set(
+        "encode",
+        lua.create_function(|_, data: LuaString| Ok(STANDARD.encode(data.as_bytes())))?,
+    )?;
+    base64.set(
+        "decode",
+        lua.create_function(|lua, text: LuaString| {
+            let data = STANDARD
+                .decode(text.as_bytes())
+                .map_err(LuaError::external)?;
+            lua.create_string(data)
+        })?,
+    )?;
+
+    return Ok(base64);
+}
+
+fn hex(lua: &Lua) -> LuaResult<LuaTable> {
+    let hex = lua.create_table()?;
+
+    hex.set(
+        "encode",
+        lua.create_function(|_, data: LuaString| Ok(hex::encode(data.as_bytes())))?,
+    )?;
+    hex.set(
+        "decode",
+        lua.create_function(|lua, text: LuaString| {
+            let data = hex::decode(text.as_bytes()).map_err(LuaError::external)?;
+            lua.create_string(data)
+        })?,
+    )?;
+
+    return Ok(hex);
+}
+
+fn regex(lua: &Lua) -> LuaResult<LuaTable> {
+    let regex = lua.create_table()?;
+
+    let cache = Mutex::new(HashMap::<String, Regex>::new());
+    let compile = Arc::new(move |pattern: &str| -> LuaResult<Regex> {
+        let mut cache = cache.lock().expect("Regex cache lock poisoned");
+
+        if let Some(regex) = cache.get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern).map_err(LuaError::external)?;
+
+        if cache.len() >= REGEX_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(pattern.to_string(), regex.clone());
+
+        return Ok(regex);
+    });
+
+    let matches = compile.clone();
+    regex.set(
+        "is_match",
+        lua.create_function(move |_, (pattern, text): (String, String)| {
+            Ok(matches(&pattern)?.is_match(&text))
+        })?,
+    )?;
+
+    let find = compile.clone();
+    regex.set(
+        "find",
+        lua.create_function(move |_, (pattern, text): (String, String)| {
+            Ok(find(&pattern)?
+                .find(&text)
+                .map(|found| found.as_str().to_string()))
+        })?,
+    )?;
+
+    let captures = compile.clone();
+    regex.set(
+        "captures",
+        lua.create_function(move |lua, (pattern, text): (String, String)| {
+            let regex = captures(&pattern)?;
+
+            let Some(found) = regex.captures(&text) else {
+                return Ok(None);
+            };
+
+            // Groups by index, starting with the whole match at 0, and by name
+            let table = lua.create_table()?;
+
+            for (idx, group) in found.iter().enumerate() {
+                if let Some(group) = group {
+                    table.set(idx, group.as_str())?;
+                }
+            }
+
+            for name in regex.capture_names().flatten() {
+                if let Some(group) = found.name(name) {
+                    table.set(name, group.as_str())?;
+                }
+            }
+
+            Ok(Some(table))
+        })?,
+    )?;
+
+    let replace = compile;
+    regex.set(
+        "replace",
+        lua.create_function(
+            move |_, (pattern, text, replacement): (String, String, String)| {
+                Ok(replace(&pattern)?
+                    .replace_all(&text, replacement.as_str())
+                    .to_string())
+            },
+        )?,
+    )?;
+
+    return Ok(regex);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval<T: FromLuaMulti>(script: &str) -> LuaResult<T> {
+        let lua = Lua::new();
+        install(&lua, None)?;
+        lua.load(script).eval()
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let encoded: String = eval("return postoffice.json.encode({ a = { 1, 2 } })").unwrap();
+        assert_eq!(encoded, r#"{"a":[1,2]}"#);
+
+        let decoded: i64 = eval(r#"return postoffice.json.decode('{"a":[1,2]}').a[2]"#).unwrap();
+        assert_eq!(decoded, 2);
+
+        assert!(eval::<LuaValue>("return postoffice.json.decode('{')").is_err());
+    }
+
+    #[test]
+    fn encodes_binary_data() {
+        let encoded: (String, String) =
+            eval(r#"return postoffice.base64.encode('\0\255'), postoffice.hex.encode('\0\255')"#)
+                .unwrap();
+        assert_eq!(encoded, ("AP8=".to_string(), "00ff".to_string()));
+
+        let decoded: bool = eval(
+            r#"return postoffice.base64.decode('AP8=') == '\0\255'
+                and postoffice.hex.decode('00FF') == '\0\255'"#,
+        )
+        .unwrap();
+        assert!(decoded);
+
+        assert!(eval::<LuaValue>("return postoffice.base64.decode('*')").is_err());
+        assert!(eval::<LuaValue>("return postoffice.hex.decode('0')").is_err());
+    }
+
+    #[test]
+    fn matches_regexes() {
+        let found: (bool, bool, Option<String>, Option<String>) = eval(
+            r#"local regex = postoffice.regex
+            return regex.is_match('^/fader/\\d+$', '/fader/12'),
+                regex.is_match('^/fader', '/button/1'),
+                regex.find('\\d+', '/fader/12'),
+                regex.find('\\d+', '/fader')"#,
+        )
+        .unwrap();
+        assert_eq!(found, (true, false, Some("12".to_string()), None));
+
+        let captures: (String, String, String, bool) = eval(
+            r#"local found = postoffice.regex.captures('/(?<kind>\\w+)/(\\d+)', '/fader/12')
+            return found[0], found.kind, found[2],
+                postoffice.regex.captures('\\d', 'x') == nil"#,
+        )
+        .unwrap();
+        assert_eq!(
+            captures,
+            (
+                "/fader/12".to_string(),
+                "fader".to_string(),
+                "12".to_string(),
+                true
+            )
+        );
+
+        let replaced: String =
+            eval(r#"return postoffice.regex.replace('(\\d+)', '/fader/1/2', '[$1]')"#).unwrap();
+        assert_eq!(replaced, "/fader/[1]/[2]");
+
+        assert!(eval::<bool>("return postoffice.regex.is_match('(', 'x')").is_err());
+    }
+
+    #[test]
+    fn regex_cache_is_bounded() {
+        let matched: bool = eval(&format!(
+            "local matched = true
+            for i = 1, {} do
+                matched = matched and postoffice.regex.is_match('^' .. i .. '$', tostring(i))
+            end
+            return matched",
+            REGEX_CACHE_SIZE * 2 + 1
+        ))
+        .unwrap();
+
+        assert!(matched);
+    }
+
+    #[test]
+    fn now_is_in_milliseconds() {
+        let now: i64 = eval("return postoffice.now()").unwrap();
+        let expected = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        assert!((expected - now).abs() < 10_000, "{} vs {}", now, expected);
+    }
+
+    #[test]
+    fn module_can_be_required() {
+        let same: bool = eval("return require('postoffice') == postoffice").unwrap();
+        assert!(same);
+
+        let logged = eval::<()>(
+            "postoffice.log.debug('a') postoffice.log.info('b')
+            postoffice.log.warn('c') postoffice.log.error('d')",
+        );
+        assert!(logged.is_ok());
+    }
+
+    #[test]
+    fn require_finds_modules_next_to_the_script() {
+        let dir =
+            std::env::temp_dir().join(format!("postoffice-test-{}-lua-lib", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("helper.lua"), "return { value = 1 }").unwrap();
+        std::fs::write(dir.join("nested").join("init.lua"), "return { value = 2 }").unwrap();
+
+        let lua = Lua::new();
+        install(&lua, Some(&dir)).unwrap();
+        let values: LuaResult<(i64, i64)> = lua
+            .load("return require('helper').value, require('nested').value")
+            .eval();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(values.unwrap(), (1, 2));
+    }
+}
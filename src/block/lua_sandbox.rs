@@ -0,0 +1,274 @@
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, LuaOptions, StdLib, VmState, prelude::*};
+use serde::Deserialize;
+
+/// The hook that enforces `max_instructions` and `timeout` runs after this many instructions
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Replaces every table in the globals with a read-only proxy. `package.loaded` stays
+/// writable, so `require` can cache modules.
+const FREEZE_LIBS: &str = r#"
+local loaded = package.loaded
+
+local function freeze(name, t)
+    for key, value in next, t do
+        if type(value) == "table" and value ~= loaded then
+            t[key] = freeze(name .. "." .. key, value)
+        end
+    end
+
+    return setmetatable({}, {
+        __index = t,
+        __newindex = function() error("attempt to modify library table '" .. name .. "'", 2) end,
+        __pairs = function() return next, t, nil end,
+        __len = function() return #t end,
+        __metatable = false,
+    })
+end
+
+local globals = _G
+for name, value in next, globals do
+    if type(value) == "table" and value ~= globals then
+        local proxy = freeze(name, value)
+        globals[name] = proxy
+
+        if loaded[name] == value then
+            loaded[name] = proxy
+        end
+    end
+end
+
+local strings = getmetatable("")
+strings.__index = globals.string
+strings.__metatable = false
+"#;
+
+/// Libraries that give scripts access to the system and are only loaded on request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum LuaLib {
+    IO,
+    OS,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LuaSandboxConfig {
+    #[serde(default)]
+    pub libs: Vec<LuaLib>,
+    /// Instructions a script may run per message
+    #[serde(default = "default_max_instructions")]
+    pub max_instructions: Option<u64>,
+    /// Milliseconds a script may run per message
+    #[serde(default = "default_timeout")]
+    pub timeout: Option<u64>,
+    /// Bytes of memory a VM may allocate
+    #[serde(default = "default_max_memory")]
+    pub max_memory: Option<usize>,
+    /// Runs every message in a new VM that parses the script again, like before VMs were
+    /// reused. Only meant for `bench --fresh-vm` to compare against, not part of the config.
+    #[serde(skip)]
+    pub fresh_vm: bool,
+}
+
+fn default_max_instructions() -> Option<u64> {
+    Some(10_000_000)
+}
+
+fn default_timeout() -> Option<u64> {
+    Some(1000)
+}
+
+fn default_max_memory() -> Option<usize> {
+    Some(64 * 1024 * 1024)
+}
+
+impl Default for LuaSandboxConfig {
+    fn default() -> Self {
+        Self {
+            libs: vec![],
+            max_instructions: default_max_instructions(),
+            timeout: default_timeout(),
+            max_memory: default_max_memory(),
+            fresh_vm: false,
+        }
+    }
+}
+
+/// What the current run may still use, checked by the hook
+struct Budget {
+    instructions: u64,
+    deadline: Option<Instant>,
+}
+
+impl LuaSandboxConfig {
+    /// Creates a VM with the allowed libraries and limits
+    pub fn create_lua(self: &Self) -> LuaResult<Lua> {
+        let mut libs = StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH
+            | StdLib::PACKAGE;
+
+        if self.libs.contains(&LuaLib::IO) {
+            libs |= StdLib::IO;
+        }
+        if self.libs.contains(&LuaLib::OS) {
+            libs |= StdLib::OS;
+        }
+
+        let lua = Lua::new_with(libs, LuaOptions::default())?;
+
+        let globals = lua.globals();
+
+        // These read files from disk, like `io` does
+        if !self.libs.contains(&LuaLib::IO) {
+            globals.set("dofile", LuaValue::Nil)?;
+            globals.set("loadfile", LuaValue::Nil)?;
+        }
+
+        // Malformed bytecode can corrupt the VM, so scripts can only load source code
+        let load: LuaFunction = lua
+            .load(
+                r#"local load = load
+                return function(chunk, name, mode, ...) return load(chunk, name, "t", ...) end"#,
+            )
+            .eval()?;
+        globals.set("load", load)?;
+        globals
+            .get::<LuaTable>("string")?
+            .set("dump", LuaValue::Nil)?;
+
+        // These bypass the environment of a run or change the VM for every later run
+        globals.set("collectgarbage", LuaValue::Nil)?;
+        globals.set("rawset", LuaValue::Nil)?;
+        globals
+            .get::<LuaTable>("package")?
+            .get::<LuaTable>("loaded")?
+            .set("_G", LuaValue::Nil)?;
+
+        if let Some(max_memory) = self.max_memory {
+            lua.set_memory_limit(max_memory)?;
+        }
+
+        if self.max_instructions.is_some() || self.timeout.is_some() {
+            let max_instructions = self.max_instructions;
+
+            lua.set_hook(
+                HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+                move |lua, _| {
+                    let Some(mut budget) = lua.app_data_mut::<Budget>() else {
+                        return Ok(VmState::Continue);
+                    };
+
+                    budget.instructions += HOOK_INTERVAL as u64;
+
+                    if let Some(max_instructions) = max_instructions
+                        && budget.instructions > max_instructions
+                    {
+                        return Err(LuaError::runtime(format!(
+                            "Script exceeded the limit of {} instructions",
+                            max_instructions
+                        )));
+                    }
+
+                    if let Some(deadline) = budget.deadline
+                        && Instant::now() > deadline
+                    {
+                        return Err(LuaError::runtime("Script exceeded its timeout"));
+                    }
+
+                    Ok(VmState::Continue)
+                },
+            );
+        }
+
+        return Ok(lua);
+    }
+
+    /// Makes the library tables, e.g. `string`, read-only, so a script can't change them for
+    /// the later runs of a VM. Call it once all globals are installed.
+    pub fn freeze_libs(self: &Self, lua: &Lua) -> LuaResult<()> {
+        lua.load(FREEZE_LIBS).set_name("=freeze_libs").exec()
+    }
+
+    /// Resets the budget of `lua` before a run
+    pub fn start_run(self: &Self, lua: &Lua) {
+        if self.max_instructions.is_none() && self.timeout.is_none() {
+            return;
+        }
+
+        let deadline = self
+            .timeout
+            .map(|timeout| Instant::now() + Duration::from_millis(timeout));
+
+        // Reuses the budget of the last run, this runs for every message
+        if let Some(mut budget) = lua.app_data_mut::<Budget>() {
+            budget.instructions = 0;
+            budget.deadline = deadline;
+            return;
+        }
+
+        lua.set_app_data(Budget {
+            instructions: 0,
+            deadline,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytecode() -> Vec<u8> {
+        let lua = Lua::new();
+        let chunk = lua.load("return 1").into_function().unwrap();
+        chunk.dump(false)
+    }
+
+    #[test]
+    fn load_rejects_binary_chunks() {
+        let lua = LuaSandboxConfig::default().create_lua().unwrap();
+        let load: LuaFunction = lua.globals().get("load").unwrap();
+        let chunk = lua.create_string(bytecode()).unwrap();
+
+        for mode in [
+            LuaValue::Nil,
+            LuaValue::String(lua.create_string("b").unwrap()),
+        ] {
+            let (func, err): (Option<LuaFunction>, Option<String>) =
+                load.call((&chunk, "chunk", mode)).unwrap();
+
+            assert!(func.is_none());
+            assert!(err.unwrap().contains("binary"));
+        }
+    }
+
+    #[test]
+    fn load_accepts_source() {
+        let lua = LuaSandboxConfig::default().create_lua().unwrap();
+
+        let value: i64 = lua.load("return load('return 1 + 1')()").eval().unwrap();
+        assert_eq!(value, 2);
+
+        let value: i64 = lua
+            .load("return load('return x', 'chunk', 't', { x = 3 })()")
+            .eval()
+            .unwrap();
+        assert_eq!(value, 3);
+    }
+
+    #[test]
+    fn removes_unsafe_functions() {
+        let lua = LuaSandboxConfig::default().create_lua().unwrap();
+
+        let removed: bool = lua
+            .load(
+                "return string.dump == nil and collectgarbage == nil and rawset == nil
+                    and package.loaded._G == nil",
+            )
+            .eval()
+            .unwrap();
+        assert!(removed);
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use mlua::prelude::*;
+
+use crate::message::{InternalMessage, InternalMessageData};
+
+use super::{
+    Block,
+    lua_data::{data_from_lua, data_to_lua},
+    lua_filter::LuaFilterConfig,
+    lua_sandbox::LuaSandboxConfig,
+    lua_vm::LuaScript,
+    script_state::StateConfig,
+};
+
+pub struct LuaTransformBlock {
+    pub script: Arc<LuaScript>,
+}
+
+impl LuaTransformBlock {
+    pub fn new(
+        config: LuaFilterConfig,
+        state: Option<StateConfig>,
+        sandbox: LuaSandboxConfig,
+    ) -> anyhow::Result<LuaTransformBlock> {
+        Ok(LuaTransformBlock {
+            script: Arc::new(LuaScript::new(config.load()?, sandbox, setup)?.with_state(state)?),
+        })
+    }
+}
+
+fn setup(lua: &Lua) -> LuaResult<()> {
+    let emit = lua.create_function(
+        |lua, (topic, data, data_type): (String, LuaValue, Option<String>)| {
+            let entry = lua.create_table()?;
+            entry.set("topic", topic)?;
+            entry.set("data", data)?;
+            entry.set("data_type", data_type)?;
+
+            let emitted: LuaTable = lua.named_registry_value("_INTERNAL_EMITTED_")?;
+            return emitted.push(entry);
+        },
+    )?;
+
+    lua.globals().set("emit", emit)
+}
+
+/// What the script left behind: the emitted messages, or the new `topic` and `data` globals
+enum Output {
+    Emitted(Vec<(String, InternalMessageData)>),
+    Changed(Option<String>, InternalMessageData),
+}
+
+#[async_trait]
+impl Block for LuaTransformBlock {
+    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let input_type = message.data.type_name();
+
+        let (message, output) = self
+            .script
+            .run(
+                message,
+                move |lua, env, message| {
+                    lua.set_named_registry_value("_INTERNAL_EMITTED_", lua.create_table()?)?;
+
+                    env.set("topic", lua.to_value(&message.topic)?)?;
+                    env.set("data", data_to_lua(lua, &message.data)?)?;
+                    env.set("data_type", input_type)?;
+
+                    Ok(())
+                },
+                move |lua, env, _| {
+                    let emitted: LuaTable = lua.named_registry_value("_INTERNAL_EMITTED_")?;
+
+                    if emitted.raw_len() > 0 {
+                        let mut messages = vec![];
+
+                        for entry in emitted.sequence_values::<LuaTable>() {
+                            let entry = entry?;
+                            // Emitted messages have the type of the incoming message by default
+                            let data_type = entry
+                                .get::<Option<String>>("data_type")?
+                                .unwrap_or(input_type.to_string());
+
+                            messages.push((
+                                entry.get("topic")?,
+                                data_from_lua(lua, entry.get("data")?, &data_type)?,
+                            ));
+                        }
+
+                        return Ok(Output::Emitted(messages));
+                    }
+
+                    let data_type: String = env.get("data_type")?;
+
+                    Ok(Output::Changed(
+                        env.get("topic")?,
+                        data_from_lua(lua, env.get("data")?, &data_type)?,
+                    ))
+                },
+            )
+            .await?;
+
+        match output {
+            Output::Emitted(messages) => Ok(messages
+                .into_iter()
+                .map(|(topic, data)| InternalMessage {
+                    topic,
+                    data,
+                    ..message.clone()
+                })
+                .collect()),
+            // Setting `topic` to nil drops the message
+            Output::Changed(None, _) => Ok(vec![]),
+            Output::Changed(Some(topic), data) => Ok(vec![InternalMessage {
+                topic,
+                data,
+                ..message
+            }]),
+        }
+    }
+
+    async fn shutdown(self: &Self) -> anyhow::Result<()> {
+        self.script.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn transform(script: &str) -> LuaTransformBlock {
+        LuaTransformBlock::new(
+            LuaFilterConfig::Inline(script.to_string()),
+            None,
+            LuaSandboxConfig::default(),
+        )
+        .unwrap()
+    }
+
+    fn message(data: InternalMessageData) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 1,
+            topic: "/a".to_string(),
+            data,
+            dead_letter: false,
+            hops: 2,
+        }
+    }
+
+    fn topics(messages: &[InternalMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.topic.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn changes_topic_and_data() {
+        let block = transform("topic = topic .. '/b'; data = data:upper()");
+
+        let messages = block
+            .exec(message(InternalMessageData::String("x".to_string())))
+            .await
+            .unwrap();
+
+        assert_eq!(topics(&messages), vec!["/a/b"]);
+        assert!(matches!(messages[0].data, InternalMessageData::String(ref data) if data == "X"));
+        assert_eq!(messages[0].source_connector_idx, 1);
+        assert_eq!(messages[0].hops, 2);
+    }
+
+    #[tokio::test]
+    async fn nil_topic_drops_the_message() {
+        let block = transform("if data == 'drop' then topic = nil end");
+
+        let dropped = block
+            .exec(message(InternalMessageData::String("drop".to_string())))
+            .await
+            .unwrap();
+        assert!(dropped.is_empty());
+
+        // The globals are reset, the next message keeps its topic
+        let kept = block
+            .exec(message(InternalMessageData::String("keep".to_string())))
+            .await
+            .unwrap();
+        assert_eq!(topics(&kept), vec!["/a"]);
+    }
+
+    #[tokio::test]
+    async fn emit_replaces_the_message() {
+        let block = transform(
+            "emit(topic .. '/1', data)
+            emit('/json', { x = 1 }, 'JSON')
+            topic = nil",
+        );
+
+        let messages = block
+            .exec(message(InternalMessageData::String("x".to_string())))
+            .await
+            .unwrap();
+
+        assert_eq!(topics(&messages), vec!["/a/1", "/json"]);
+        // Emitted messages have the type of the incoming message unless a type is given
+        assert!(matches!(messages[0].data, InternalMessageData::String(ref data) if data == "x"));
+        assert!(
+            matches!(messages[1].data, InternalMessageData::Json(ref data) if *data == json!({ "x": 1 }))
+        );
+        assert!(
+            messages
+                .iter()
+                .all(|m| m.source_connector_idx == 1 && m.hops == 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn emitted_messages_are_not_kept() {
+        let block = transform("if data == 'emit' then emit('/emitted', data) end");
+
+        let emitted = block
+            .exec(message(InternalMessageData::String("emit".to_string())))
+            .await
+            .unwrap();
+        assert_eq!(topics(&emitted), vec!["/emitted"]);
+
+        let changed = block
+            .exec(message(InternalMessageData::String("x".to_string())))
+            .await
+            .unwrap();
+        assert_eq!(topics(&changed), vec!["/a"]);
+    }
+
+    #[tokio::test]
+    async fn data_must_match_data_type() {
+        let block = transform("data = { x = 1 }");
+
+        assert!(
+            block
+                .exec(message(InternalMessageData::String("x".to_string())))
+                .await
+                .is_err()
+        );
+    }
+}
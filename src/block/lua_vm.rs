@@ -0,0 +1,444 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    blocking::run_blocking,
+    lua_lib,
+    lua_sandbox::LuaSandboxConfig,
+    script_state::{ScriptState, StateConfig},
+};
+use mlua::{ChunkMode, prelude::*};
+use serde_json::{Value, json};
+
+/// Tables in the state can't be nested deeper than this, which also stops tables that contain
+/// themselves
+const MAX_STATE_DEPTH: usize = 64;
+
+pub fn lua_error(e: LuaError) -> anyhow::Error {
+    anyhow::Error::msg(e.to_string())
+}
+
+struct LuaVm {
+    lua: Lua,
+    chunk: LuaFunction,
+    /// Metatable of every environment, falls back to the globals
+    env_meta: LuaTable,
+}
+
+impl LuaVm {
+    fn new(lua: Lua, chunk: LuaFunction) -> LuaResult<Self> {
+        let env_meta = lua.create_table()?;
+        env_meta.set("__index", lua.globals())?;
+        // Hides the globals from `getmetatable(_ENV)`
+        env_meta.set("__metatable", false)?;
+
+        Ok(Self {
+            lua,
+            chunk,
+            env_meta,
+        })
+    }
+}
+
+/// A script and where it came from
+pub struct LuaSource {
+    /// The chunk name that is shown in error messages, e.g. `@script.lua`
+    pub name: String,
+    pub script: String,
+    /// The directory of the script file, `require` looks for modules there
+    pub dir: Option<PathBuf>,
+}
+
+/// A script that is compiled once and run by a pool of Lua VMs.
+///
+/// Every run gets its own global environment that falls back to the globals of the VM, so
+/// globals assigned by the script don't leak into the next message.
+pub struct LuaScript {
+    bytecode: Vec<u8>,
+    /// The script and its chunk name, only parsed again with `fresh_vm`
+    source: (String, String),
+    dir: Option<PathBuf>,
+    sandbox: LuaSandboxConfig,
+    /// Installs the functions of a block, e.g. `finish`, into the globals of a new VM
+    setup: fn(&Lua) -> LuaResult<()>,
+    /// Idle VMs. A VM is only used by one message at a time, so the pool grows to the number
+    /// of messages that run the script at the same time. Use `concurrency` to limit it.
+    pool: Mutex<Vec<LuaVm>>,
+    state: Option<ScriptState>,
+}
+
+impl LuaScript {
+    pub fn new(
+        source: LuaSource,
+        sandbox: LuaSandboxConfig,
+        setup: fn(&Lua) -> LuaResult<()>,
+    ) -> anyhow::Result<Self> {
+        // `_ENV` is passed by `run`. It is kept on the first line so error messages point to
+        // the right line of the script.
+        let script = format!("local _ENV = ...; {}", source.script);
+
+        let vm = Self::init_vm(source.dir.as_deref(), &sandbox, setup).map_err(lua_error)?;
+
+        let chunk = vm
+            .load(&script)
+            .set_name(&source.name)
+            .into_function()
+            .map_err(lua_error)?;
+        let bytecode = chunk.dump(false);
+
+        let vm = LuaVm::new(vm, chunk).map_err(lua_error)?;
+
+        return Ok(Self {
+            bytecode,
+            source: (script, source.name),
+            dir: source.dir,
+            sandbox,
+            setup,
+            pool: Mutex::new(vec![vm]),
+            state: None,
+        });
+    }
+
+    /// Provides a `state` table that persists across runs. Runs of a script with state don't
+    /// overlap, so they can't overwrite each other's changes.
+    pub fn with_state(mut self: Self, config: Option<StateConfig>) -> anyhow::Result<Self> {
+        if let Some(config) = config {
+            self.state = Some(ScriptState::new(config, "Lua")?);
+        }
+
+        return Ok(self);
+    }
+
+    /// Writes the state to its snapshot file, if there is one
+    pub fn snapshot(self: &Self) -> anyhow::Result<()> {
+        match self.state {
+            Some(ref state) => state.snapshot(),
+            None => Ok(()),
+        }
+    }
+
+    fn init_vm(
+        dir: Option<&Path>,
+        sandbox: &LuaSandboxConfig,
+        setup: fn(&Lua) -> LuaResult<()>,
+    ) -> LuaResult<Lua> {
+        let lua = sandbox.create_lua()?;
+        lua_lib::install(&lua, dir)?;
+        setup(&lua)?;
+        sandbox.freeze_libs(&lua)?;
+
+        return Ok(lua);
+    }
+
+    fn create_vm(self: &Self) -> LuaResult<LuaVm> {
+        let lua = Self::init_vm(self.dir.as_deref(), &self.sandbox, self.setup)?;
+
+        let chunk = match self.sandbox.fresh_vm {
+            true => lua
+                .load(&self.source.0)
+                .set_name(&self.source.1)
+                .into_function()?,
+            false => lua
+                .load(&self.bytecode)
+                .set_mode(ChunkMode::Binary)
+                .into_function()?,
+        };
+
+        LuaVm::new(lua, chunk)
+    }
+
+    /// Runs the script with `run_blocking`, so a long running script doesn't hold up other
+    /// tasks. `input`, e.g. the message, is handed to `prepare`, which sets the
+    /// globals for this run, and to `collect`, which reads the results after the script
+    /// finished. `input` is returned next to the result.
+    pub async fn run<T, R>(
+        self: &Arc<Self>,
+        input: T,
+        prepare: impl FnOnce(&Lua, &LuaTable, &T) -> LuaResult<()> + Send + 'static,
+        collect: impl FnOnce(&Lua, &LuaTable, &T) -> LuaResult<R> + Send + 'static,
+    ) -> anyhow::Result<(T, R)>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+    {
+        let script = self.clone();
+
+        return run_blocking(move || {
+            let res = script.run_blocking(
+                |lua, env| prepare(lua, env, &input),
+                |lua, env| collect(lua, env, &input),
+            );
+
+            res.map(|res| (input, res))
+        })
+        .await;
+    }
+
+    fn run_blocking<R>(
+        self: &Self,
+        prepare: impl FnOnce(&Lua, &LuaTable) -> LuaResult<()>,
+        collect: impl FnOnce(&Lua, &LuaTable) -> LuaResult<R>,
+    ) -> anyhow::Result<R> {
+        let vm = match self.sandbox.fresh_vm {
+            true => None,
+            false => self.pool.lock().expect("Lua pool lock poisoned").pop(),
+        };
+
+        let vm = match vm {
+            Some(vm) => vm,
+            None => self.create_vm().map_err(lua_error)?,
+        };
+
+        // Held for the whole run, so runs with state don't overlap
+        let mut state = self
+            .state
+            .as_ref()
+            .map(|state| state.value.lock().expect("Script state lock poisoned"));
+
+        self.sandbox.start_run(&vm.lua);
+
+        let res = Self::run_in(&vm, state.as_deref_mut(), prepare, collect);
+
+        if !self.sandbox.fresh_vm {
+            self.pool.lock().expect("Lua pool lock poisoned").push(vm);
+        }
+
+        return res.map_err(lua_error);
+    }
+
+    fn run_in<R>(
+        vm: &LuaVm,
+        state: Option<&mut Value>,
+        prepare: impl FnOnce(&Lua, &LuaTable) -> LuaResult<()>,
+        collect: impl FnOnce(&Lua, &LuaTable) -> LuaResult<R>,
+    ) -> LuaResult<R> {
+        let lua = &vm.lua;
+
+        let env = lua.create_table()?;
+        env.set_metatable(Some(vm.env_meta.clone()));
+        // `_G.x = ...` would otherwise write to the globals and outlive the run
+        env.set("_G", &env)?;
+
+        if let Some(ref state) = state {
+            env.set("state", state_from_json(lua, state)?)?;
+        }
+
+        prepare(lua, &env)?;
+        vm.chunk.call::<()>(&env)?;
+
+        // Changes of a failed run are discarded
+        if let Some(state) = state {
+            *state = match env.get::<LuaValue>("state")? {
+                LuaValue::Nil => json!({}),
+                value => state_to_json(lua, value, 0)?,
+            };
+        }
+
+        return collect(lua, &env);
+    }
+}
+
+/// Converts the `state` table to JSON. JSON objects only have string keys, so number keys are
+/// stored as strings, e.g. `state[100]` as `"100"`. Tables with the keys `1..n` become arrays.
+fn state_to_json(lua: &Lua, value: LuaValue, depth: usize) -> LuaResult<Value> {
+    let LuaValue::Table(table) = value else {
+        return lua.from_value(value);
+    };
+
+    if depth >= MAX_STATE_DEPTH {
+        return Err(LuaError::runtime(format!(
+            "state is nested deeper than {} tables",
+            MAX_STATE_DEPTH
+        )));
+    }
+
+    let pairs = table
+        .pairs::<LuaValue, LuaValue>()
+        .collect::<LuaResult<Vec<_>>>()?;
+
+    let is_array = match pairs.len() {
+        0 => table
+            .metatable()
+            .is_some_and(|meta| meta == lua.array_metatable()),
+        len => pairs.iter().all(
+            |(key, _)| matches!(key, LuaValue::Integer(idx) if (1..=len as i64).contains(idx)),
+        ),
+    };
+
+    if is_array {
+        let mut values = vec![Value::Null; pairs.len()];
+
+        for (key, value) in pairs {
+            if let LuaValue::Integer(idx) = key {
+                values[idx as usize - 1] = state_to_json(lua, value, depth + 1)?;
+            }
+        }
+
+        return Ok(Value::Array(values));
+    }
+
+    let mut entries = serde_json::Map::new();
+
+    for (key, value) in pairs {
+        let key = match key {
+            LuaValue::String(key) => key.to_str()?.to_string(),
+            LuaValue::Integer(key) => key.to_string(),
+            LuaValue::Number(key) => key.to_string(),
+            key => {
+                return Err(LuaError::runtime(format!(
+                    "state can't have keys of type {}",
+                    key.type_name()
+                )));
+            }
+        };
+
+        entries.insert(key, state_to_json(lua, value, depth + 1)?);
+    }
+
+    return Ok(Value::Object(entries));
+}
+
+/// Converts the JSON of `state_to_json` back to a table. Keys that are integers, e.g. `"100"`,
+/// become integer keys again.
+fn state_from_json(lua: &Lua, value: &Value) -> LuaResult<LuaValue> {
+    match value {
+        Value::Array(values) => {
+            let table = lua.create_table_with_capacity(values.len(), 0)?;
+
+            for value in values {
+                table.raw_push(state_from_json(lua, value)?)?;
+            }
+
+            // Keeps empty arrays arrays
+            table.set_metatable(Some(lua.array_metatable()));
+
+            return Ok(LuaValue::Table(table));
+        }
+        Value::Object(entries) => {
+            let table = lua.create_table_with_capacity(0, entries.len())?;
+
+            for (key, value) in entries {
+                let value = state_from_json(lua, value)?;
+
+                match key.parse::<i64>() {
+                    Ok(idx) if idx.to_string() == *key => table.raw_set(idx, value)?,
+                    _ => table.raw_set(key.as_str(), value)?,
+                }
+            }
+
+            return Ok(LuaValue::Table(table));
+        }
+        value => lua.to_value(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(script: &str) -> LuaScript {
+        let source = LuaSource {
+            name: "test".to_string(),
+            script: script.to_string(),
+            dir: None,
+        };
+
+        LuaScript::new(source, LuaSandboxConfig::default(), |_| Ok(())).unwrap()
+    }
+
+    fn run(script: &LuaScript) -> Option<i64> {
+        script
+            .run_blocking(|_, _| Ok(()), |_, env| env.get("seen"))
+            .unwrap()
+    }
+
+    #[test]
+    fn globals_are_reset_between_runs() {
+        let script = script("seen = _G.x; _G.x = 1; y = 1");
+
+        assert_eq!(run(&script), None);
+        assert_eq!(run(&script), None);
+    }
+
+    #[test]
+    fn state_keeps_integer_keys() {
+        let script = script(
+            "state[100] = (state[100] or 0) + 1
+            state.list = { 'a', 'b' }
+            state.sparse = { [1] = 'a', [3] = 'c' }
+            seen = state[100]",
+        )
+        .with_state(Some(StateConfig::default()))
+        .unwrap();
+
+        assert_eq!(run(&script), Some(1));
+        assert_eq!(run(&script), Some(2));
+
+        let state = script.state.as_ref().unwrap().value.lock().unwrap();
+        assert_eq!(
+            *state,
+            json!({ "100": 2, "list": ["a", "b"], "sparse": { "1": "a", "3": "c" } })
+        );
+    }
+
+    #[test]
+    fn state_rejects_tables_that_contain_themselves() {
+        let script = script("state.self = state")
+            .with_state(Some(StateConfig::default()))
+            .unwrap();
+
+        assert!(script.run_blocking(|_, _| Ok(()), |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn globals_are_hidden() {
+        let script = script("seen = getmetatable(_ENV) == false and 1 or 0");
+
+        assert_eq!(run(&script), Some(1));
+    }
+
+    #[test]
+    fn library_tables_are_read_only() {
+        let script = script(
+            "local changed = pcall(function() string.x = 1 end)
+                or pcall(function() require('postoffice').x = 1 end)
+                or pcall(function() table.insert = nil end)
+            seen = (changed or string.x ~= nil) and 0 or 1",
+        );
+
+        assert_eq!(run(&script), Some(1));
+        assert_eq!(run(&script), Some(1));
+    }
+
+    #[test]
+    fn frozen_libraries_still_work() {
+        let script = script(
+            "local count = 0
+            for _ in pairs(string) do count = count + 1 end
+            local ok = ('x'):upper() == 'X' and #string.format('%d', 10) == 2
+                and require('postoffice') == postoffice and getmetatable('') == false
+            seen = (ok and count > 0) and 1 or 0",
+        );
+
+        assert_eq!(run(&script), Some(1));
+    }
+
+    #[test]
+    fn fresh_vm_parses_the_source() {
+        let source = LuaSource {
+            name: "test".to_string(),
+            script: "seen = (seen or 0) + 1".to_string(),
+            dir: None,
+        };
+        let sandbox = LuaSandboxConfig {
+            fresh_vm: true,
+            ..Default::default()
+        };
+        let script = LuaScript::new(source, sandbox, |_| Ok(())).unwrap();
+
+        assert_eq!(run(&script), Some(1));
+        assert_eq!(run(&script), Some(1));
+    }
+}
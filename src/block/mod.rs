@@ -1,15 +1,43 @@
 mod add_leading_slash;
+#[cfg(any(feature = "lua", feature = "rhai", feature = "wasm"))]
+mod blocking;
 mod convert_body;
+#[cfg(feature = "lua")]
+mod lua_data;
+#[cfg(feature = "lua")]
 mod lua_filter;
+#[cfg(feature = "lua")]
+mod lua_lib;
+#[cfg(feature = "lua")]
+mod lua_sandbox;
+#[cfg(feature = "lua")]
+mod lua_transform;
+#[cfg(feature = "lua")]
+mod lua_vm;
 mod match_topic;
 mod remove_body;
 mod remove_leading_slash;
 mod replace_body;
 mod replace_topic;
+#[cfg(feature = "rhai")]
+mod rhai_data;
+#[cfg(feature = "rhai")]
+mod rhai_filter;
+#[cfg(feature = "rhai")]
+mod rhai_lib;
+#[cfg(feature = "rhai")]
+mod rhai_script;
+#[cfg(feature = "rhai")]
+mod rhai_transform;
+#[cfg(any(feature = "lua", feature = "rhai"))]
+mod script_state;
 mod wait;
+#[cfg(feature = "wasm")]
+mod wasm;
 
+use anyhow::Context;
 use async_trait::async_trait;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, de::Error};
 
 use add_leading_slash::AddLeadingSlashBlock;
 use match_topic::{MatchTopicBlock, MatchTopicConfig};
@@ -18,55 +46,180 @@ use remove_leading_slash::RemoveLeadingSlashBlock;
 use replace_body::ReplaceBodyBlock;
 use replace_topic::ReplaceTopicBlock;
 
+#[cfg(feature = "lua")]
+use lua_filter::{LuaFilterBlock, LuaFilterConfig};
+#[cfg(feature = "lua")]
+use lua_sandbox::LuaSandboxConfig;
+#[cfg(feature = "lua")]
+use lua_transform::LuaTransformBlock;
+#[cfg(feature = "rhai")]
+use rhai_filter::RhaiFilterBlock;
+#[cfg(feature = "rhai")]
+use rhai_script::{RhaiConfig, RhaiSandboxConfig};
+#[cfg(feature = "rhai")]
+use rhai_transform::RhaiTransformBlock;
+#[cfg(any(feature = "lua", feature = "rhai"))]
+use script_state::StateConfig;
+#[cfg(feature = "wasm")]
+use wasm::{WasmBlock, WasmConfig};
+
 use crate::{
     block::{
         convert_body::{ConvertBodyBlock, ConvertBodyConfig},
-        lua_filter::{LuaFilterBlock, LuaFilterConfig},
         wait::WaitBlock,
     },
     message::InternalMessage,
+    plugin::{PluginBlockConfig, plugin_kind, plugins},
 };
 
 #[derive(Debug, Deserialize)]
+#[serde(remote = "Self")]
 pub enum BlockConfig {
     AddLeadingSlash {
         to: Vec<Connection>,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     RemoveLeadingSlash {
         to: Vec<Connection>,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     RemoveBody {
         to: Vec<Connection>,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     ReplaceBody {
         to: Vec<Connection>,
         config: serde_json::Value,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     MatchTopic {
         to: Vec<Connection>,
         config: MatchTopicConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     ReplaceTopic {
         to: Vec<Connection>,
         config: String,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
+    #[cfg(feature = "lua")]
     LuaFilter {
         to: Vec<Connection>,
         config: LuaFilterConfig,
+        state: Option<StateConfig>,
+        #[serde(default)]
+        sandbox: LuaSandboxConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
+    },
+    #[cfg(feature = "lua")]
+    LuaTransform {
+        to: Vec<Connection>,
+        config: LuaFilterConfig,
+        state: Option<StateConfig>,
+        #[serde(default)]
+        sandbox: LuaSandboxConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
+    },
+    #[cfg(feature = "rhai")]
+    RhaiFilter {
+        to: Vec<Connection>,
+        config: RhaiConfig,
+        state: Option<StateConfig>,
+        #[serde(default)]
+        sandbox: RhaiSandboxConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
+    },
+    #[cfg(feature = "rhai")]
+    RhaiTransform {
+        to: Vec<Connection>,
+        config: RhaiConfig,
+        state: Option<StateConfig>,
+        #[serde(default)]
+        sandbox: RhaiSandboxConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     ConvertBody {
         to: Vec<Connection>,
         config: ConvertBodyConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
     Wait {
         to: Vec<Connection>,
         config: u64,
+        #[serde(flatten)]
+        options: BlockOptions,
+    },
+    #[cfg(feature = "wasm")]
+    Wasm {
+        to: Vec<Connection>,
+        config: WasmConfig,
+        #[serde(flatten)]
+        options: BlockOptions,
     },
+    /// A type registered by a plugin, see `Plugins`
+    #[serde(skip)]
+    Plugin(PluginBlockConfig),
+}
+
+impl<'de> Deserialize<'de> for BlockConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        // Plugins take precedence, so they can replace a built-in type
+        if plugin_kind(&value).is_some_and(|kind| plugins().has_block(kind)) {
+            return Ok(BlockConfig::Plugin(
+                PluginBlockConfig::deserialize(value).map_err(D::Error::custom)?,
+            ));
+        }
+
+        return BlockConfig::deserialize(value).map_err(D::Error::custom);
+    }
+}
+
+/// Options that every block supports next to its own `config`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BlockOptions {
+    /// Receives messages for which the block returned an error
+    #[serde(default)]
+    pub on_error: Vec<Connection>,
+    /// Drops messages that passed more blocks than this. Overrides the global `max_hops`.
+    pub max_hops: Option<u32>,
+    /// Shown next to the index of the block, e.g. in cycle reports
+    pub name: Option<String>,
 }
 
 pub struct BlockHandle {
+    /// The name of the `BlockConfig` variant or plugin, e.g. `Wait`
+    pub kind: String,
     pub block: Box<dyn Block>,
     pub to: Vec<Connection>,
+    pub options: BlockOptions,
+}
+
+impl BlockHandle {
+    /// Returns every outgoing connection, including the error connections
+    pub fn connections(self: &Self) -> impl Iterator<Item = &Connection> {
+        self.to.iter().chain(self.options.on_error.iter())
+    }
+
+    /// Describes the block with index `idx` for humans, e.g. `Block 2 "retry" (Wait)`
+    pub fn describe(self: &Self, idx: usize) -> String {
+        match self.options.name {
+            Some(ref name) => format!("Block {} {:?} ({})", idx, name, self.kind),
+            None => format!("Block {} ({})", idx, self.kind),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -75,20 +228,223 @@ pub enum Connection {
     Sink(usize),
 }
 
+impl BlockConfig {
+    pub fn kind(self: &Self) -> &str {
+        match self {
+            BlockConfig::AddLeadingSlash { .. } => "AddLeadingSlash",
+            BlockConfig::RemoveLeadingSlash { .. } => "RemoveLeadingSlash",
+            BlockConfig::RemoveBody { .. } => "RemoveBody",
+            BlockConfig::ReplaceBody { .. } => "ReplaceBody",
+            BlockConfig::MatchTopic { .. } => "MatchTopic",
+            BlockConfig::ReplaceTopic { .. } => "ReplaceTopic",
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaFilter { .. } => "LuaFilter",
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaTransform { .. } => "LuaTransform",
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiFilter { .. } => "RhaiFilter",
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiTransform { .. } => "RhaiTransform",
+            BlockConfig::ConvertBody { .. } => "ConvertBody",
+            BlockConfig::Wait { .. } => "Wait",
+            #[cfg(feature = "wasm")]
+            BlockConfig::Wasm { .. } => "Wasm",
+            BlockConfig::Plugin(config) => &config.kind,
+        }
+    }
+
+    pub fn to(self: &Self) -> &Vec<Connection> {
+        match self {
+            BlockConfig::AddLeadingSlash { to, .. } => to,
+            BlockConfig::RemoveLeadingSlash { to, .. } => to,
+            BlockConfig::RemoveBody { to, .. } => to,
+            BlockConfig::ReplaceBody { to, .. } => to,
+            BlockConfig::MatchTopic { to, .. } => to,
+            BlockConfig::ReplaceTopic { to, .. } => to,
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaFilter { to, .. } => to,
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaTransform { to, .. } => to,
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiFilter { to, .. } => to,
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiTransform { to, .. } => to,
+            BlockConfig::ConvertBody { to, .. } => to,
+            BlockConfig::Wait { to, .. } => to,
+            #[cfg(feature = "wasm")]
+            BlockConfig::Wasm { to, .. } => to,
+            BlockConfig::Plugin(config) => &config.to,
+        }
+    }
+
+    pub fn options(self: &Self) -> &BlockOptions {
+        match self {
+            BlockConfig::AddLeadingSlash { options, .. } => options,
+            BlockConfig::RemoveLeadingSlash { options, .. } => options,
+            BlockConfig::RemoveBody { options, .. } => options,
+            BlockConfig::ReplaceBody { options, .. } => options,
+            BlockConfig::MatchTopic { options, .. } => options,
+            BlockConfig::ReplaceTopic { options, .. } => options,
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaFilter { options, .. } => options,
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaTransform { options, .. } => options,
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiFilter { options, .. } => options,
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiTransform { options, .. } => options,
+            BlockConfig::ConvertBody { options, .. } => options,
+            BlockConfig::Wait { options, .. } => options,
+            #[cfg(feature = "wasm")]
+            BlockConfig::Wasm { options, .. } => options,
+            BlockConfig::Plugin(config) => &config.options,
+        }
+    }
+
+    /// Describes the most important part of the config in a few words, e.g. the pattern of a
+    /// `MatchTopic` block
+    pub fn summary(self: &Self) -> Option<String> {
+        match self {
+            BlockConfig::ReplaceBody { config, .. } => Some(config.to_string()),
+            BlockConfig::MatchTopic { config, .. } => Some(match config {
+                MatchTopicConfig::Exact(pattern) => format!("Exact {:?}", pattern),
+                MatchTopicConfig::StartsWith(pattern) => format!("StartsWith {:?}", pattern),
+                MatchTopicConfig::EndsWith(pattern) => format!("EndsWith {:?}", pattern),
+                MatchTopicConfig::Regex(pattern) => format!("Regex {:?}", pattern),
+            }),
+            BlockConfig::ReplaceTopic { config, .. } => Some(format!("{:?}", config)),
+            #[cfg(feature = "lua")]
+            BlockConfig::LuaFilter { config, .. } | BlockConfig::LuaTransform { config, .. } => {
+                Some(match config {
+                    LuaFilterConfig::Inline(_) => "Inline script".to_string(),
+                    LuaFilterConfig::File(path) => path.clone(),
+                })
+            }
+            #[cfg(feature = "rhai")]
+            BlockConfig::RhaiFilter { config, .. } | BlockConfig::RhaiTransform { config, .. } => {
+                Some(match config {
+                    RhaiConfig::Inline(_) => "Inline script".to_string(),
+                    RhaiConfig::File(path) => path.clone(),
+                })
+            }
+            BlockConfig::ConvertBody { config, .. } => Some(format!("{:?}", config)),
+            BlockConfig::Wait { config, .. } => Some(format!("{} ms", config)),
+            #[cfg(feature = "wasm")]
+            BlockConfig::Wasm { config, .. } => Some(config.path.clone()),
+            BlockConfig::Plugin(config) if !config.config.is_null() => {
+                Some(config.config.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
 pub fn make_block(config: BlockConfig) -> anyhow::Result<BlockHandle> {
-    let (to, block): (Vec<Connection>, Box<dyn Block>) = match config {
-        BlockConfig::AddLeadingSlash { to } => (to, Box::new(AddLeadingSlashBlock {})),
-        BlockConfig::RemoveLeadingSlash { to } => (to, Box::new(RemoveLeadingSlashBlock {})),
-        BlockConfig::RemoveBody { to } => (to, Box::new(RemoveBodyBlock {})),
-        BlockConfig::ReplaceBody { to, config } => (to, Box::new(ReplaceBodyBlock { config })),
-        BlockConfig::MatchTopic { to, config } => (to, Box::new(MatchTopicBlock::new(config)?)),
-        BlockConfig::ReplaceTopic { to, config } => (to, Box::new(ReplaceTopicBlock { config })),
-        BlockConfig::LuaFilter { to, config } => (to, Box::new(LuaFilterBlock::new(config)?)),
-        BlockConfig::ConvertBody { to, config } => (to, Box::new(ConvertBodyBlock { config })),
-        BlockConfig::Wait { to, config } => (to, Box::new(WaitBlock { config })),
+    let kind = config.kind().to_string();
+
+    let (to, options, block): (Vec<Connection>, BlockOptions, Box<dyn Block>) = match config {
+        BlockConfig::AddLeadingSlash { to, options } => {
+            (to, options, Box::new(AddLeadingSlashBlock {}))
+        }
+        BlockConfig::RemoveLeadingSlash { to, options } => {
+            (to, options, Box::new(RemoveLeadingSlashBlock {}))
+        }
+        BlockConfig::RemoveBody { to, options } => (to, options, Box::new(RemoveBodyBlock {})),
+        BlockConfig::ReplaceBody {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(ReplaceBodyBlock { config })),
+        BlockConfig::MatchTopic {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(MatchTopicBlock::new(config)?)),
+        BlockConfig::ReplaceTopic {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(ReplaceTopicBlock { config })),
+        #[cfg(feature = "lua")]
+        BlockConfig::LuaFilter {
+            to,
+            config,
+            state,
+            sandbox,
+            options,
+        } => (
+            to,
+            options,
+            Box::new(LuaFilterBlock::new(config, state, sandbox)?),
+        ),
+        #[cfg(feature = "lua")]
+        BlockConfig::LuaTransform {
+            to,
+            config,
+            state,
+            sandbox,
+            options,
+        } => (
+            to,
+            options,
+            Box::new(LuaTransformBlock::new(config, state, sandbox)?),
+        ),
+        #[cfg(feature = "rhai")]
+        BlockConfig::RhaiFilter {
+            to,
+            config,
+            state,
+            sandbox,
+            options,
+        } => (
+            to,
+            options,
+            Box::new(RhaiFilterBlock::new(config, state, sandbox)?),
+        ),
+        #[cfg(feature = "rhai")]
+        BlockConfig::RhaiTransform {
+            to,
+            config,
+            state,
+            sandbox,
+            options,
+        } => (
+            to,
+            options,
+            Box::new(RhaiTransformBlock::new(config, state, sandbox)?),
+        ),
+        BlockConfig::ConvertBody {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(ConvertBodyBlock { config })),
+        BlockConfig::Wait {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(WaitBlock { config })),
+        #[cfg(feature = "wasm")]
+        BlockConfig::Wasm {
+            to,
+            config,
+            options,
+        } => (to, options, Box::new(WasmBlock::new(config)?)),
+        BlockConfig::Plugin(config) => {
+            let block = plugins()
+                .block(&config.kind)?
+                .make_block(config.config)
+                .context(format!("Failed to create {} block", config.kind))?;
+
+            (config.to, config.options, block)
+        }
     };
 
-    return Ok(BlockHandle { block, to });
+    return Ok(BlockHandle {
+        kind,
+        block,
+        to,
+        options,
+    });
 }
 
 #[async_trait]
@@ -96,4 +452,9 @@ pub trait Block: Sync + Send {
     async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
         Ok(vec![message])
     }
+
+    /// Called once on shutdown after all messages were handled
+    async fn shutdown(self: &Self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
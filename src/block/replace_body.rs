@@ -10,9 +10,12 @@ pub struct ReplaceBodyBlock {
 
 #[async_trait]
 impl Block for ReplaceBodyBlock {
-    async fn exec(self: &Self, mut message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+    async fn exec(
+        self: &Self,
+        mut message: InternalMessage,
+    ) -> anyhow::Result<Vec<InternalMessage>> {
         message.data = InternalMessageData::Json(self.config.clone());
 
         Ok(vec![message])
     }
-}
\ No newline at end of file
+}
@@ -10,7 +10,10 @@ pub struct ReplaceTopicBlock {
 
 #[async_trait]
 impl Block for ReplaceTopicBlock {
-    async fn exec(self: &Self, mut message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+    async fn exec(
+        self: &Self,
+        mut message: InternalMessage,
+    ) -> anyhow::Result<Vec<InternalMessage>> {
         message.topic = self.config.clone();
 
         Ok(vec![message])
@@ -0,0 +1,259 @@
+use bytes::Bytes;
+use rhai::{Array, Dynamic, Map};
+use rosc::{OscArray, OscColor, OscMidiMessage, OscTime, OscType};
+
+use crate::message::InternalMessageData;
+
+/// Converts a body to the value of the `data` variable, like `data_to_lua` does for Lua
+pub fn data_to_rhai(data: &InternalMessageData) -> anyhow::Result<Dynamic> {
+    match data {
+        InternalMessageData::Empty => Ok(Dynamic::UNIT),
+        InternalMessageData::String(value) => Ok(Dynamic::from(value.clone())),
+        InternalMessageData::Binary(bytes) => Ok(Dynamic::from_blob(bytes.to_vec())),
+        InternalMessageData::Json(value) => Ok(rhai::serde::to_dynamic(value).map_err(rhai_error)?),
+        InternalMessageData::OSC(args) => Ok(Dynamic::from_array(osc_args_to_rhai(args))),
+    }
+}
+
+/// Converts the `data` variable back to a body of the type `data_type`. `()` is always `Empty`.
+pub fn data_from_rhai(value: Dynamic, data_type: &str) -> anyhow::Result<InternalMessageData> {
+    if value.is_unit() {
+        return Ok(InternalMessageData::Empty);
+    }
+
+    match data_type {
+        "Empty" => Ok(InternalMessageData::Empty),
+        "String" => match value.into_immutable_string() {
+            Ok(value) => Ok(InternalMessageData::String(value.to_string())),
+            Err(type_name) => Err(type_error("String", type_name)),
+        },
+        "Binary" => {
+            if value.is_blob() {
+                let blob = value.into_blob().map_err(anyhow::Error::msg)?;
+                return Ok(InternalMessageData::Binary(Bytes::from(blob)));
+            }
+
+            match value.into_immutable_string() {
+                Ok(value) => Ok(InternalMessageData::Binary(Bytes::from(
+                    value.as_bytes().to_vec(),
+                ))),
+                Err(type_name) => Err(type_error("Binary", type_name)),
+            }
+        }
+        "JSON" => Ok(InternalMessageData::Json(
+            rhai::serde::from_dynamic(&value).map_err(rhai_error)?,
+        )),
+        "OSC" => match value.into_array() {
+            Ok(args) => Ok(InternalMessageData::OSC(osc_args_from_rhai(args)?)),
+            Err(type_name) => Err(type_error("OSC", type_name)),
+        },
+        data_type => Err(anyhow::Error::msg(format!(
+            "Unknown data_type {:?}, expected Empty, String, Binary, JSON or OSC",
+            data_type
+        ))),
+    }
+}
+
+pub fn rhai_error(e: impl ToString) -> anyhow::Error {
+    anyhow::Error::msg(e.to_string())
+}
+
+fn type_error(data_type: &str, type_name: &str) -> anyhow::Error {
+    anyhow::Error::msg(format!(
+        "data_type is {}, but data is a {}",
+        data_type, type_name
+    ))
+}
+
+fn expected(expected: &str, type_name: &str) -> anyhow::Error {
+    anyhow::Error::msg(format!(
+        "Expected {} in OSC argument, got {}",
+        expected, type_name
+    ))
+}
+
+/// OSC arguments become an array of maps like `#{ type: "Int", value: 1 }`
+fn osc_args_to_rhai(args: &[OscType]) -> Array {
+    args.iter()
+        .map(|arg| Dynamic::from_map(osc_to_rhai(arg)))
+        .collect()
+}
+
+fn osc_to_rhai(arg: &OscType) -> Map {
+    fn map<const N: usize>(fields: [(&str, Dynamic); N]) -> Dynamic {
+        Dynamic::from_map(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.into(), value))
+                .collect(),
+        )
+    }
+
+    let (kind, value) = match arg {
+        OscType::Int(value) => ("Int", Dynamic::from_int(*value as i64)),
+        OscType::Float(value) => ("Float", Dynamic::from_float(*value as f64)),
+        OscType::String(value) => ("String", Dynamic::from(value.clone())),
+        OscType::Blob(value) => ("Blob", Dynamic::from_blob(value.clone())),
+        OscType::Time(time) => (
+            "Time",
+            map([
+                ("seconds", Dynamic::from_int(time.seconds as i64)),
+                ("fractional", Dynamic::from_int(time.fractional as i64)),
+            ]),
+        ),
+        OscType::Long(value) => ("Long", Dynamic::from_int(*value)),
+        OscType::Double(value) => ("Double", Dynamic::from_float(*value)),
+        OscType::Char(value) => ("Char", Dynamic::from_char(*value)),
+        OscType::Color(color) => (
+            "Color",
+            map([
+                ("red", Dynamic::from_int(color.red as i64)),
+                ("green", Dynamic::from_int(color.green as i64)),
+                ("blue", Dynamic::from_int(color.blue as i64)),
+                ("alpha", Dynamic::from_int(color.alpha as i64)),
+            ]),
+        ),
+        OscType::Midi(midi) => (
+            "Midi",
+            map([
+                ("port", Dynamic::from_int(midi.port as i64)),
+                ("status", Dynamic::from_int(midi.status as i64)),
+                ("data1", Dynamic::from_int(midi.data1 as i64)),
+                ("data2", Dynamic::from_int(midi.data2 as i64)),
+            ]),
+        ),
+        OscType::Bool(value) => ("Bool", Dynamic::from_bool(*value)),
+        OscType::Array(array) => (
+            "Array",
+            Dynamic::from_array(osc_args_to_rhai(&array.content)),
+        ),
+        OscType::Nil => ("Nil", Dynamic::UNIT),
+        OscType::Inf => ("Inf", Dynamic::UNIT),
+    };
+
+    let mut map = Map::new();
+    map.insert("type".into(), Dynamic::from(kind.to_string()));
+    map.insert("value".into(), value);
+
+    return map;
+}
+
+fn osc_args_from_rhai(args: Array) -> anyhow::Result<Vec<OscType>> {
+    args.into_iter().map(osc_from_rhai).collect()
+}
+
+/// Accepts typed maps and, for convenience, plain numbers, strings and booleans
+fn osc_from_rhai(arg: Dynamic) -> anyhow::Result<OscType> {
+    if let Ok(value) = arg.as_int() {
+        return Ok(OscType::Int(value as i32));
+    }
+    if let Ok(value) = arg.as_float() {
+        return Ok(OscType::Float(value as f32));
+    }
+    if let Ok(value) = arg.as_bool() {
+        return Ok(OscType::Bool(value));
+    }
+    if arg.is_string() {
+        return Ok(OscType::String(arg.to_string()));
+    }
+
+    let type_name = arg.type_name();
+    let Some(map) = arg.try_cast::<Map>() else {
+        return Err(anyhow::Error::msg(format!(
+            "Can't convert a {} to an OSC argument",
+            type_name
+        )));
+    };
+
+    let kind = field(&map, "type")?.to_string();
+
+    match kind.as_str() {
+        "Int" => Ok(OscType::Int(int(&map, "value")?)),
+        "Float" => Ok(OscType::Float(float(&map, "value")? as f32)),
+        "String" => Ok(OscType::String(field(&map, "value")?.to_string())),
+        "Blob" => Ok(OscType::Blob(
+            field(&map, "value")?
+                .into_blob()
+                .map_err(|type_name| expected("Blob", type_name))?,
+        )),
+        "Time" => {
+            let value = submap(&map)?;
+            Ok(OscType::Time(OscTime {
+                seconds: int(&value, "seconds")?,
+                fractional: int(&value, "fractional")?,
+            }))
+        }
+        "Long" => Ok(OscType::Long(int(&map, "value")?)),
+        "Double" => Ok(OscType::Double(float(&map, "value")?)),
+        "Char" => Ok(OscType::Char(
+            field(&map, "value")?
+                .as_char()
+                .map_err(|type_name| expected("Char", type_name))?,
+        )),
+        "Color" => {
+            let value = submap(&map)?;
+            Ok(OscType::Color(OscColor {
+                red: int(&value, "red")?,
+                green: int(&value, "green")?,
+                blue: int(&value, "blue")?,
+                alpha: int(&value, "alpha")?,
+            }))
+        }
+        "Midi" => {
+            let value = submap(&map)?;
+            Ok(OscType::Midi(OscMidiMessage {
+                port: int(&value, "port")?,
+                status: int(&value, "status")?,
+                data1: int(&value, "data1")?,
+                data2: int(&value, "data2")?,
+            }))
+        }
+        "Bool" => Ok(OscType::Bool(
+            field(&map, "value")?
+                .as_bool()
+                .map_err(|type_name| expected("Bool", type_name))?,
+        )),
+        "Array" => Ok(OscType::Array(OscArray {
+            content: osc_args_from_rhai(
+                field(&map, "value")?
+                    .into_array()
+                    .map_err(|type_name| expected("Array", type_name))?,
+            )?,
+        })),
+        "Nil" => Ok(OscType::Nil),
+        "Inf" => Ok(OscType::Inf),
+        kind => Err(anyhow::Error::msg(format!("Unknown OSC type {:?}", kind))),
+    }
+}
+
+fn field(map: &Map, name: &str) -> anyhow::Result<Dynamic> {
+    map.get(name)
+        .cloned()
+        .ok_or_else(|| anyhow::Error::msg(format!("OSC argument is missing {:?}", name)))
+}
+
+fn submap(map: &Map) -> anyhow::Result<Map> {
+    let value = field(map, "value")?;
+    let type_name = value.type_name();
+
+    value
+        .try_cast::<Map>()
+        .ok_or_else(|| expected("map", type_name))
+}
+
+fn int<T: TryFrom<i64>>(map: &Map, name: &str) -> anyhow::Result<T> {
+    let value = field(map, name)?
+        .as_int()
+        .map_err(|type_name| expected("integer", type_name))?;
+
+    T::try_from(value).map_err(|_| anyhow::Error::msg(format!("{} is out of range", name)))
+}
+
+fn float(map: &Map, name: &str) -> anyhow::Result<f64> {
+    let value = field(map, name)?;
+
+    value
+        .as_float()
+        .or_else(|_| value.as_int().map(|value| value as f64))
+        .map_err(|type_name| expected("float", type_name))
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rhai::{Dynamic, Engine};
+
+use crate::message::InternalMessage;
+
+use super::{
+    Block,
+    rhai_data::data_to_rhai,
+    rhai_script::{RhaiConfig, RhaiSandboxConfig, RhaiScript, with_output},
+    script_state::StateConfig,
+};
+
+pub struct RhaiFilterBlock {
+    pub script: Arc<RhaiScript>,
+}
+
+impl RhaiFilterBlock {
+    pub fn new(
+        config: RhaiConfig,
+        state: Option<StateConfig>,
+        sandbox: RhaiSandboxConfig,
+    ) -> anyhow::Result<RhaiFilterBlock> {
+        Ok(RhaiFilterBlock {
+            script: Arc::new(RhaiScript::new(config, sandbox, setup)?.with_state(state)?),
+        })
+    }
+}
+
+fn setup(engine: &mut Engine) {
+    engine.register_fn("finish", |matches: bool| {
+        with_output(|output| output.matches = matches)
+    });
+}
+
+#[async_trait]
+impl Block for RhaiFilterBlock {
+    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let (message, matches) = self
+            .script
+            .run(
+                message,
+                |scope, message| {
+                    scope.push("topic", message.topic.clone());
+                    scope.push("data", data_to_rhai(&message.data)?);
+                    scope.push("data_type", Dynamic::from(message.data.type_name()));
+
+                    Ok(())
+                },
+                |_, output, _| Ok(output.matches),
+            )
+            .await?;
+
+        if matches {
+            Ok(vec![message])
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    async fn shutdown(self: &Self) -> anyhow::Result<()> {
+        self.script.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rosc::OscType;
+
+    use super::*;
+    use crate::message::InternalMessageData;
+
+    fn fader(value: f32) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 0,
+            topic: "/fader/1".to_string(),
+            data: InternalMessageData::OSC(vec![OscType::Float(value)]),
+            dead_letter: false,
+            hops: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_changed_values() {
+        let block = RhaiFilterBlock::new(
+            RhaiConfig::Inline(
+                "let value = data[0].value;
+                finish(state[topic] != value);
+                state[topic] = value;"
+                    .to_string(),
+            ),
+            Some(StateConfig::default()),
+            RhaiSandboxConfig::default(),
+        )
+        .unwrap();
+
+        let mut forwarded = vec![];
+        for value in [0.5, 0.5, 0.7] {
+            forwarded.push(block.exec(fader(value)).await.unwrap().len());
+        }
+
+        assert_eq!(forwarded, [1, 0, 1]);
+    }
+}
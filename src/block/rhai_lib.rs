@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use regex::Regex;
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Module};
+use tracing::{debug, error, info, warn};
+
+/// Compiled patterns are kept per block, the cache is cleared once it holds this many
+const REGEX_CACHE_SIZE: usize = 64;
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn runtime_error(e: impl ToString) -> Box<EvalAltResult> {
+    e.to_string().into()
+}
+
+/// Registers the `postoffice` module, the counterpart of the Lua library, e.g.
+/// `postoffice::json::encode(data)`
+pub fn install(engine: &mut Engine) {
+    let mut module = Module::new();
+
+    module.set_sub_module("json", json());
+    module.set_sub_module("log", log());
+    module.set_sub_module("base64", base64());
+    module.set_sub_module("hex", hex());
+    module.set_sub_module("regex", regex());
+    module.set_native_fn("now", || {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(runtime_error)?;
+        Ok(now.as_millis() as i64)
+    });
+
+    engine.register_static_module("postoffice", module.into());
+}
+
+fn json() -> Module {
+    let mut json = Module::new();
+
+    json.set_native_fn("encode", |value: Dynamic| {
+        let value: serde_json::Value = rhai::serde::from_dynamic(&value)?;
+        serde_json::to_string(&value).map_err(runtime_error)
+    });
+    json.set_native_fn("decode", |text: ImmutableString| {
+        let value: serde_json::Value = serde_json::from_str(&text).map_err(runtime_error)?;
+        rhai::serde::to_dynamic(value)
+    });
+
+    return json;
+}
+
+fn log() -> Module {
+    let mut log = Module::new();
+
+    log.set_native_fn("debug", |message: ImmutableString| {
+        debug!("{}", message);
+        Ok(())
+    });
+    log.set_native_fn("info", |message: ImmutableString| {
+        info!("{}", message);
+        Ok(())
+    });
+    log.set_native_fn("warn", |message: ImmutableString| {
+        warn!("{}", message);
+        Ok(())
+    });
+    log.set_native_fn("error", |message: ImmutableString| {
+        error!("{}", message);
+        Ok(())
+    });
+
+    return log;
+}
+
+fn base64() -> Module {
+    let mut base64 = Module::new();
+
+    base64.set_native_fn("encode", |data: Blob| Ok(STANDARD.encode(data)));
+    base64.set_native_fn("encode", |text: ImmutableString| {
+        Ok(STANDARD.encode(text.as_bytes()))
+    });
+    base64.set_native_fn("decode", |text: ImmutableString| -> RhaiResult<Blob> {
+        STANDARD.decode(text.as_bytes()).map_err(runtime_error)
+    });
+
+    return base64;
+}
+
+fn hex() -> Module {
+    let mut hex = Module::new();
+
+    hex.set_native_fn("encode", |data: Blob| Ok(hex::encode(data)));
+    hex.set_native_fn("encode", |text: ImmutableString| {
+        Ok(hex::encode(text.as_bytes()))
+    });
+    hex.set_native_fn("decode", |text: ImmutableString| -> RhaiResult<Blob> {
+        hex::decode(text.as_bytes()).map_err(runtime_error)
+    });
+
+    return hex;
+}
+
+fn regex() -> Module {
+    let mut regex = Module::new();
+
+    let cache = Mutex::new(HashMap::<String, Regex>::new());
+    let compile = Arc::new(move |pattern: &str| -> RhaiResult<Regex> {
+        let mut cache = cache.lock().expect("Regex cache lock poisoned");
+
+        if let Some(regex) = cache.get(pattern) {
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern).map_err(runtime_error)?;
+
+        if cache.len() >= REGEX_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(pattern.to_string(), regex.clone());
+
+        return Ok(regex);
+    });
+
+    let matches = compile.clone();
+    regex.set_native_fn(
+        "is_match",
+        move |pattern: ImmutableString, text: ImmutableString| {
+            Ok(matches(&pattern)?.is_match(&text))
+        },
+    );
+
+    let find = compile.clone();
+    regex.set_native_fn(
+        "find",
+        move |pattern: ImmutableString, text: ImmutableString| {
+            Ok(match find(&pattern)?.find(&text) {
+                Some(found) => Dynamic::from(found.as_str().to_string()),
+                None => Dynamic::UNIT,
+            })
+        },
+    );
+
+    let captures = compile.clone();
+    regex.set_native_fn(
+        "captures",
+        move |pattern: ImmutableString, text: ImmutableString| {
+            let regex = captures(&pattern)?;
+
+            let Some(found) = regex.captures(&text) else {
+                return Ok(Dynamic::UNIT);
+            };
+
+            // Groups by index, starting with the whole match at "0", and by name
+            let mut map = Map::new();
+
+            for (idx, group) in found.iter().enumerate() {
+                if let Some(group) = group {
+                    map.insert(idx.to_string().into(), group.as_str().into());
+                }
+            }
+
+            for name in regex.capture_names().flatten() {
+                if let Some(group) = found.name(name) {
+                    map.insert(name.into(), group.as_str().into());
+                }
+            }
+
+            Ok(Dynamic::from_map(map))
+        },
+    );
+
+    let replace = compile;
+    regex.set_native_fn(
+        "replace",
+        move |pattern: ImmutableString, text: ImmutableString, replacement: ImmutableString| {
+            Ok(replace(&pattern)?
+                .replace_all(&text, replacement.as_str())
+                .to_string())
+        },
+    );
+
+    return regex;
+}
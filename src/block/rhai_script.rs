@@ -0,0 +1,419 @@
+use std::{
+    cell::RefCell,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use rhai::{AST, Dynamic, Engine, EvalAltResult, Scope, module_resolvers::FileModuleResolver};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use super::{
+    blocking::run_blocking,
+    rhai_data::rhai_error,
+    rhai_lib,
+    script_state::{ScriptState, StateConfig},
+};
+
+/// Strings, arrays and maps can't grow larger than this, so a script can't use up all memory
+const MAX_SIZE: usize = 1024 * 1024;
+/// `timeout` is checked after this many operations
+const PROGRESS_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub enum RhaiConfig {
+    Inline(String),
+    File(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RhaiSandboxConfig {
+    /// Operations a script may run per message
+    #[serde(default = "default_max_operations")]
+    pub max_operations: Option<u64>,
+    /// Milliseconds a script may run per message
+    #[serde(default = "default_timeout")]
+    pub timeout: Option<u64>,
+}
+
+fn default_max_operations() -> Option<u64> {
+    Some(10_000_000)
+}
+
+fn default_timeout() -> Option<u64> {
+    Some(1000)
+}
+
+impl Default for RhaiSandboxConfig {
+    fn default() -> Self {
+        Self {
+            max_operations: default_max_operations(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+/// What the functions of a block, e.g. `finish`, collected during a run
+#[derive(Default)]
+pub struct RhaiOutput {
+    pub matches: bool,
+    /// `topic`, `data` and `data_type` of every call to `emit`
+    pub emitted: Vec<(String, Dynamic, Option<String>)>,
+}
+
+#[derive(Default)]
+struct Run {
+    output: RhaiOutput,
+    deadline: Option<Instant>,
+}
+
+thread_local! {
+    /// Functions registered on the engine can't see the scope of the script, so they write to
+    /// the run of their thread. A script runs on a single thread from start to end.
+    static RUN: RefCell<Run> = RefCell::new(Run::default());
+}
+
+/// Lets a function registered by a block write to the output of the current run
+pub fn with_output(f: impl FnOnce(&mut RhaiOutput)) {
+    RUN.with_borrow_mut(|run| f(&mut run.output));
+}
+
+/// A script that is compiled once. Unlike Lua VMs, a Rhai engine can run any number of scripts
+/// at the same time, so there is no pool.
+pub struct RhaiScript {
+    engine: Engine,
+    ast: AST,
+    timeout: Option<u64>,
+    state: Option<ScriptState>,
+}
+
+impl RhaiScript {
+    pub fn new(
+        config: RhaiConfig,
+        sandbox: RhaiSandboxConfig,
+        setup: fn(&mut Engine),
+    ) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+
+        engine.set_max_operations(sandbox.max_operations.unwrap_or(0));
+        engine.set_max_string_size(MAX_SIZE);
+        engine.set_max_array_size(MAX_SIZE);
+        engine.set_max_map_size(MAX_SIZE);
+
+        if sandbox.timeout.is_some() {
+            engine.on_progress(|operations| {
+                if operations % PROGRESS_INTERVAL != 0 {
+                    return None;
+                }
+
+                RUN.with_borrow(|run| match run.deadline {
+                    Some(deadline) if Instant::now() > deadline => Some(Dynamic::UNIT),
+                    _ => None,
+                })
+            });
+        }
+
+        engine.on_print(|text| info!("{}", text));
+        engine.on_debug(|text, _, _| debug!("{}", text));
+
+        rhai_lib::install(&mut engine);
+        setup(&mut engine);
+
+        let (source, script) = match config {
+            RhaiConfig::Inline(script) => ("inline".to_string(), script),
+            RhaiConfig::File(path) => {
+                let script = std::fs::read_to_string(&path)
+                    .context(format!("Failed to read Rhai script {:?}", path))?;
+
+                // `import` finds modules next to the script
+                if let Some(dir) = Path::new(&path)
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                {
+                    engine.set_module_resolver(FileModuleResolver::new_with_path(dir));
+                }
+
+                (path, script)
+            }
+        };
+
+        let mut ast = engine
+            .compile(&script)
+            .map_err(rhai_error)
+            .context(format!("Failed to compile Rhai script {}", source))?;
+        ast.set_source(source);
+
+        return Ok(Self {
+            engine,
+            ast,
+            timeout: sandbox.timeout,
+            state: None,
+        });
+    }
+
+    /// Provides a `state` map that persists across runs, like `LuaScript::with_state`
+    pub fn with_state(mut self: Self, config: Option<StateConfig>) -> anyhow::Result<Self> {
+        if let Some(config) = config {
+            self.state = Some(ScriptState::new(config, "Rhai")?);
+        }
+
+        return Ok(self);
+    }
+
+    /// Writes the state to its snapshot file, if there is one
+    pub fn snapshot(self: &Self) -> anyhow::Result<()> {
+        match self.state {
+            Some(ref state) => state.snapshot(),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the script with `run_blocking`, like `LuaScript::run`. `prepare`
+    /// pushes the variables for this run, e.g. `topic`, and `collect` reads the scope and the
+    /// output after the script finished.
+    pub async fn run<T, R>(
+        self: &Arc<Self>,
+        input: T,
+        prepare: impl FnOnce(&mut Scope, &T) -> anyhow::Result<()> + Send + 'static,
+        collect: impl FnOnce(Scope, RhaiOutput, &T) -> anyhow::Result<R> + Send + 'static,
+    ) -> anyhow::Result<(T, R)>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+    {
+        let script = self.clone();
+
+        return run_blocking(move || {
+            let res = script.run_blocking(&input, prepare, collect);
+
+            res.map(|res| (input, res))
+        })
+        .await;
+    }
+
+    fn run_blocking<T, R>(
+        self: &Self,
+        input: &T,
+        prepare: impl FnOnce(&mut Scope, &T) -> anyhow::Result<()>,
+        collect: impl FnOnce(Scope, RhaiOutput, &T) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        RUN.with_borrow_mut(|run| {
+            *run = Run {
+                output: RhaiOutput::default(),
+                deadline: self
+                    .timeout
+                    .map(|timeout| Instant::now() + Duration::from_millis(timeout)),
+            }
+        });
+
+        // Held for the whole run, so runs with state don't overlap
+        let mut state = self
+            .state
+            .as_ref()
+            .map(|state| state.value.lock().expect("Script state lock poisoned"));
+
+        let mut scope = Scope::new();
+        prepare(&mut scope, input)?;
+
+        if let Some(ref state) = state {
+            scope.push(
+                "state",
+                rhai::serde::to_dynamic(&**state).map_err(rhai_error)?,
+            );
+        }
+
+        self.engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| match *e {
+                // Only `timeout` terminates scripts
+                EvalAltResult::ErrorTerminated(..) => {
+                    anyhow::Error::msg("Script exceeded its timeout")
+                }
+                e => rhai_error(e),
+            })?;
+
+        // Changes of a failed run are discarded
+        if let Some(ref mut state) = state {
+            let value = scope.get_value::<Dynamic>("state").unwrap_or(Dynamic::UNIT);
+
+            **state = match value.is_unit() {
+                true => serde_json::json!({}),
+                false => rhai::serde::from_dynamic(&value)
+                    .map_err(rhai_error)
+                    .context("state can't be stored")?,
+            };
+        }
+
+        let output = RUN.with_borrow_mut(|run| std::mem::take(&mut run.output));
+
+        return collect(scope, output, input);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn script(script: &str, sandbox: RhaiSandboxConfig) -> RhaiScript {
+        RhaiScript::new(RhaiConfig::Inline(script.to_string()), sandbox, |_| {}).unwrap()
+    }
+
+    /// Runs the script and returns the variable `seen`
+    fn run(script: &RhaiScript) -> anyhow::Result<Dynamic> {
+        script.run_blocking(
+            &(),
+            |_, _| Ok(()),
+            |scope, _, _| Ok(scope.get_value::<Dynamic>("seen").unwrap_or(Dynamic::UNIT)),
+        )
+    }
+
+    fn state(script: &RhaiScript) -> serde_json::Value {
+        script.state.as_ref().unwrap().value.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn variables_are_reset_between_runs() {
+        let script = script(
+            "let seen = if is_def_var(\"x\") { 1 } else { 0 }; let x = 1;",
+            RhaiSandboxConfig::default(),
+        );
+
+        assert_eq!(run(&script).unwrap().as_int(), Ok(0));
+        assert_eq!(run(&script).unwrap().as_int(), Ok(0));
+    }
+
+    #[test]
+    fn state_persists_across_runs() {
+        let script = script(
+            "state.count = (state.count ?? 0) + 1; state.list = [\"a\", \"b\"]; let seen = state.count;",
+            RhaiSandboxConfig::default(),
+        )
+        .with_state(Some(StateConfig::default()))
+        .unwrap();
+
+        assert_eq!(run(&script).unwrap().as_int(), Ok(1));
+        assert_eq!(run(&script).unwrap().as_int(), Ok(2));
+        assert_eq!(state(&script), json!({ "count": 2, "list": ["a", "b"] }));
+    }
+
+    #[test]
+    fn failed_run_discards_state() {
+        let script = script(
+            "state.count = (state.count ?? 0) + 1; if state.count > 1 { throw \"fail\" }",
+            RhaiSandboxConfig::default(),
+        )
+        .with_state(Some(StateConfig::default()))
+        .unwrap();
+
+        assert!(run(&script).is_ok());
+        assert!(run(&script).is_err());
+        assert_eq!(state(&script), json!({ "count": 1 }));
+    }
+
+    #[test]
+    fn state_is_restored_from_snapshot() {
+        let path = std::env::temp_dir().join(format!(
+            "postoffice-test-{}-rhai-state.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let config = StateConfig {
+            snapshot: Some(path.to_string_lossy().to_string()),
+        };
+        let source = "state.count = (state.count ?? 0) + 1; let seen = state.count;";
+
+        let first = script(source, RhaiSandboxConfig::default())
+            .with_state(Some(config.clone()))
+            .unwrap();
+        assert_eq!(run(&first).unwrap().as_int(), Ok(1));
+        first.snapshot().unwrap();
+
+        let second = script(source, RhaiSandboxConfig::default())
+            .with_state(Some(config))
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(run(&second).unwrap().as_int(), Ok(2));
+    }
+
+    #[test]
+    fn max_operations_stops_script() {
+        let script = script(
+            "loop {}",
+            RhaiSandboxConfig {
+                max_operations: Some(10_000),
+                timeout: None,
+            },
+        );
+
+        assert!(run(&script).is_err());
+    }
+
+    #[test]
+    fn timeout_stops_script() {
+        let script = script(
+            "loop {}",
+            RhaiSandboxConfig {
+                max_operations: None,
+                timeout: Some(50),
+            },
+        );
+
+        let err = run(&script).unwrap_err();
+        assert_eq!(err.to_string(), "Script exceeded its timeout");
+    }
+
+    #[test]
+    fn strings_are_limited() {
+        let script = script(
+            "let s = \"x\"; loop { s += s; }",
+            RhaiSandboxConfig::default(),
+        );
+
+        assert!(run(&script).is_err());
+    }
+
+    #[test]
+    fn library() {
+        let script = script(
+            r#"let seen = [
+                postoffice::json::encode(#{ a: [1, 2] }),
+                postoffice::json::decode("{\"b\": true}").b,
+                postoffice::base64::encode("hi"),
+                postoffice::base64::decode("aGk=").len(),
+                postoffice::hex::encode("hi"),
+                postoffice::hex::decode("6869").len(),
+                postoffice::regex::is_match("^/fader/\\d+$", "/fader/1"),
+                postoffice::regex::find("\\d+", "/fader/12"),
+                postoffice::regex::captures("^/(?P<device>\\w+)/", "/mixer/1").device,
+                postoffice::regex::replace("\\d", "a1b2", "_"),
+                postoffice::now() > 0,
+            ];
+            postoffice::log::info("done");"#,
+            RhaiSandboxConfig::default(),
+        );
+
+        let seen: serde_json::Value = rhai::serde::from_dynamic(&run(&script).unwrap()).unwrap();
+
+        assert_eq!(
+            seen,
+            json!([
+                "{\"a\":[1,2]}",
+                true,
+                "aGk=",
+                2,
+                "6869",
+                2,
+                true,
+                "12",
+                "mixer",
+                "a_b_",
+                true
+            ])
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rhai::{Dynamic, Engine, ImmutableString};
+
+use crate::message::{InternalMessage, InternalMessageData};
+
+use super::{
+    Block,
+    rhai_data::{data_from_rhai, data_to_rhai},
+    rhai_script::{RhaiConfig, RhaiSandboxConfig, RhaiScript, with_output},
+    script_state::StateConfig,
+};
+
+pub struct RhaiTransformBlock {
+    pub script: Arc<RhaiScript>,
+}
+
+impl RhaiTransformBlock {
+    pub fn new(
+        config: RhaiConfig,
+        state: Option<StateConfig>,
+        sandbox: RhaiSandboxConfig,
+    ) -> anyhow::Result<RhaiTransformBlock> {
+        Ok(RhaiTransformBlock {
+            script: Arc::new(RhaiScript::new(config, sandbox, setup)?.with_state(state)?),
+        })
+    }
+}
+
+fn setup(engine: &mut Engine) {
+    engine.register_fn("emit", |topic: ImmutableString, data: Dynamic| {
+        with_output(|output| output.emitted.push((topic.to_string(), data, None)))
+    });
+    engine.register_fn(
+        "emit",
+        |topic: ImmutableString, data: Dynamic, data_type: ImmutableString| {
+            with_output(|output| {
+                output
+                    .emitted
+                    .push((topic.to_string(), data, Some(data_type.to_string())))
+            })
+        },
+    );
+}
+
+/// What the script left behind: the emitted messages, or the new `topic` and `data` variables
+enum Output {
+    Emitted(Vec<(String, InternalMessageData)>),
+    Changed(Option<String>, InternalMessageData),
+}
+
+#[async_trait]
+impl Block for RhaiTransformBlock {
+    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let (message, output) = self
+            .script
+            .run(
+                message,
+                |scope, message| {
+                    scope.push("topic", message.topic.clone());
+                    scope.push("data", data_to_rhai(&message.data)?);
+                    scope.push("data_type", Dynamic::from(message.data.type_name()));
+
+                    Ok(())
+                },
+                |scope, output, message| {
+                    if !output.emitted.is_empty() {
+                        let mut messages = vec![];
+
+                        for (topic, data, data_type) in output.emitted {
+                            // Emitted messages have the type of the incoming message by default
+                            let data_type =
+                                data_type.unwrap_or_else(|| message.data.type_name().to_string());
+
+                            messages.push((topic, data_from_rhai(data, &data_type)?));
+                        }
+
+                        return Ok(Output::Emitted(messages));
+                    }
+
+                    let topic = scope.get_value::<Dynamic>("topic").unwrap_or(Dynamic::UNIT);
+                    let data = scope.get_value::<Dynamic>("data").unwrap_or(Dynamic::UNIT);
+                    let data_type = scope
+                        .get_value::<Dynamic>("data_type")
+                        .map(|data_type| data_type.to_string())
+                        .unwrap_or_default();
+
+                    // Setting `topic` to `()` drops the message
+                    let topic = if topic.is_unit() {
+                        None
+                    } else {
+                        Some(topic.into_string().map_err(|type_name| {
+                            anyhow::Error::msg(format!("topic must be a string, got {}", type_name))
+                        })?)
+                    };
+
+                    Ok(Output::Changed(topic, data_from_rhai(data, &data_type)?))
+                },
+            )
+            .await?;
+
+        match output {
+            Output::Emitted(messages) => Ok(messages
+                .into_iter()
+                .map(|(topic, data)| InternalMessage {
+                    topic,
+                    data,
+                    ..message.clone()
+                })
+                .collect()),
+            Output::Changed(None, _) => Ok(vec![]),
+            Output::Changed(Some(topic), data) => Ok(vec![InternalMessage {
+                topic,
+                data,
+                ..message
+            }]),
+        }
+    }
+
+    async fn shutdown(self: &Self) -> anyhow::Result<()> {
+        self.script.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(script: &str) -> RhaiTransformBlock {
+        RhaiTransformBlock::new(
+            RhaiConfig::Inline(script.to_string()),
+            None,
+            RhaiSandboxConfig::default(),
+        )
+        .unwrap()
+    }
+
+    fn message(topic: &str, data: &str) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 1,
+            topic: topic.to_string(),
+            data: InternalMessageData::String(data.to_string()),
+            dead_letter: false,
+            hops: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_topic_and_data() {
+        let block =
+            block(r#"topic = "/new" + topic; data = #{ value: data }; data_type = "JSON";"#);
+
+        let messages = block.exec(message("/a", "x")).await.unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "/new/a");
+        assert!(matches!(
+            &messages[0].data,
+            InternalMessageData::Json(value) if *value == serde_json::json!({ "value": "x" })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unit_topic_drops_message() {
+        let block = block("topic = ();");
+
+        assert!(block.exec(message("/a", "x")).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn emit_replaces_message() {
+        let block = block(
+            r#"topic = "/ignored";
+            emit(topic + "/x", data);
+            emit("/y", [1.5], "OSC");"#,
+        );
+
+        let messages = block.exec(message("/a", "x")).await.unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].topic, "/ignored/x");
+        assert!(matches!(&messages[0].data, InternalMessageData::String(s) if s == "x"));
+        assert_eq!(messages[1].topic, "/y");
+        assert!(matches!(&messages[1].data, InternalMessageData::OSC(args) if args.len() == 1));
+        assert_eq!(messages[1].source_connector_idx, 1);
+    }
+}
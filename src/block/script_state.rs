@@ -0,0 +1,72 @@
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StateConfig {
+    /// Restores the state from this file on startup and writes it back on shutdown
+    pub snapshot: Option<String>,
+}
+
+/// The `state` of a script block, stored as JSON so every run of the script sees the same state
+pub struct ScriptState {
+    pub value: Mutex<Value>,
+    snapshot: Option<PathBuf>,
+    /// The language of the script, e.g. `Lua`, for error messages and logs
+    language: &'static str,
+}
+
+impl ScriptState {
+    pub fn new(config: StateConfig, language: &'static str) -> anyhow::Result<Self> {
+        let snapshot = config.snapshot.map(PathBuf::from);
+
+        let value = match snapshot {
+            Some(ref path) if path.exists() => {
+                let data = fs::read_to_string(path).context(format!(
+                    "Failed to read {} state snapshot {:?}",
+                    language, path
+                ))?;
+
+                info!(snapshot = ?path, "Restored {} state", language);
+
+                serde_json::from_str(&data)
+                    .context(format!("Invalid {} state snapshot {:?}", language, path))?
+            }
+            _ => json!({}),
+        };
+
+        return Ok(Self {
+            value: Mutex::new(value),
+            snapshot,
+            language,
+        });
+    }
+
+    /// Writes the state to its snapshot file, if there is one
+    pub fn snapshot(self: &Self) -> anyhow::Result<()> {
+        let Some(ref path) = self.snapshot else {
+            return Ok(());
+        };
+
+        let data =
+            serde_json::to_string_pretty(&*self.value.lock().expect("Script state lock poisoned"))?;
+
+        // Write to a temporary file first, so a crash can't leave a half written snapshot behind
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).context(format!(
+            "Failed to write {} state snapshot {:?}",
+            self.language, tmp
+        ))?;
+        fs::rename(&tmp, path).context(format!(
+            "Failed to write {} state snapshot {:?}",
+            self.language, path
+        ))?;
+
+        info!(snapshot = ?path, "Saved {} state", self.language);
+
+        return Ok(());
+    }
+}
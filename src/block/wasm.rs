@@ -0,0 +1,616 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TrapCode, TypedFunc,
+};
+
+use crate::message::{InternalMessage, InternalMessageData};
+
+use super::{Block, blocking::run_blocking};
+
+/// Module name of the host functions a plugin can import
+const HOST_MODULE: &str = "postoffice";
+
+/// Messages a plugin may emit per message, like the Rhai limits on arrays
+const MAX_EMITTED: usize = 1024;
+/// Bytes of topics and bodies a plugin may emit per message
+const MAX_EMITTED_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct WasmConfig {
+    /// Path to a `.wasm` or `.wat` file
+    pub path: String,
+    /// Fuel a plugin may consume per message, roughly one unit per instruction
+    #[serde(default = "default_fuel")]
+    pub fuel: Option<u64>,
+    /// Bytes of linear memory an instance may use
+    #[serde(default = "default_max_memory")]
+    pub max_memory: Option<usize>,
+}
+
+fn default_fuel() -> Option<u64> {
+    Some(10_000_000)
+}
+
+fn default_max_memory() -> Option<usize> {
+    Some(64 * 1024 * 1024)
+}
+
+/// The message a plugin currently handles and the messages it returned
+#[derive(Default)]
+struct HostState {
+    limits: StoreLimits,
+    source_connector_idx: usize,
+    hops: u32,
+    topic: Vec<u8>,
+    data_type: u8,
+    data: Vec<u8>,
+    forward: bool,
+    emitted: Vec<(String, InternalMessageData)>,
+    emitted_size: usize,
+    error: Option<String>,
+}
+
+struct WasmInstance {
+    store: Store<HostState>,
+    handle: TypedFunc<(), i32>,
+    /// A trapped instance may have been left in any state
+    trapped: bool,
+}
+
+struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+    linker: Linker<HostState>,
+    fuel: Option<u64>,
+    max_memory: Option<usize>,
+    /// Idle instances, like the VMs of `LuaScript`
+    pool: Mutex<Vec<WasmInstance>>,
+}
+
+pub struct WasmBlock {
+    plugin: Arc<WasmPlugin>,
+}
+
+impl WasmBlock {
+    pub fn new(config: WasmConfig) -> anyhow::Result<WasmBlock> {
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(config.fuel.is_some());
+        let engine = Engine::new(&engine_config);
+
+        let wasm = std::fs::read(&config.path)
+            .context(format!("Failed to read Wasm plugin {:?}", config.path))?;
+        let module = Module::new(&engine, wasm)
+            .context(format!("Failed to compile Wasm plugin {:?}", config.path))?;
+
+        let mut linker = Linker::new(&engine);
+        link_host(&mut linker)?;
+
+        let plugin = WasmPlugin {
+            engine,
+            module,
+            linker,
+            fuel: config.fuel,
+            max_memory: config.max_memory,
+            pool: Mutex::new(vec![]),
+        };
+
+        // Fails early if the plugin doesn't implement the ABI
+        let instance = plugin.instantiate().context(format!(
+            "Failed to instantiate Wasm plugin {:?}",
+            config.path
+        ))?;
+        plugin
+            .pool
+            .lock()
+            .expect("Wasm pool lock poisoned")
+            .push(instance);
+
+        return Ok(WasmBlock {
+            plugin: Arc::new(plugin),
+        });
+    }
+}
+
+impl WasmPlugin {
+    fn instantiate(self: &Self) -> anyhow::Result<WasmInstance> {
+        let mut limits = StoreLimitsBuilder::new();
+        if let Some(max_memory) = self.max_memory {
+            limits = limits.memory_size(max_memory);
+        }
+
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                limits: limits.build(),
+                ..HostState::default()
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+
+        if let Some(fuel) = self.fuel {
+            store.set_fuel(fuel)?;
+        }
+
+        let instance = self
+            .linker
+            .instantiate_and_start(&mut store, &self.module)?;
+
+        instance
+            .get_memory(&store, "memory")
+            .context("Plugin doesn't export `memory`")?;
+        let handle = instance
+            .get_typed_func::<(), i32>(&store, "handle")
+            .context("Plugin doesn't export `handle() -> i32`")?;
+
+        return Ok(WasmInstance {
+            store,
+            handle,
+            trapped: false,
+        });
+    }
+
+    fn run(self: &Self, message: &InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let instance = self.pool.lock().expect("Wasm pool lock poisoned").pop();
+
+        let mut instance = match instance {
+            Some(instance) => instance,
+            None => self.instantiate()?,
+        };
+
+        let res = self.run_in(&mut instance, message);
+
+        if !instance.trapped {
+            self.pool
+                .lock()
+                .expect("Wasm pool lock poisoned")
+                .push(instance);
+        }
+
+        return res;
+    }
+
+    fn run_in(
+        self: &Self,
+        instance: &mut WasmInstance,
+        message: &InternalMessage,
+    ) -> anyhow::Result<Vec<InternalMessage>> {
+        let (data_type, data) = message.data.encode()?;
+
+        let state = instance.store.data_mut();
+        state.source_connector_idx = message.source_connector_idx;
+        state.hops = message.hops;
+        state.topic = message.topic.as_bytes().to_vec();
+        state.data_type = data_type;
+        state.data = data;
+        state.forward = false;
+        state.emitted.clear();
+        state.emitted_size = 0;
+        state.error = None;
+
+        if let Some(fuel) = self.fuel {
+            instance.store.set_fuel(fuel)?;
+        }
+
+        let status = instance.handle.call(&mut instance.store, ()).map_err(|e| {
+            instance.trapped = true;
+
+            match e.as_trap_code() {
+                Some(TrapCode::OutOfFuel) => anyhow::Error::msg("Plugin ran out of fuel"),
+                _ => anyhow::Error::msg(format!("Plugin trapped: {}", e)),
+            }
+        })?;
+
+        let state = instance.store.data_mut();
+
+        if status != 0 {
+            return Err(anyhow::Error::msg(match state.error.take() {
+                Some(error) => format!("Plugin failed with status {}: {}", status, error),
+                None => format!("Plugin failed with status {}", status),
+            }));
+        }
+
+        let mut messages = vec![];
+
+        if state.forward {
+            messages.push(message.clone());
+        }
+
+        for (topic, data) in state.emitted.drain(..) {
+            messages.push(InternalMessage {
+                topic,
+                data,
+                ..message.clone()
+            });
+        }
+
+        return Ok(messages);
+    }
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("Plugin doesn't export `memory`"))
+}
+
+fn read(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let start = ptr as u32 as usize;
+    let end = start + len as u32 as usize;
+
+    // Checks the range before copying, so a bogus length can't allocate a huge buffer
+    memory(caller)?
+        .data(caller)
+        .get(start..end)
+        .map(|data| data.to_vec())
+        .ok_or_else(|| wasmi::Error::new("Plugin passed a range outside of its memory"))
+}
+
+fn write(caller: &mut Caller<'_, HostState>, ptr: i32, data: &[u8]) -> Result<(), wasmi::Error> {
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, data)
+        .map_err(|e| wasmi::Error::new(e.to_string()))
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    String::from_utf8(read(caller, ptr, len)?).map_err(|e| wasmi::Error::new(e.to_string()))
+}
+
+/// Defines the host functions, see the README for the ABI
+fn link_host(linker: &mut Linker<HostState>) -> anyhow::Result<()> {
+    linker.func_wrap(HOST_MODULE, "topic_len", |caller: Caller<'_, HostState>| {
+        caller.data().topic.len() as i32
+    })?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "read_topic",
+        |mut caller: Caller<'_, HostState>, ptr: i32| {
+            let topic = std::mem::take(&mut caller.data_mut().topic);
+            let res = write(&mut caller, ptr, &topic);
+            caller.data_mut().topic = topic;
+            res
+        },
+    )?;
+    linker.func_wrap(HOST_MODULE, "data_type", |caller: Caller<'_, HostState>| {
+        caller.data().data_type as i32
+    })?;
+    linker.func_wrap(HOST_MODULE, "data_len", |caller: Caller<'_, HostState>| {
+        caller.data().data.len() as i32
+    })?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "read_data",
+        |mut caller: Caller<'_, HostState>, ptr: i32| {
+            let data = std::mem::take(&mut caller.data_mut().data);
+            let res = write(&mut caller, ptr, &data);
+            caller.data_mut().data = data;
+            res
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "source_connector",
+        |caller: Caller<'_, HostState>| caller.data().source_connector_idx as i32,
+    )?;
+    linker.func_wrap(HOST_MODULE, "hops", |caller: Caller<'_, HostState>| {
+        caller.data().hops as i32
+    })?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "forward",
+        |mut caller: Caller<'_, HostState>| {
+            caller.data_mut().forward = true;
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "emit",
+        |mut caller: Caller<'_, HostState>,
+         topic_ptr: i32,
+         topic_len: i32,
+         data_ptr: i32,
+         data_len: i32,
+         data_type: i32| {
+            let state = caller.data();
+            let size = state.emitted_size + topic_len as u32 as usize + data_len as u32 as usize;
+
+            if state.emitted.len() >= MAX_EMITTED {
+                return Err(wasmi::Error::new(format!(
+                    "Plugin emitted more than {} messages",
+                    MAX_EMITTED
+                )));
+            }
+            if size > MAX_EMITTED_SIZE {
+                return Err(wasmi::Error::new(format!(
+                    "Plugin emitted more than {} bytes",
+                    MAX_EMITTED_SIZE
+                )));
+            }
+
+            let topic = read_string(&caller, topic_ptr, topic_len)?;
+            let data = read(&caller, data_ptr, data_len)?;
+            let data = InternalMessageData::decode(data_type as u8, &data)
+                .map_err(|e| wasmi::Error::new(format!("Invalid body from plugin: {:#}", e)))?;
+
+            let state = caller.data_mut();
+            state.emitted.push((topic, data));
+            state.emitted_size = size;
+
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "log",
+        |caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| {
+            let text = read_string(&caller, ptr, len)?;
+
+            match level {
+                0 => debug!("{}", text),
+                1 => info!("{}", text),
+                2 => warn!("{}", text),
+                _ => error!("{}", text),
+            }
+
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "fail",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let error = read_string(&caller, ptr, len)?;
+            caller.data_mut().error = Some(error);
+
+            Ok(())
+        },
+    )?;
+
+    return Ok(());
+}
+
+#[async_trait]
+impl Block for WasmBlock {
+    async fn exec(self: &Self, message: InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        let plugin = self.plugin.clone();
+
+        // Plugins run like Lua and Rhai scripts, without holding up other tasks
+        return run_blocking(move || plugin.run(&message)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The host functions that the test plugins import
+    const IMPORTS: &str = r#"
+        (import "postoffice" "topic_len" (func $topic_len (result i32)))
+        (import "postoffice" "read_topic" (func $read_topic (param i32)))
+        (import "postoffice" "data_type" (func $data_type (result i32)))
+        (import "postoffice" "data_len" (func $data_len (result i32)))
+        (import "postoffice" "read_data" (func $read_data (param i32)))
+        (import "postoffice" "forward" (func $forward))
+        (import "postoffice" "emit" (func $emit (param i32 i32 i32 i32 i32)))
+        (import "postoffice" "fail" (func $fail (param i32 i32)))
+    "#;
+
+    /// Creates a block from the body of a WAT module, which has one page of memory
+    fn plugin(name: &str, body: &str, fuel: Option<u64>, max_memory: Option<usize>) -> WasmBlock {
+        let path = std::env::temp_dir().join(format!(
+            "postoffice-test-{}-{}.wat",
+            std::process::id(),
+            name
+        ));
+        let wat = format!(
+            "(module {} (memory (export \"memory\") 1) {})",
+            IMPORTS, body
+        );
+        std::fs::write(&path, wat).expect("Failed to write plugin");
+
+        let block = WasmBlock::new(WasmConfig {
+            path: path.to_string_lossy().to_string(),
+            fuel,
+            max_memory,
+        });
+        let _ = std::fs::remove_file(&path);
+
+        block.expect("Failed to create plugin")
+    }
+
+    fn message(topic: &str, data: &str) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 3,
+            topic: topic.to_string(),
+            data: InternalMessageData::String(data.to_string()),
+            dead_letter: false,
+            hops: 2,
+        }
+    }
+
+    fn run(block: &WasmBlock, message: &InternalMessage) -> anyhow::Result<Vec<InternalMessage>> {
+        block.plugin.run(message)
+    }
+
+    fn pooled(block: &WasmBlock) -> usize {
+        block.plugin.pool.lock().unwrap().len()
+    }
+
+    #[test]
+    fn reads_and_emits_message() {
+        // Emits the topic and body it read back as a new message
+        let block = plugin(
+            "echo",
+            r#"(func (export "handle") (result i32)
+                (call $read_topic (i32.const 0))
+                (call $read_data (i32.const 1024))
+                (call $emit
+                    (i32.const 0) (call $topic_len)
+                    (i32.const 1024) (call $data_len)
+                    (call $data_type))
+                (i32.const 0))"#,
+            default_fuel(),
+            default_max_memory(),
+        );
+
+        let messages = run(&block, &message("/echo", "hello")).unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "/echo");
+        assert!(matches!(&messages[0].data, InternalMessageData::String(s) if s == "hello"));
+        // Emitted messages keep the source and hops
+        assert_eq!(messages[0].source_connector_idx, 3);
+        assert_eq!(messages[0].hops, 2);
+    }
+
+    #[test]
+    fn forwards_or_drops_message() {
+        // Forwards messages with a topic of two bytes, drops all others
+        let block = plugin(
+            "forward",
+            r#"(func (export "handle") (result i32)
+                (if (i32.eq (call $topic_len) (i32.const 2)) (then (call $forward)))
+                (i32.const 0))"#,
+            default_fuel(),
+            default_max_memory(),
+        );
+
+        let messages = run(&block, &message("/a", "data")).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].topic, "/a");
+
+        assert!(run(&block, &message("/ab", "data")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fail_sets_error() {
+        let block = plugin(
+            "fail",
+            r#"(data (i32.const 0) "nope")
+            (func (export "handle") (result i32)
+                (call $fail (i32.const 0) (i32.const 4))
+                (i32.const 2))"#,
+            default_fuel(),
+            default_max_memory(),
+        );
+
+        let err = run(&block, &message("/a", "data")).unwrap_err();
+
+        assert_eq!(err.to_string(), "Plugin failed with status 2: nope");
+        // Failing without a trap keeps the instance
+        assert_eq!(pooled(&block), 1);
+    }
+
+    #[test]
+    fn out_of_fuel() {
+        let block = plugin(
+            "fuel",
+            r#"(func (export "handle") (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0))"#,
+            Some(10_000),
+            default_max_memory(),
+        );
+
+        let err = run(&block, &message("/a", "data")).unwrap_err();
+
+        assert_eq!(err.to_string(), "Plugin ran out of fuel");
+    }
+
+    #[test]
+    fn memory_limit() {
+        // Returns 1 if the memory can't grow by another page
+        let block = plugin(
+            "memory",
+            r#"(func (export "handle") (result i32)
+                (i32.eq (memory.grow (i32.const 1)) (i32.const -1)))"#,
+            default_fuel(),
+            Some(2 * 65536),
+        );
+
+        assert!(run(&block, &message("/a", "data")).is_ok());
+
+        let err = run(&block, &message("/a", "data")).unwrap_err();
+        assert_eq!(err.to_string(), "Plugin failed with status 1");
+    }
+
+    #[test]
+    fn discards_trapped_instance() {
+        // Counts the messages in a global and emits the count, traps for the topic `/trap`
+        let block = plugin(
+            "trap",
+            r#"(global $count (mut i32) (i32.const 0))
+            (data (i32.const 0) "/n")
+            (func (export "handle") (result i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (if (i32.eq (call $topic_len) (i32.const 5)) (then unreachable))
+                (i32.store8 (i32.const 16) (i32.add (i32.const 48) (global.get $count)))
+                (call $emit (i32.const 0) (i32.const 2) (i32.const 16) (i32.const 1) (i32.const 1))
+                (i32.const 0))"#,
+            default_fuel(),
+            default_max_memory(),
+        );
+
+        let count = |block: &WasmBlock| match &run(block, &message("/a", "")).unwrap()[0].data {
+            InternalMessageData::String(s) => s.clone(),
+            data => panic!("Unexpected body {:?}", data),
+        };
+
+        assert_eq!(count(&block), "1");
+        assert_eq!(count(&block), "2");
+
+        let err = run(&block, &message("/trap", "")).unwrap_err();
+        assert!(err.to_string().starts_with("Plugin trapped"), "{}", err);
+        assert_eq!(pooled(&block), 0);
+
+        // A new instance starts counting from the beginning
+        assert_eq!(count(&block), "1");
+    }
+
+    #[test]
+    fn emitted_messages_are_capped() {
+        let block = plugin(
+            "emit_cap",
+            r#"(func (export "handle") (result i32)
+                (local $i i32)
+                (loop $emit_more
+                    (call $emit (i32.const 0) (i32.const 1) (i32.const 0) (i32.const 0) (i32.const 0))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $emit_more (i32.lt_u (local.get $i) (i32.const 2000))))
+                (i32.const 0))"#,
+            default_fuel(),
+            default_max_memory(),
+        );
+
+        let err = run(&block, &message("/a", "data")).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("Plugin emitted more than 1024 messages"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_module_without_abi() {
+        let path =
+            std::env::temp_dir().join(format!("postoffice-test-{}-no-abi.wat", std::process::id()));
+        std::fs::write(&path, "(module (memory (export \"memory\") 1))").unwrap();
+
+        let res = WasmBlock::new(WasmConfig {
+            path: path.to_string_lossy().to_string(),
+            fuel: default_fuel(),
+            max_memory: default_max_memory(),
+        });
+        let _ = std::fs::remove_file(&path);
+
+        assert!(res.is_err());
+    }
+}
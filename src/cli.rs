@@ -1,9 +1,7 @@
 use std::fs;
 
-use clap::Parser;
-use serde::Deserialize;
-
-use crate::{block::BlockConfig, connector::ConnectorConfig};
+use clap::{Parser, Subcommand, ValueEnum};
+use postoffice::Config;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -14,14 +12,81 @@ pub struct Args {
     #[arg(long)]
     pub ignore_cycles: bool,
 
+    /// Log every message with its full content. Shortcut for `RUST_LOG=info,postoffice=trace`
     #[arg(long)]
     pub debug: bool,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Full)]
+    pub log_format: LogFormat,
+
+    /// Serve the admin endpoints (`/healthz`, `/readyz`, `/metrics`) on this port
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+
+    /// Interface for the admin endpoints
+    #[arg(long, default_value_t = String::from("0.0.0.0"))]
+    pub admin_interface: String,
+
+    /// Time in milliseconds to wait for all required connectors to become ready
+    #[arg(long, default_value_t = 30000)]
+    pub startup_timeout: u64,
+
+    /// Time in milliseconds to wait for in-flight messages on shutdown
+    #[arg(long, default_value_t = 5000)]
+    pub shutdown_timeout: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print the graph of connectors and blocks in the config instead of running it
+    Graph {
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Send messages through the blocks of the config and measure the throughput. Connectors are
+    /// not started, messages for sinks are discarded.
+    Bench(BenchArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Index of the block that receives the messages
+    #[arg(long, default_value_t = 0)]
+    pub block: usize,
+
+    #[arg(long, default_value_t = 10000)]
+    pub messages: usize,
+
+    /// Number of messages that are handled at the same time
+    #[arg(long, default_value_t = 1)]
+    pub concurrency: usize,
+
+    #[arg(long, default_value_t = String::from("/bench"))]
+    pub topic: String,
+
+    /// JSON body of every message
+    #[arg(long, default_value_t = String::from("[0.5]"))]
+    pub data: String,
+
+    /// Runs every message in a new Lua VM that parses the script again, like before VMs were reused
+    #[arg(long)]
+    pub fresh_vm: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub connectors: Vec<ConnectorConfig>,
-    pub blocks: Vec<BlockConfig>,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Json,
 }
 
 pub fn get_config(args: &Args) -> anyhow::Result<Config> {
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConcurrencyConfig {
+    /// Maximum number of incoming messages that are processed at the same time
+    pub max_tasks: Option<usize>,
+    /// Maximum number of incoming messages from one source that are processed at the same time.
+    /// Can be overridden with the `max_tasks` option of a connector.
+    pub max_tasks_per_source: Option<usize>,
+    #[serde(default)]
+    pub saturated: SaturationPolicy,
+}
+
+/// What happens to an incoming message while a limit is reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum SaturationPolicy {
+    /// Hold the message back until a task finished
+    #[default]
+    Wait,
+    /// Drop the message
+    Drop,
+}
+
+/// The limit that kept a message from being admitted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitReached {
+    /// `max_tasks`, no source can start a task
+    Global,
+    /// The limit of the source of the message
+    Source,
+}
+
+/// Permits that a pipeline task holds while it runs
+#[derive(Debug)]
+pub struct Admission {
+    _global: Option<OwnedSemaphorePermit>,
+    _source: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub saturated: SaturationPolicy,
+    global: Option<Arc<Semaphore>>,
+    /// The semaphore of every source by its connector index
+    per_source: Arc<Vec<Option<Arc<Semaphore>>>>,
+}
+
+impl Limits {
+    /// `per_source` holds the `max_tasks` option of every connector by its index
+    pub fn new(config: &ConcurrencyConfig, per_source: Vec<Option<usize>>) -> Self {
+        Self {
+            saturated: config.saturated,
+            global: config
+                .max_tasks
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            per_source: Arc::new(
+                per_source
+                    .into_iter()
+                    .map(|max| {
+                        max.or(config.max_tasks_per_source)
+                            .map(|max| Arc::new(Semaphore::new(max.max(1))))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn source(self: &Self, idx: usize) -> Option<Arc<Semaphore>> {
+        self.per_source.get(idx).cloned().flatten()
+    }
+
+    /// Returns which limit is reached if the source with index `idx` can't start another task
+    pub fn try_admit(self: &Self, idx: usize) -> Result<Admission, LimitReached> {
+        let global = match self.global {
+            Some(ref semaphore) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| LimitReached::Global)?,
+            ),
+            None => None,
+        };
+
+        let source = match self.source(idx) {
+            Some(semaphore) => Some(
+                semaphore
+                    .try_acquire_owned()
+                    .map_err(|_| LimitReached::Source)?,
+            ),
+            None => None,
+        };
+
+        Ok(Admission {
+            _global: global,
+            _source: source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_tasks: Option<usize>, per_source: Option<usize>) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            max_tasks,
+            max_tasks_per_source: per_source,
+            saturated: SaturationPolicy::Wait,
+        }
+    }
+
+    #[test]
+    fn global_limit_applies_to_all_sources() {
+        let limits = Limits::new(&limits(Some(2), None), vec![None, None]);
+
+        let _a = limits.try_admit(0).unwrap();
+        let _b = limits.try_admit(1).unwrap();
+
+        assert_eq!(limits.try_admit(0).unwrap_err(), LimitReached::Global);
+        assert_eq!(limits.try_admit(1).unwrap_err(), LimitReached::Global);
+    }
+
+    #[test]
+    fn source_limit_only_applies_to_its_source() {
+        let limits = Limits::new(&limits(None, Some(1)), vec![None, None]);
+
+        let _a = limits.try_admit(0).unwrap();
+
+        assert_eq!(limits.try_admit(0).unwrap_err(), LimitReached::Source);
+        assert!(limits.try_admit(1).is_ok());
+    }
+
+    #[test]
+    fn connector_overrides_source_limit() {
+        let limits = Limits::new(&limits(None, Some(1)), vec![Some(2), None]);
+
+        let _a = limits.try_admit(0).unwrap();
+        let _b = limits.try_admit(0).unwrap();
+
+        assert_eq!(limits.try_admit(0).unwrap_err(), LimitReached::Source);
+    }
+
+    #[test]
+    fn source_limit_doesnt_take_global_permit() {
+        let limits = Limits::new(&limits(Some(2), Some(1)), vec![None, None]);
+
+        let _a = limits.try_admit(0).unwrap();
+        assert_eq!(limits.try_admit(0).unwrap_err(), LimitReached::Source);
+
+        // The rejected message must not hold on to the second global permit
+        let _b = limits.try_admit(1).unwrap();
+        assert_eq!(limits.try_admit(1).unwrap_err(), LimitReached::Global);
+    }
+
+    #[test]
+    fn dropping_admission_releases_permits() {
+        let limits = Limits::new(&limits(Some(1), Some(1)), vec![None]);
+
+        let admission = limits.try_admit(0).unwrap();
+        assert!(limits.try_admit(0).is_err());
+
+        drop(admission);
+        assert!(limits.try_admit(0).is_ok());
+    }
+}
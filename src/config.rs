@@ -0,0 +1,28 @@
+use serde::Deserialize;
+
+use crate::{
+    block::{BlockConfig, Connection},
+    concurrency::ConcurrencyConfig,
+    connector::ConnectorConfig,
+    queue::QueueConfig,
+    router::Ordering,
+};
+
+/// The content of a config file
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub connectors: Vec<ConnectorConfig>,
+    pub blocks: Vec<BlockConfig>,
+    /// Receives messages that a sink failed to deliver, unless the sink has its own `dead_letter`
+    pub dead_letter: Option<Vec<Connection>>,
+    /// Capacity and overflow policy of the queue that all sources share
+    #[serde(default)]
+    pub source_queue: QueueConfig,
+    #[serde(default)]
+    pub ordering: Ordering,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    /// Messages that passed more blocks than this are dropped. Unlimited unless cycles are
+    /// ignored, see `Pipeline::new`.
+    pub max_hops: Option<u32>,
+}
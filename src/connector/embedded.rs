@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, mpsc};
+
+use crate::{
+    lifecycle::LifeCycleMessage,
+    message::{InternalMessage, InternalMessageData},
+};
+
+use super::{ConnectorContext, SinkRX};
+
+/// Connects the application that embeds postoffice, see `PostofficeBuilder::input` and
+/// `PostofficeBuilder::output`
+#[derive(Debug, Clone)]
+pub enum EmbeddedConnectorConfig {
+    /// Receives the messages sent through an `Input`
+    Input(Arc<Mutex<mpsc::Receiver<(String, InternalMessageData)>>>),
+    /// Hands the messages for this sink to an `Output`
+    Output(mpsc::Sender<InternalMessage>),
+}
+
+/// Sends messages into postoffice as if they were received by a source connector
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub idx: usize,
+    tx: mpsc::Sender<(String, InternalMessageData)>,
+}
+
+impl Input {
+    pub fn new(idx: usize, capacity: usize) -> (Self, EmbeddedConnectorConfig) {
+        let (tx, rx) = mpsc::channel(capacity);
+
+        return (
+            Self { idx, tx },
+            EmbeddedConnectorConfig::Input(Arc::new(Mutex::new(rx))),
+        );
+    }
+
+    /// Waits while the source queue is full. Fails once postoffice shuts down.
+    pub async fn send(
+        self: &Self,
+        topic: impl Into<String>,
+        data: InternalMessageData,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send((topic.into(), data))
+            .await
+            .map_err(|_| anyhow::Error::msg("Postoffice is shut down"))
+    }
+}
+
+/// Receives the messages that are routed to a sink
+#[derive(Debug)]
+pub struct Output {
+    pub idx: usize,
+    rx: mpsc::Receiver<InternalMessage>,
+}
+
+impl Output {
+    pub fn new(idx: usize, capacity: usize) -> (Self, EmbeddedConnectorConfig) {
+        let (tx, rx) = mpsc::channel(capacity);
+
+        return (Self { idx, rx }, EmbeddedConnectorConfig::Output(tx));
+    }
+
+    /// Returns `None` once postoffice shut down and every message was received
+    pub async fn recv(self: &mut Self) -> Option<InternalMessage> {
+        self.rx.recv().await
+    }
+}
+
+pub async fn make_embedded_connector(
+    ctx: ConnectorContext,
+    config: EmbeddedConnectorConfig,
+    mut sink_rx: SinkRX,
+) -> anyhow::Result<()> {
+    let ConnectorContext {
+        idx,
+        source_tx,
+        lifecycle_tx,
+        mut shutdown,
+        ..
+    } = ctx;
+
+    lifecycle_tx
+        .send(LifeCycleMessage::Ready { idx })
+        .await
+        .expect("Failed to send LifeCycleMessage");
+
+    match config {
+        EmbeddedConnectorConfig::Input(rx) => {
+            let mut rx = rx.lock().await;
+
+            loop {
+                let (topic, data) = tokio::select! {
+                    Some(message) = rx.recv() => message,
+                    _ = shutdown.recv() => break,
+                };
+
+                let message = InternalMessage {
+                    source_connector_idx: idx,
+                    topic,
+                    data,
+                    dead_letter: false,
+                    hops: 0,
+                };
+
+                if source_tx.send(message).await.is_err() {
+                    // This can only happen if `source_rx` was closed during shutdown
+                    break;
+                }
+            }
+
+            // Lets `Input::send` fail instead of waiting forever
+            rx.close();
+        }
+        EmbeddedConnectorConfig::Output(tx) => {
+            // Keep the shutdown guard alive until every message is handed over
+            let _shutdown = shutdown;
+
+            // `recv` only returns `None` once all `sink_tx` are dropped during shutdown
+            while let Some(message) = sink_rx.recv().await {
+                if tx.send(message).await.is_err() {
+                    // The `Output` was dropped, the application isn't interested anymore
+                    sink_rx.close();
+                }
+            }
+        }
+    }
+
+    return Ok(());
+}
@@ -1,12 +1,18 @@
+pub mod embedded;
 pub mod mqtt;
 pub mod osc_recv;
 pub mod osc_send;
+pub mod supervisor;
 pub mod udp_send;
 
+use std::sync::{Arc, RwLock};
+
+use embedded::{EmbeddedConnectorConfig, make_embedded_connector};
 use mqtt::{MQTTConnectorConfig, make_mqtt_connector};
 use osc_recv::{OSCRecvConnectorConfig, make_osc_recv_connector};
 use osc_send::{OSCSendConnectorConfig, make_osc_send_connector};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+use supervisor::SupervisionConfig;
 use tokio::sync::mpsc;
 
 use crate::{
@@ -14,31 +20,145 @@ use crate::{
     connector::udp_send::{UDPSendConnectorConfig, make_udp_send_connector},
     lifecycle::LifeCycleTX,
     message::InternalMessage,
+    plugin::{PluginConnectorConfig, plugin_kind, plugins},
+    queue::{QueueConfig, QueueName, QueueRX, QueueTX, queue},
+    shutdown::Shutdown,
 };
 
 #[derive(Debug, Clone)]
 pub struct ConnectorHandle {
     pub to: Vec<Connection>,
-    pub sink_tx: mpsc::Sender<InternalMessage>,
+    pub sink_tx: QueueTX,
+}
+
+pub type SourceTX = QueueTX;
+pub type SinkRX = QueueRX;
+
+/// Receives messages that the sink with the given index failed to deliver.
+/// The messages were already converted with `InternalMessage::into_error_message`.
+pub type DeadLetterTX = mpsc::Sender<(usize, InternalMessage)>;
+
+/// Everything a connector needs to talk to the rest of postoffice
+pub struct ConnectorContext {
+    pub idx: usize,
+    pub source_tx: SourceTX,
+    pub dead_letter_tx: DeadLetterTX,
+    pub lifecycle_tx: LifeCycleTX,
+    pub shutdown: Shutdown,
+}
+
+/// Holds the handle of every connector by its index. Slots are only empty after the shutdown.
+#[derive(Debug, Clone)]
+pub struct ConnectorHandles(Arc<Vec<RwLock<Option<ConnectorHandle>>>>);
+
+impl ConnectorHandles {
+    pub fn new(len: usize) -> Self {
+        Self(Arc::new((0..len).map(|_| RwLock::new(None)).collect()))
+    }
+
+    pub fn get(self: &Self, idx: usize) -> Option<ConnectorHandle> {
+        self.0
+            .get(idx)?
+            .read()
+            .expect("ConnectorHandles lock poisoned")
+            .clone()
+    }
+
+    pub fn set(self: &Self, idx: usize, handle: ConnectorHandle) {
+        if let Some(slot) = self.0.get(idx) {
+            *slot.write().expect("ConnectorHandles lock poisoned") = Some(handle);
+        }
+    }
+
+    /// Drops every handle, which closes all `sink_tx` that are not in use anymore
+    pub fn clear(self: &Self) {
+        for slot in self.0.iter() {
+            *slot.write().expect("ConnectorHandles lock poisoned") = None;
+        }
+    }
+}
+
+/// Options that every connector supports next to its own `config`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectorOptions {
+    #[serde(default)]
+    pub supervision: SupervisionConfig,
+    /// Whether the startup waits for this connector to become ready
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// What happens to messages for this sink while it isn't ready
+    #[serde(default)]
+    pub not_ready: NotReadyPolicy,
+    /// Receives messages this sink failed to deliver. Overrides the global `dead_letter`.
+    pub dead_letter: Option<Vec<Connection>>,
+    /// Capacity and overflow policy of the queue in front of this sink
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// Maximum number of messages from this source that are processed at the same time.
+    /// Overrides the global `concurrency.max_tasks_per_source`.
+    pub max_tasks: Option<usize>,
+}
+
+fn default_required() -> bool {
+    true
 }
 
-pub type SourceTX = mpsc::Sender<InternalMessage>;
+impl Default for ConnectorOptions {
+    fn default() -> Self {
+        Self {
+            supervision: SupervisionConfig::default(),
+            required: default_required(),
+            not_ready: NotReadyPolicy::default(),
+            dead_letter: None,
+            queue: QueueConfig::default(),
+            max_tasks: None,
+        }
+    }
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum NotReadyPolicy {
+    /// Queue messages in the sink until it is ready again
+    #[default]
+    Buffer,
+    /// Drop messages while the sink isn't ready
+    Drop,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(remote = "Self")]
 pub enum ConnectorConfig {
     MQTT {
         config: MQTTConnectorConfig,
         to: Option<Vec<Connection>>,
+        #[serde(flatten)]
+        options: ConnectorOptions,
     },
     OSCRecv {
         config: OSCRecvConnectorConfig,
         to: Option<Vec<Connection>>,
+        #[serde(flatten)]
+        options: ConnectorOptions,
     },
     OSCSend {
         config: OSCSendConnectorConfig,
+        #[serde(flatten)]
+        options: ConnectorOptions,
     },
     UDPSend {
         config: UDPSendConnectorConfig,
+        #[serde(flatten)]
+        options: ConnectorOptions,
+    },
+    /// A type registered by a plugin, see `Plugins`
+    #[serde(skip)]
+    Plugin(PluginConnectorConfig),
+    /// An `Input` or `Output` of the application that embeds postoffice
+    #[serde(skip)]
+    Embedded {
+        config: EmbeddedConnectorConfig,
+        to: Option<Vec<Connection>>,
+        options: ConnectorOptions,
     },
     // TODO: HTTPRecvServer
     // TODO: HTTPRecvSSE
@@ -46,24 +166,135 @@ pub enum ConnectorConfig {
     // TODO: HTTPSendSSE
 }
 
+impl<'de> Deserialize<'de> for ConnectorConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+
+        // Plugins take precedence, so they can replace a built-in type
+        if plugin_kind(&value).is_some_and(|kind| plugins().has_connector(kind)) {
+            return Ok(ConnectorConfig::Plugin(
+                PluginConnectorConfig::deserialize(value).map_err(D::Error::custom)?,
+            ));
+        }
+
+        return ConnectorConfig::deserialize(value).map_err(D::Error::custom);
+    }
+}
+
+impl ConnectorConfig {
+    pub fn options(self: &Self) -> &ConnectorOptions {
+        match self {
+            ConnectorConfig::MQTT { options, .. } => options,
+            ConnectorConfig::OSCRecv { options, .. } => options,
+            ConnectorConfig::OSCSend { options, .. } => options,
+            ConnectorConfig::UDPSend { options, .. } => options,
+            ConnectorConfig::Plugin(config) => &config.options,
+            ConnectorConfig::Embedded { options, .. } => options,
+        }
+    }
+
+    pub fn kind(self: &Self) -> &str {
+        match self {
+            ConnectorConfig::MQTT { .. } => "MQTT",
+            ConnectorConfig::OSCRecv { .. } => "OSCRecv",
+            ConnectorConfig::OSCSend { .. } => "OSCSend",
+            ConnectorConfig::UDPSend { .. } => "UDPSend",
+            ConnectorConfig::Plugin(config) => &config.kind,
+            ConnectorConfig::Embedded { config, .. } => match config {
+                EmbeddedConnectorConfig::Input(_) => "Input",
+                EmbeddedConnectorConfig::Output(_) => "Output",
+            },
+        }
+    }
+
+    /// Returns the connections of a source. Sinks have none.
+    pub fn to(self: &Self) -> &[Connection] {
+        match self {
+            ConnectorConfig::MQTT { to, .. } => to.as_deref().unwrap_or_default(),
+            ConnectorConfig::OSCRecv { to, .. } => to.as_deref().unwrap_or_default(),
+            ConnectorConfig::OSCSend { .. } => &[],
+            ConnectorConfig::UDPSend { .. } => &[],
+            ConnectorConfig::Plugin(config) => config.to.as_deref().unwrap_or_default(),
+            ConnectorConfig::Embedded { to, .. } => to.as_deref().unwrap_or_default(),
+        }
+    }
+
+    /// Describes where the connector connects to, e.g. `localhost:1883`
+    pub fn summary(self: &Self) -> String {
+        match self {
+            ConnectorConfig::MQTT { config, .. } => match config.topics {
+                Some(ref topics) if !topics.is_empty() => {
+                    format!("{}:{} {}", config.host, config.port, topics.join(", "))
+                }
+                _ => format!("{}:{}", config.host, config.port),
+            },
+            ConnectorConfig::OSCRecv { config, .. } => {
+                format!("{}:{}", config.interface, config.port)
+            }
+            ConnectorConfig::OSCSend { config, .. } => format!("{}:{}", config.host, config.port),
+            ConnectorConfig::UDPSend { config, .. } => format!("{}:{}", config.host, config.port),
+            ConnectorConfig::Plugin(config) => config.config.to_string(),
+            ConnectorConfig::Embedded { .. } => "In-process".to_string(),
+        }
+    }
+}
+
+/// Creates the queue in front of every connector. The queues outlive restarts of their
+/// connector, so messages for a sink are buffered until it runs.
+pub fn make_sink_queues(
+    connectors: &[ConnectorConfig],
+) -> anyhow::Result<(ConnectorHandles, Vec<SinkRX>)> {
+    let handles = ConnectorHandles::new(connectors.len());
+    let mut sink_rxs = vec![];
+
+    for (idx, config) in connectors.iter().enumerate() {
+        let (sink_tx, sink_rx) = queue(
+            QueueName {
+                queue: "sink",
+                connector: Some(idx),
+            },
+            &config.options().queue,
+        )?;
+
+        handles.set(
+            idx,
+            ConnectorHandle {
+                to: config.to().to_vec(),
+                sink_tx,
+            },
+        );
+        sink_rxs.push(sink_rx);
+    }
+
+    return Ok((handles, sink_rxs));
+}
+
 pub async fn make_connector(
-    idx: usize,
-    source_tx: SourceTX,
+    ctx: ConnectorContext,
     config: ConnectorConfig,
-    lifecycle_tx: LifeCycleTX,
-) -> anyhow::Result<ConnectorHandle> {
+    sink_rx: SinkRX,
+) -> anyhow::Result<()> {
     match config {
-        ConnectorConfig::MQTT { config, to } => {
-            make_mqtt_connector(idx, source_tx, config, to, lifecycle_tx).await
+        ConnectorConfig::MQTT { config, to, .. } => {
+            make_mqtt_connector(ctx, config, &to, sink_rx).await
+        }
+        ConnectorConfig::OSCRecv { config, .. } => {
+            make_osc_recv_connector(ctx, config, sink_rx).await
+        }
+        ConnectorConfig::OSCSend { config, .. } => {
+            make_osc_send_connector(ctx, config, sink_rx).await
         }
-        ConnectorConfig::OSCRecv { config, to } => {
-            make_osc_recv_connector(idx, source_tx, config, to, lifecycle_tx).await
+        ConnectorConfig::UDPSend { config, .. } => {
+            make_udp_send_connector(ctx, config, sink_rx).await
         }
-        ConnectorConfig::OSCSend { config } => {
-            make_osc_send_connector(idx, source_tx, config, None, lifecycle_tx).await
+        ConnectorConfig::Plugin(config) => {
+            plugins()
+                .connector(&config.kind)?
+                .make_connector(ctx, config.config, sink_rx)
+                .await
         }
-        ConnectorConfig::UDPSend { config } => {
-            make_udp_send_connector(idx, source_tx, config, None, lifecycle_tx).await
+        ConnectorConfig::Embedded { config, .. } => {
+            make_embedded_connector(ctx, config, sink_rx).await
         }
     }
 }
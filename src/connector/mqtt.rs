@@ -2,17 +2,18 @@ use std::time::Duration;
 
 use rumqttc::{AsyncClient, MqttOptions, QoS};
 use serde::Deserialize;
-use tokio::sync::mpsc;
+use tracing::{debug, info};
 
 use crate::{
     block::Connection,
-    lifecycle::{LifeCycleMessage, LifeCycleTX},
+    lifecycle::LifeCycleMessage,
     message::{InternalMessage, InternalMessageData},
+    metrics::{Counter, metrics},
 };
 
-use super::{ConnectorHandle, SourceTX};
+use super::{ConnectorContext, SinkRX};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct MQTTConnectorConfig {
     pub client_id: Option<String>,
     pub host: String,
@@ -21,101 +22,154 @@ pub struct MQTTConnectorConfig {
 }
 
 pub async fn make_mqtt_connector(
-    idx: usize,
-    source_tx: SourceTX,
+    ctx: ConnectorContext,
     config: MQTTConnectorConfig,
-    to: Option<Vec<Connection>>,
-    lifecycle_tx: LifeCycleTX,
-) -> anyhow::Result<ConnectorHandle> {
-    let (sink_tx, mut sink_rx) = mpsc::channel::<InternalMessage>(32);
-
+    to: &Option<Vec<Connection>>,
+    mut sink_rx: SinkRX,
+) -> anyhow::Result<()> {
+    let ConnectorContext {
+        idx,
+        source_tx,
+        dead_letter_tx,
+        lifecycle_tx,
+        mut shutdown,
+    } = ctx;
     let is_source = match to {
-        Some(ref connections) => connections.len() > 0,
+        Some(connections) => !connections.is_empty(),
         None => false,
     };
 
-    tokio::task::spawn(async move {
-        let mut mqttoptions = MqttOptions::new(
-            config.client_id.unwrap_or("postoffice".to_string()),
-            config.host,
-            config.port,
-        );
-
-        mqttoptions.set_keep_alive(Duration::from_secs(5));
-
-        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-        let c2 = client.clone();
-        let lifecycle_tx2 = lifecycle_tx.clone();
-        tokio::task::spawn(async move {
-            loop {
-                let msg = sink_rx.recv().await;
-
-                match msg {
-                    Some(msg) => {
-                        if let Ok(payload) = msg.data.get_binary() {
-                            match c2
-                                .publish(msg.topic, QoS::AtLeastOnce, false, payload)
-                                .await
-                            {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    lifecycle_tx2
-                                        .clone()
-                                        .send(LifeCycleMessage::Failed { idx, err: e.into() })
-                                        .await
-                                        .expect("Failed to send LifeCycleMessage");
-                                }
-                            }
-                        }
-                    }
-                    None => panic!(
-                        "Unable to recv from sink_rx. This means that all sink_tx are closed"
-                    ),
+    let mut mqttoptions = MqttOptions::new(
+        config.client_id.unwrap_or("postoffice".to_string()),
+        config.host,
+        config.port,
+    );
+
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+    let c2 = client.clone();
+    let lifecycle_tx2 = lifecycle_tx.clone();
+    // Polled together with the eventloop, which is what actually sends the publishes
+    let publisher = async move {
+        let label = idx.to_string();
+        let labels = [("connector", label.as_str())];
+        let sent = metrics().counter(Counter::SinkMessages, &labels);
+        let conversion_errors = metrics().counter(Counter::ConversionErrors, &labels);
+
+        // `recv` only returns `None` once all `sink_tx` are dropped during shutdown,
+        // at which point every message that is still in flight was already sent
+        while let Some(msg) = sink_rx.recv().await {
+            let payload = match msg.data.clone().get_binary() {
+                Ok(payload) => payload,
+                Err(err) => {
+                    conversion_errors.inc();
+
+                    let msg = msg.into_error_message(&err, ("connector", idx));
+                    let _ = dead_letter_tx.send((idx, msg)).await;
+                    continue;
+                }
+            };
+
+            match c2
+                .publish(msg.topic.clone(), QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                Ok(_) => sent.inc(),
+                Err(e) => {
+                    let err = anyhow::Error::from(e);
+
+                    let msg = msg.into_error_message(&err, ("connector", idx));
+                    let _ = dead_letter_tx.send((idx, msg)).await;
+
+                    lifecycle_tx2
+                        .send(LifeCycleMessage::Failed { idx, err })
+                        .await
+                        .expect("Failed to send LifeCycleMessage");
                 }
             }
-        });
-
-        loop {
-            match eventloop.poll().await {
-                Ok(rumqttc::Event::Incoming(packet)) => match packet {
-                    rumqttc::Packet::ConnAck(_) => {
-                        subscribe_to_topics(&client, &config.topics, is_source)
-                            .await
-                            .expect("Unable to subscribe to topics");
+        }
 
+        // The eventloop keeps running until the disconnect is on the wire
+        let _ = c2.disconnect().await;
+    };
+    tokio::pin!(publisher);
+    let mut publishing = true;
+
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = &mut publisher, if publishing => {
+                publishing = false;
+                continue;
+            }
+            _ = shutdown.recv(), if !shutdown.is_shutdown() => {
+                if is_source {
+                    let _ = unsubscribe_from_topics(&client, &config.topics).await;
+                }
+                continue;
+            }
+        };
+
+        match event {
+            Ok(rumqttc::Event::Incoming(packet)) => match packet {
+                rumqttc::Packet::ConnAck(_) => {
+                    // The connection stays up, so messages can still be published
+                    if let Err(err) = subscribe_to_topics(&client, &config.topics, is_source).await
+                    {
                         lifecycle_tx
-                            .send(LifeCycleMessage::Ready { idx })
+                            .send(LifeCycleMessage::Failed {
+                                idx,
+                                err: err.context("Unable to subscribe to topics"),
+                            })
                             .await
                             .expect("Failed to send LifeCycleMessage");
                     }
-                    rumqttc::Packet::Publish(publish) => {
-                        let msg = InternalMessage {
-                            source_connector_idx: idx,
-                            topic: publish.topic,
-                            data: InternalMessageData::Binary(publish.payload),
-                        };
-
-                        source_tx
-                            .send(msg)
-                            .await
-                            .expect("Unable to send messages to source_rx");
+
+                    lifecycle_tx
+                        .send(LifeCycleMessage::Ready { idx })
+                        .await
+                        .expect("Failed to send LifeCycleMessage");
+                }
+                rumqttc::Packet::Publish(publish) => {
+                    // Sources must not produce new messages once the shutdown started
+                    if shutdown.is_shutdown() {
+                        continue;
+                    }
+
+                    let msg = InternalMessage {
+                        source_connector_idx: idx,
+                        topic: publish.topic,
+                        data: InternalMessageData::Binary(publish.payload),
+                        dead_letter: false,
+                        hops: 0,
+                    };
+
+                    if source_tx.send(msg).await.is_err() {
+                        // This can only happen if `source_rx` was closed during shutdown
+                        continue;
                     }
-                    _ => {}
-                },
-                Ok(_) => {}
-                Err(e) => lifecycle_tx
-                    .send(LifeCycleMessage::Disconnected { idx, err: e.into() })
-                    .await
-                    .expect("Failed to send LifeCycleMessage"),
+                }
+                _ => {}
+            },
+            Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                if shutdown.is_shutdown() {
+                    // There is nothing left to flush if the broker is gone anyway
+                    break;
+                }
+
+                // The supervisor reconnects by recreating the connector
+                return Err(anyhow::Error::from(e).context("Lost connection to MQTT broker"));
             }
         }
-    });
+    }
 
-    return Ok(ConnectorHandle {
-        sink_tx,
-        to: to.unwrap_or_default(),
-    });
+    info!(connector = idx, "Disconnected from MQTT broker");
+
+    return Ok(());
 }
 
 async fn subscribe_to_topics(
@@ -132,7 +186,22 @@ async fn subscribe_to_topics(
             client.subscribe("#", QoS::ExactlyOnce).await?;
         }
     } else {
-        println!("[MQTT]: Skipping subscribe because it will drop all messages anyway...");
+        debug!("Skipping MQTT subscribe because it will drop all messages anyway");
+    }
+
+    return Ok(());
+}
+
+async fn unsubscribe_from_topics(
+    client: &AsyncClient,
+    topics: &Option<Vec<String>>,
+) -> anyhow::Result<()> {
+    if let Some(topics) = topics {
+        for topic in topics {
+            client.unsubscribe(topic).await?;
+        }
+    } else {
+        client.unsubscribe("#").await?;
     }
 
     return Ok(());
@@ -2,69 +2,64 @@ use std::{net::SocketAddrV4, str::FromStr};
 
 use rosc::OscPacket;
 use serde::Deserialize;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::net::UdpSocket;
 
 use crate::{
-    block::Connection,
-    lifecycle::{LifeCycleMessage, LifeCycleTX},
+    lifecycle::LifeCycleMessage,
     message::{InternalMessage, InternalMessageData},
 };
 
-use super::{ConnectorHandle, SourceTX};
+use super::{ConnectorContext, SinkRX};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct OSCRecvConnectorConfig {
     pub interface: String,
     pub port: u16,
 }
 
 pub async fn make_osc_recv_connector(
-    idx: usize,
-    source_tx: SourceTX,
+    ctx: ConnectorContext,
     config: OSCRecvConnectorConfig,
-    to: Option<Vec<Connection>>,
-    lifecycle_tx: LifeCycleTX,
-) -> anyhow::Result<ConnectorHandle> {
-    let (sink_tx, mut _sink_rx) = mpsc::channel::<InternalMessage>(32);
-
+    _sink_rx: SinkRX,
+) -> anyhow::Result<()> {
+    let ConnectorContext {
+        idx,
+        source_tx,
+        lifecycle_tx,
+        mut shutdown,
+        ..
+    } = ctx;
     let addr = format!("{}:{}", config.interface, config.port);
     let addr = SocketAddrV4::from_str(addr.as_str())?;
     let sock = UdpSocket::bind(addr).await?;
 
-    tokio::task::spawn(async move {
-        let mut buf = [0u8; rosc::decoder::MTU];
+    let mut buf = [0u8; rosc::decoder::MTU];
+
+    lifecycle_tx
+        .send(LifeCycleMessage::Ready { idx })
+        .await
+        .expect("Failed to send LifeCycleMessage");
 
-        lifecycle_tx
-            .send(LifeCycleMessage::Ready { idx })
-            .await
-            .expect("Failed to send LifeCycleMessage");
+    loop {
+        let size = tokio::select! {
+            // A socket that fails to receive is recreated by the supervisor
+            res = sock.recv(&mut buf) => res?,
+            _ = shutdown.recv() => break,
+        };
 
-        loop {
-            match sock.recv(&mut buf).await {
-                Ok(size) => {
-                    if let Ok((_, packet)) = rosc::decoder::decode_udp(&buf[..size]) {
-                        let messages = collect_messages_from_osc_packet(idx, packet);
+        if let Ok((_, packet)) = rosc::decoder::decode_udp(&buf[..size]) {
+            let messages = collect_messages_from_osc_packet(idx, packet);
 
-                        for message in messages {
-                            source_tx
-                                .send(message)
-                                .await
-                                .expect("Unable to send messages to source_rx");
-                        }
-                    }
+            for message in messages {
+                if source_tx.send(message).await.is_err() {
+                    // This can only happen if `source_rx` was closed during shutdown
+                    break;
                 }
-                Err(e) => lifecycle_tx
-                    .send(LifeCycleMessage::Failed { idx, err: e.into() })
-                    .await
-                    .expect("Failed to send LifeCycleMessage"),
             }
         }
-    });
+    }
 
-    return Ok(ConnectorHandle {
-        sink_tx,
-        to: to.unwrap_or_default(),
-    });
+    return Ok(());
 }
 
 fn collect_messages_from_osc_packet(source_idx: usize, packet: OscPacket) -> Vec<InternalMessage> {
@@ -73,12 +68,13 @@ fn collect_messages_from_osc_packet(source_idx: usize, packet: OscPacket) -> Vec
             source_connector_idx: source_idx,
             topic: osc_message.addr,
             data: InternalMessageData::OSC(osc_message.args),
+            dead_letter: false,
+            hops: 0,
         }],
         OscPacket::Bundle(osc_bundle) => osc_bundle
             .content
             .into_iter()
-            .map(|packet| collect_messages_from_osc_packet(source_idx, packet))
-            .flatten()
+            .flat_map(|packet| collect_messages_from_osc_packet(source_idx, packet))
             .collect(),
     }
 }
@@ -2,67 +2,86 @@ use std::{net::SocketAddrV4, str::FromStr};
 
 use rosc::{OscMessage, OscPacket};
 use serde::Deserialize;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::net::UdpSocket;
 
 use crate::{
-    block::Connection,
-    lifecycle::{LifeCycleMessage, LifeCycleTX},
-    message::InternalMessage,
+    lifecycle::LifeCycleMessage,
+    metrics::{Counter, metrics},
 };
 
-use super::{ConnectorHandle, SourceTX};
+use super::{ConnectorContext, SinkRX};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct OSCSendConnectorConfig {
     pub host: String,
     pub port: u16,
 }
 
 pub async fn make_osc_send_connector(
-    idx: usize,
-    _source_tx: SourceTX,
+    ctx: ConnectorContext,
     config: OSCSendConnectorConfig,
-    to: Option<Vec<Connection>>,
-    lifecycle_tx: LifeCycleTX,
-) -> anyhow::Result<ConnectorHandle> {
-    let (sink_tx, mut sink_rx) = mpsc::channel::<InternalMessage>(32);
+    mut sink_rx: SinkRX,
+) -> anyhow::Result<()> {
+    let ConnectorContext {
+        idx,
+        dead_letter_tx,
+        lifecycle_tx,
+        shutdown,
+        ..
+    } = ctx;
 
     let host_addr = SocketAddrV4::from_str("0.0.0.0:0")?;
     let to_addr = SocketAddrV4::from_str(format!("{}:{}", config.host, config.port).as_str())?;
     let sock = UdpSocket::bind(host_addr).await?;
 
-    tokio::task::spawn(async move {
-        lifecycle_tx
-            .send(LifeCycleMessage::Ready { idx })
-            .await
-            .expect("Failed to send LifeCycleMessage");
-
-        loop {
-            if let Some(msg) = sink_rx.recv().await {
-                if let Ok(args) = msg.data.get_osc() {
-                    let msg_buf = rosc::encoder::encode(&OscPacket::Message(OscMessage {
-                        addr: msg.topic,
-                        args,
-                    }));
-
-                    if let Ok(msg_buf) = msg_buf {
-                        match sock.send_to(&msg_buf, to_addr).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                lifecycle_tx
-                                    .send(LifeCycleMessage::Failed { idx, err: e.into() })
-                                    .await
-                                    .expect("Failed to send LifeCycleMessage");
-                            }
-                        }
-                    }
-                }
+    // Keep the shutdown guard alive until every message is sent
+    let _shutdown = shutdown;
+
+    lifecycle_tx
+        .send(LifeCycleMessage::Ready { idx })
+        .await
+        .expect("Failed to send LifeCycleMessage");
+
+    let label = idx.to_string();
+    let labels = [("connector", label.as_str())];
+    let sent = metrics().counter(Counter::SinkMessages, &labels);
+    let conversion_errors = metrics().counter(Counter::ConversionErrors, &labels);
+
+    // `recv` only returns `None` once all `sink_tx` are dropped during shutdown
+    while let Some(msg) = sink_rx.recv().await {
+        let msg_buf = msg.data.clone().get_osc().and_then(|args| {
+            Ok(rosc::encoder::encode(&OscPacket::Message(OscMessage {
+                addr: msg.topic.clone(),
+                args,
+            }))?)
+        });
+
+        let msg_buf = match msg_buf {
+            Ok(msg_buf) => msg_buf,
+            Err(err) => {
+                conversion_errors.inc();
+
+                let msg = msg.into_error_message(&err, ("connector", idx));
+                let _ = dead_letter_tx.send((idx, msg)).await;
+                continue;
+            }
+        };
+
+        match sock.send_to(&msg_buf, to_addr).await {
+            Ok(_) => sent.inc(),
+            Err(e) => {
+                let err = anyhow::Error::from(e);
+
+                let msg = msg.into_error_message(&err, ("connector", idx));
+                let _ = dead_letter_tx.send((idx, msg)).await;
+
+                lifecycle_tx
+                    .send(LifeCycleMessage::Failed { idx, err })
+                    .await
+                    .expect("Failed to send LifeCycleMessage");
             }
         }
-    });
+    }
 
-    return Ok(ConnectorHandle {
-        sink_tx,
-        to: to.unwrap_or_default(),
-    });
+    return Ok(());
 }
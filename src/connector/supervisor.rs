@@ -0,0 +1,441 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    lifecycle::{LifeCycleMessage, LifeCycleTX},
+    shutdown::Shutdown,
+};
+
+use super::{ConnectorConfig, ConnectorContext, DeadLetterTX, SinkRX, SourceTX, make_connector};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub enum SupervisionConfig {
+    /// Shut down postoffice if the connector exits
+    #[default]
+    Fatal,
+    /// Keep running without the connector
+    Ignore,
+    /// Recreate the connector with an exponential backoff
+    Restart(RestartConfig),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RestartConfig {
+    /// Delay before the first restart in milliseconds. Defaults to 1000.
+    pub backoff: Option<u64>,
+    /// Upper bound for the delay in milliseconds. Defaults to 60000.
+    pub max_backoff: Option<u64>,
+    /// Give up after this many restarts. Restarts forever if missing.
+    pub max_attempts: Option<u32>,
+    /// Shut down postoffice instead of giving up once `max_attempts` is reached
+    #[serde(default)]
+    pub fatal: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum SupervisionDecision {
+    Fatal,
+    GiveUp,
+    Restart(Duration),
+}
+
+impl SupervisionConfig {
+    fn decide(self: &Self, attempt: u32) -> SupervisionDecision {
+        match self {
+            SupervisionConfig::Fatal => SupervisionDecision::Fatal,
+            SupervisionConfig::Ignore => SupervisionDecision::GiveUp,
+            SupervisionConfig::Restart(config) => {
+                if config.max_attempts.is_some_and(|max| attempt > max) {
+                    if config.fatal {
+                        return SupervisionDecision::Fatal;
+                    } else {
+                        return SupervisionDecision::GiveUp;
+                    }
+                }
+
+                let backoff = config.backoff.unwrap_or(1000);
+                let max_backoff = config.max_backoff.unwrap_or(60000);
+
+                let delay = backoff
+                    .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+                    .min(max_backoff);
+
+                SupervisionDecision::Restart(Duration::from_millis(delay))
+            }
+        }
+    }
+}
+
+/// Runs the connector with index `idx`, which reads its messages from `sink_rx`.
+///
+/// If the connector fails, while it is created or while it runs, the `supervision` policy
+/// decides whether it is recreated, given up or whether postoffice shuts down. `sink_rx` is held
+/// until then, so messages for the sink are buffered in the meantime. The queue is closed once
+/// the connector was given up.
+pub async fn supervise_connector(
+    idx: usize,
+    source_tx: SourceTX,
+    dead_letter_tx: DeadLetterTX,
+    config: ConnectorConfig,
+    sink_rx: SinkRX,
+    lifecycle_tx: LifeCycleTX,
+    mut shutdown: Shutdown,
+) {
+    let supervision = config.options().supervision.clone();
+    let mut attempt = 0;
+
+    // The connector reports its state through the supervisor, so a restart can be told apart
+    // from a connector that failed again after it was ready
+    let (connector_tx, mut connector_rx) = mpsc::channel::<LifeCycleMessage>(32);
+
+    loop {
+        let run = make_connector(
+            ConnectorContext {
+                idx,
+                source_tx: source_tx.clone(),
+                dead_letter_tx: dead_letter_tx.clone(),
+                lifecycle_tx: connector_tx.clone(),
+                shutdown: shutdown.clone(),
+            },
+            config.clone(),
+            sink_rx.clone(),
+        );
+        tokio::pin!(run);
+
+        let res = loop {
+            tokio::select! {
+                res = &mut run => break res,
+                Some(msg) = connector_rx.recv() => {
+                    forward_reset(&lifecycle_tx, msg, &mut attempt).await;
+                }
+            }
+        };
+
+        // The connector may have reported its state right before it completed
+        while let Ok(msg) = connector_rx.try_recv() {
+            forward_reset(&lifecycle_tx, msg, &mut attempt).await;
+        }
+
+        let Err(err) = res else {
+            break;
+        };
+
+        // Connectors may fail while they are torn down
+        if shutdown.is_shutdown() {
+            break;
+        }
+
+        attempt += 1;
+
+        match supervision.decide(attempt) {
+            SupervisionDecision::Fatal => {
+                forward(&lifecycle_tx, LifeCycleMessage::Exited { idx, err }).await;
+
+                shutdown.trigger(1);
+                break;
+            }
+            SupervisionDecision::GiveUp => {
+                forward(&lifecycle_tx, LifeCycleMessage::Exited { idx, err }).await;
+                break;
+            }
+            SupervisionDecision::Restart(delay) => {
+                forward(
+                    &lifecycle_tx,
+                    LifeCycleMessage::Restarting {
+                        idx,
+                        attempt,
+                        delay,
+                        err,
+                    },
+                )
+                .await;
+
+                let sleep = tokio::time::sleep(delay);
+                tokio::pin!(sleep);
+
+                let stopped = loop {
+                    tokio::select! {
+                        _ = &mut sleep => break false,
+                        _ = shutdown.recv() => break true,
+                        Some(msg) = connector_rx.recv() => forward(&lifecycle_tx, msg).await,
+                    }
+                };
+
+                if stopped {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Tasks that a connector spawned may still report their state until they stopped
+    drop((connector_tx, sink_rx, shutdown));
+
+    while let Some(msg) = connector_rx.recv().await {
+        forward(&lifecycle_tx, msg).await;
+    }
+}
+
+/// Forwards `msg` and starts the restart attempts over once the connector is ready
+async fn forward_reset(lifecycle_tx: &LifeCycleTX, msg: LifeCycleMessage, attempt: &mut u32) {
+    if let LifeCycleMessage::Ready { .. } = msg {
+        *attempt = 0;
+    }
+
+    forward(lifecycle_tx, msg).await;
+}
+
+async fn forward(lifecycle_tx: &LifeCycleTX, msg: LifeCycleMessage) {
+    lifecycle_tx
+        .send(msg)
+        .await
+        .expect("Failed to send LifeCycleMessage");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Instant,
+    };
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        connector::ConnectorOptions,
+        plugin::{ConnectorPlugin, PluginConnectorConfig, plugins},
+        queue::{QueueConfig, QueueName, queue},
+        shutdown::ShutdownHandler,
+    };
+
+    /// Fails its first `failures` runs, either right away or after it was ready
+    struct FlakyConnector {
+        runs: AtomicU32,
+        failures: u32,
+        ready_first: bool,
+    }
+
+    #[async_trait]
+    impl ConnectorPlugin for FlakyConnector {
+        async fn make_connector(
+            self: &Self,
+            ctx: ConnectorContext,
+            _config: serde_json::Value,
+            _sink_rx: SinkRX,
+        ) -> anyhow::Result<()> {
+            let ConnectorContext {
+                idx,
+                lifecycle_tx,
+                mut shutdown,
+                ..
+            } = ctx;
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+
+            if self.ready_first {
+                forward(&lifecycle_tx, LifeCycleMessage::Ready { idx }).await;
+            }
+
+            if run < self.failures {
+                return Err(anyhow::Error::msg(format!("Run {} failed", run)));
+            }
+
+            if !self.ready_first {
+                forward(&lifecycle_tx, LifeCycleMessage::Ready { idx }).await;
+            }
+
+            shutdown.recv().await;
+
+            return Ok(());
+        }
+    }
+
+    fn restart(backoff: u64, max_backoff: u64, max_attempts: Option<u32>) -> SupervisionConfig {
+        SupervisionConfig::Restart(RestartConfig {
+            backoff: Some(backoff),
+            max_backoff: Some(max_backoff),
+            max_attempts,
+            fatal: false,
+        })
+    }
+
+    /// Supervises a [`FlakyConnector`] registered as `kind` and returns its lifecycle messages
+    fn supervise(
+        kind: &str,
+        supervision: SupervisionConfig,
+        failures: u32,
+        ready_first: bool,
+    ) -> (mpsc::Receiver<LifeCycleMessage>, ShutdownHandler, Shutdown) {
+        plugins()
+            .register_connector(
+                kind,
+                FlakyConnector {
+                    runs: AtomicU32::new(0),
+                    failures,
+                    ready_first,
+                },
+            )
+            .expect("Failed to register connector");
+
+        let config = ConnectorConfig::Plugin(PluginConnectorConfig {
+            kind: kind.to_string(),
+            config: serde_json::Value::Null,
+            to: None,
+            options: ConnectorOptions {
+                supervision,
+                ..Default::default()
+            },
+        });
+        let name = |queue| QueueName {
+            queue,
+            connector: Some(0),
+        };
+        let (source_tx, _) = queue(name("source"), &QueueConfig::default()).unwrap();
+        let (_, sink_rx) = queue(name("sink"), &QueueConfig::default()).unwrap();
+        let (dead_letter_tx, _) = mpsc::channel(1);
+        let (lifecycle_tx, lifecycle_rx) = mpsc::channel(32);
+
+        let handler = ShutdownHandler::new();
+        let shutdown = handler.subscribe();
+
+        tokio::spawn(supervise_connector(
+            0,
+            source_tx,
+            dead_letter_tx,
+            config,
+            sink_rx,
+            lifecycle_tx,
+            handler.subscribe(),
+        ));
+
+        (lifecycle_rx, handler, shutdown)
+    }
+
+    /// Returns the name of every lifecycle message and the restart attempts
+    async fn events(rx: &mut mpsc::Receiver<LifeCycleMessage>, count: usize) -> Vec<String> {
+        let mut events = vec![];
+
+        while events.len() < count {
+            let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("Timed out waiting for LifeCycleMessage")
+                .expect("Supervisor stopped");
+
+            match msg {
+                LifeCycleMessage::Restarting { attempt, .. } => {
+                    events.push(format!("restarting {}", attempt))
+                }
+                msg => events.push(msg.name().to_string()),
+            }
+        }
+
+        events
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let supervision = restart(100, 500, None);
+
+        let delays: Vec<SupervisionDecision> = (1..=5).map(|a| supervision.decide(a)).collect();
+
+        assert_eq!(
+            delays,
+            [100, 200, 400, 500, 500]
+                .map(|ms| SupervisionDecision::Restart(Duration::from_millis(ms)))
+        );
+    }
+
+    #[test]
+    fn max_attempts_gives_up_or_is_fatal() {
+        let mut supervision = restart(100, 500, Some(2));
+
+        assert!(matches!(
+            supervision.decide(2),
+            SupervisionDecision::Restart(_)
+        ));
+        assert_eq!(supervision.decide(3), SupervisionDecision::GiveUp);
+
+        if let SupervisionConfig::Restart(config) = &mut supervision {
+            config.fatal = true;
+        }
+        assert_eq!(supervision.decide(3), SupervisionDecision::Fatal);
+        assert_eq!(
+            SupervisionConfig::Fatal.decide(1),
+            SupervisionDecision::Fatal
+        );
+        assert_eq!(
+            SupervisionConfig::Ignore.decide(1),
+            SupervisionDecision::GiveUp
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_with_backoff_until_ready() {
+        let start = Instant::now();
+        let (mut rx, _handler, shutdown) = supervise(
+            "SupervisorTestRestart",
+            restart(20, 1000, Some(2)),
+            2,
+            false,
+        );
+
+        assert_eq!(
+            events(&mut rx, 3).await,
+            ["restarting 1", "restarting 2", "ready"]
+        );
+        // 20ms before the first restart, 40ms before the second one
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(!shutdown.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn ready_resets_attempts() {
+        // Would give up on the second failure if the attempts weren't reset
+        let (mut rx, _handler, shutdown) =
+            supervise("SupervisorTestReset", restart(1, 1, Some(1)), 3, true);
+
+        assert_eq!(
+            events(&mut rx, 7).await,
+            [
+                "ready",
+                "restarting 1",
+                "ready",
+                "restarting 1",
+                "ready",
+                "restarting 1",
+                "ready"
+            ]
+        );
+        assert!(!shutdown.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (mut rx, _handler, shutdown) = supervise(
+            "SupervisorTestGiveUp",
+            restart(1, 1, Some(2)),
+            u32::MAX,
+            false,
+        );
+
+        assert_eq!(
+            events(&mut rx, 3).await,
+            ["restarting 1", "restarting 2", "exited"]
+        );
+        // The supervisor stops once the connector was given up
+        assert!(rx.recv().await.is_none());
+        assert!(!shutdown.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn fatal_failure_triggers_shutdown() {
+        let (mut rx, _handler, shutdown) =
+            supervise("SupervisorTestFatal", SupervisionConfig::Fatal, 1, true);
+
+        assert_eq!(events(&mut rx, 2).await, ["ready", "exited"]);
+        assert!(shutdown.is_shutdown());
+        assert_eq!(shutdown.exit_code(), 1);
+    }
+}
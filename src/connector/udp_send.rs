@@ -1,60 +1,65 @@
 use std::{net::SocketAddrV4, str::FromStr};
 
 use serde::Deserialize;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::net::UdpSocket;
 
 use crate::{
-    block::Connection,
-    lifecycle::{LifeCycleMessage, LifeCycleTX},
-    message::InternalMessage,
+    lifecycle::LifeCycleMessage,
+    metrics::{Counter, metrics},
 };
 
-use super::{ConnectorHandle, SourceTX};
+use super::{ConnectorContext, SinkRX};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct UDPSendConnectorConfig {
     pub host: String,
     pub port: u16,
 }
 
 pub async fn make_udp_send_connector(
-    idx: usize,
-    _source_tx: SourceTX,
+    ctx: ConnectorContext,
     config: UDPSendConnectorConfig,
-    to: Option<Vec<Connection>>,
-    lifecycle_tx: LifeCycleTX,
-) -> anyhow::Result<ConnectorHandle> {
-    let (sink_tx, mut sink_rx) = mpsc::channel::<InternalMessage>(32);
-
+    mut sink_rx: SinkRX,
+) -> anyhow::Result<()> {
+    let ConnectorContext {
+        idx,
+        dead_letter_tx,
+        lifecycle_tx,
+        shutdown,
+        ..
+    } = ctx;
     let host_addr = SocketAddrV4::from_str("0.0.0.0:0")?;
     let to_addr = SocketAddrV4::from_str(format!("{}:{}", config.host, config.port).as_str())?;
     let sock = UdpSocket::bind(host_addr).await?;
 
-    tokio::task::spawn(async move {
-        lifecycle_tx
-            .send(LifeCycleMessage::Ready { idx })
-            .await
-            .expect("Failed to send LifeCycleMessage");
-
-        loop {
-            if let Some(msg) = sink_rx.recv().await {
-                let topic = msg.topic;
-
-                match sock.send_to(&topic.as_bytes(), to_addr).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        lifecycle_tx
-                            .send(LifeCycleMessage::Failed { idx, err: e.into() })
-                            .await
-                            .expect("Failed to send LifeCycleMessage");
-                    }
-                }
+    // Keep the shutdown guard alive until every message is sent
+    let _shutdown = shutdown;
+
+    lifecycle_tx
+        .send(LifeCycleMessage::Ready { idx })
+        .await
+        .expect("Failed to send LifeCycleMessage");
+
+    let label = idx.to_string();
+    let sent = metrics().counter(Counter::SinkMessages, &[("connector", label.as_str())]);
+
+    // `recv` only returns `None` once all `sink_tx` are dropped during shutdown
+    while let Some(msg) = sink_rx.recv().await {
+        match sock.send_to(msg.topic.as_bytes(), to_addr).await {
+            Ok(_) => sent.inc(),
+            Err(e) => {
+                let err = anyhow::Error::from(e);
+
+                let msg = msg.into_error_message(&err, ("connector", idx));
+                let _ = dead_letter_tx.send((idx, msg)).await;
+
+                lifecycle_tx
+                    .send(LifeCycleMessage::Failed { idx, err })
+                    .await
+                    .expect("Failed to send LifeCycleMessage");
             }
         }
-    });
+    }
 
-    return Ok(ConnectorHandle {
-        sink_tx,
-        to: to.unwrap_or_default(),
-    });
+    return Ok(());
 }
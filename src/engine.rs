@@ -0,0 +1,382 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::{error, info, warn};
+
+use crate::{
+    admin::{AdminState, start_admin_server},
+    block::Connection,
+    concurrency::{ConcurrencyConfig, Limits},
+    config::Config,
+    connector::{
+        ConnectorConfig, ConnectorHandles, ConnectorOptions, SinkRX,
+        embedded::{EmbeddedConnectorConfig, Input, Output},
+        make_sink_queues,
+        supervisor::supervise_connector,
+    },
+    lifecycle::LifeCycleHandler,
+    message::InternalMessage,
+    pipeline::Pipeline,
+    queue::{QueueName, QueueRX, QueueTX, queue},
+    router::{Ordering, Router, Waiting},
+    shutdown::ShutdownHandler,
+};
+
+/// Capacity of the channels behind an `Input` or `Output`
+const EMBEDDED_CAPACITY: usize = 32;
+
+/// Configures postoffice before it runs, like the command line arguments do
+pub struct PostofficeBuilder {
+    config: Config,
+    ignore_cycles: bool,
+    startup_timeout: Duration,
+    shutdown_timeout: Duration,
+    admin: Option<(String, u16)>,
+}
+
+impl PostofficeBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ignore_cycles: false,
+            startup_timeout: Duration::from_millis(30000),
+            shutdown_timeout: Duration::from_millis(5000),
+            admin: None,
+        }
+    }
+
+    /// Warns about cycles in the config instead of failing, like `--ignore-cycles`
+    pub fn ignore_cycles(mut self: Self, ignore_cycles: bool) -> Self {
+        self.ignore_cycles = ignore_cycles;
+        self
+    }
+
+    /// Time to wait for all required connectors to become ready
+    pub fn startup_timeout(mut self: Self, timeout: Duration) -> Self {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// Time to wait for in-flight messages and connectors on shutdown
+    pub fn shutdown_timeout(mut self: Self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Serves the admin endpoints on `interface:port`
+    pub fn admin(mut self: Self, interface: impl Into<String>, port: u16) -> Self {
+        self.admin = Some((interface.into(), port));
+        self
+    }
+
+    /// Adds a source that the application feeds through the returned `Input`. It is appended to
+    /// the connectors of the config, so `Input::idx` is its index.
+    pub fn input(self: &mut Self, to: Vec<Connection>) -> Input {
+        let (input, config) = Input::new(self.config.connectors.len(), EMBEDDED_CAPACITY);
+        self.push_embedded(config, Some(to));
+        input
+    }
+
+    /// Adds a sink whose messages the application receives through the returned `Output`. Blocks
+    /// route to it with `Sink(output.idx)`.
+    pub fn output(self: &mut Self) -> Output {
+        let (output, config) = Output::new(self.config.connectors.len(), EMBEDDED_CAPACITY);
+        self.push_embedded(config, None);
+        output
+    }
+
+    fn push_embedded(
+        self: &mut Self,
+        config: EmbeddedConnectorConfig,
+        to: Option<Vec<Connection>>,
+    ) {
+        self.config.connectors.push(ConnectorConfig::Embedded {
+            config,
+            to,
+            options: ConnectorOptions::default(),
+        });
+    }
+
+    /// Creates the blocks and queues. Nothing runs until `Postoffice::run_until` is called.
+    pub fn build(self: Self) -> anyhow::Result<Postoffice> {
+        let (source_tx, source_rx) = queue(
+            QueueName {
+                queue: "source",
+                connector: None,
+            },
+            &self.config.source_queue,
+        )?;
+
+        let (connector_handles, sink_rxs) = make_sink_queues(&self.config.connectors)?;
+
+        let pipeline = Arc::new(Pipeline::new(
+            self.config.blocks,
+            self.ignore_cycles,
+            self.config.max_hops,
+        )?);
+
+        return Ok(Postoffice {
+            connectors: self.config.connectors,
+            connector_handles,
+            sink_rxs,
+            dead_letter: self.config.dead_letter,
+            ordering: self.config.ordering,
+            concurrency: self.config.concurrency,
+            waiting_capacity: self.config.source_queue.capacity,
+            pipeline,
+            source_tx,
+            source_rx,
+            startup_timeout: self.startup_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            admin: self.admin,
+        });
+    }
+}
+
+/// The routing engine: connectors, blocks and the queues between them
+pub struct Postoffice {
+    connectors: Vec<ConnectorConfig>,
+    connector_handles: ConnectorHandles,
+    sink_rxs: Vec<SinkRX>,
+    dead_letter: Option<Vec<Connection>>,
+    ordering: Ordering,
+    concurrency: ConcurrencyConfig,
+    /// How many messages may wait for a concurrency limit or their ordering key
+    waiting_capacity: usize,
+    pipeline: Arc<Pipeline>,
+    source_tx: QueueTX,
+    source_rx: QueueRX,
+    startup_timeout: Duration,
+    shutdown_timeout: Duration,
+    admin: Option<(String, u16)>,
+}
+
+impl Postoffice {
+    pub fn builder(config: Config) -> PostofficeBuilder {
+        PostofficeBuilder::new(config)
+    }
+
+    /// Starts every connector and routes messages until `stop` resolves or a connector triggers
+    /// the shutdown. Everything in flight is handled before it returns the exit code.
+    pub async fn run_until(self: Self, stop: impl Future<Output = ()>) -> anyhow::Result<i32> {
+        let Postoffice {
+            connectors,
+            connector_handles,
+            sink_rxs,
+            dead_letter,
+            ordering,
+            concurrency,
+            waiting_capacity,
+            pipeline,
+            source_tx,
+            mut source_rx,
+            startup_timeout,
+            shutdown_timeout,
+            admin,
+        } = self;
+
+        let shutdown_handler = ShutdownHandler::new();
+        let mut shutdown = shutdown_handler.subscribe();
+
+        let life_cycle_handler =
+            LifeCycleHandler::start(connectors.iter().map(|con| con.options().clone()).collect());
+        let connector_states = life_cycle_handler.states.clone();
+
+        if let Some((interface, port)) = admin {
+            start_admin_server(
+                &interface,
+                port,
+                AdminState {
+                    connector_states: connector_states.clone(),
+                    shutdown: shutdown_handler.subscribe(),
+                },
+            )
+            .await
+            .context("Unable to start admin server")?;
+        }
+
+        let (dead_letter_tx, mut dead_letter_rx) = mpsc::channel::<(usize, InternalMessage)>(32);
+
+        info!("Starting connectors");
+
+        let router = Router::new(
+            pipeline,
+            connector_handles.clone(),
+            connector_states,
+            Arc::new(
+                connectors
+                    .iter()
+                    .map(|con| con.options().dead_letter.clone().or(dead_letter.clone()))
+                    .collect(),
+            ),
+            ordering,
+            Limits::new(
+                &concurrency,
+                connectors
+                    .iter()
+                    .map(|con| con.options().max_tasks)
+                    .collect(),
+            ),
+        );
+
+        for (idx, (con, sink_rx)) in connectors.into_iter().zip(sink_rxs).enumerate() {
+            info!(connector = idx, "Starting connector");
+
+            tokio::spawn(supervise_connector(
+                idx,
+                source_tx.clone(),
+                dead_letter_tx.clone(),
+                con,
+                sink_rx,
+                life_cycle_handler.lifecycle_tx.clone(),
+                shutdown_handler.subscribe(),
+            ));
+        }
+
+        tokio::pin!(stop);
+
+        info!("Waiting for connectors");
+
+        tokio::select! {
+            res = life_cycle_handler.wait_all_ready(startup_timeout) => {
+                match res {
+                    Ok(_) => info!("Startup complete"),
+                    Err(e) => {
+                        error!("Startup failed: {}", e);
+                        shutdown.trigger(1);
+                    }
+                }
+            }
+            _ = shutdown.recv() => {}
+            _ = &mut stop => shutdown.trigger(0),
+        }
+
+        let mut tasks = JoinSet::new();
+
+        // The source queue isn't read while it is full
+        let mut waiting = Waiting::new(waiting_capacity);
+
+        while !shutdown.is_shutdown() {
+            tokio::select! {
+                incoming = source_rx.recv(), if !waiting.is_full() => match incoming {
+                    Some(incoming) => router.accept_incoming(&mut tasks, &mut waiting, incoming),
+                    None => {
+                        // This can only happen if `source_rx.recv()` returns `None` which means
+                        // that all `source_tx` channel halfs are closed.
+                        panic!(
+                            "source_rx.recv() returned None which means that no work can be done at this point"
+                        )
+                    }
+                },
+                Some((sink_idx, message)) = dead_letter_rx.recv() => {
+                    router.spawn_dead_letter(&mut tasks, sink_idx, message);
+                }
+                Some(res) = tasks.join_next() => {
+                    if let Err(e) = res {
+                        error!("Pipeline task failed: {}", e);
+                    }
+
+                    router.spawn_waiting(&mut tasks, &mut waiting);
+                }
+                _ = shutdown.recv() => {}
+                _ = &mut stop => shutdown.trigger(0),
+            }
+        }
+
+        info!("Shutting down");
+
+        // Sources stop as soon as the shutdown is triggered. Everything they already sent is still
+        // processed, but nothing new is accepted.
+        source_rx.close();
+
+        loop {
+            if waiting.is_full() {
+                join_next_waiting(&router, &mut tasks, &mut waiting).await;
+                continue;
+            }
+
+            let Some(incoming) = source_rx.recv().await else {
+                break;
+            };
+
+            router.accept_incoming(&mut tasks, &mut waiting, incoming);
+        }
+
+        while !waiting.is_empty() {
+            join_next_waiting(&router, &mut tasks, &mut waiting).await;
+        }
+
+        info!(in_flight = tasks.len(), "Waiting for in-flight messages");
+
+        let drained = tokio::time::timeout(shutdown_timeout, async {
+            loop {
+                tokio::select! {
+                    // Sinks may still fail to deliver messages that are in flight
+                    Some((sink_idx, message)) = dead_letter_rx.recv() => {
+                        router.spawn_dead_letter(&mut tasks, sink_idx, message);
+                    }
+                    res = tasks.join_next() => if res.is_none() {
+                        break;
+                    }
+                }
+            }
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                in_flight = tasks.len(),
+                "Shutdown timeout reached, dropping in-flight messages"
+            );
+            tasks.shutdown().await;
+        }
+
+        router.pipeline.shutdown().await;
+
+        // Dropping the last `sink_tx` lets every sink flush its queue and disconnect
+        connector_handles.clear();
+
+        let exit_code = shutdown.exit_code();
+        drop(shutdown);
+
+        let completed = tokio::time::timeout(shutdown_timeout, async {
+            let wait_complete = shutdown_handler.wait_complete();
+            tokio::pin!(wait_complete);
+
+            loop {
+                tokio::select! {
+                    _ = &mut wait_complete => break,
+                    // A sink that fails while it flushes must not wait for space in the channel.
+                    // The pipeline already stopped, so the message can't be routed anymore.
+                    Some((sink_idx, message)) = dead_letter_rx.recv() => {
+                        error!(
+                            sink = sink_idx,
+                            message = ?message,
+                            "Failed to deliver message during shutdown, dropping it"
+                        );
+                    }
+                }
+            }
+        })
+        .await;
+
+        if completed.is_err() {
+            warn!("Shutdown timeout reached, not all connectors stopped");
+        }
+
+        info!("Shutdown complete");
+
+        return Ok(exit_code);
+    }
+}
+
+/// Waits for the next task to finish and spawns the tasks of the messages that can run now
+async fn join_next_waiting(router: &Router, tasks: &mut JoinSet<()>, waiting: &mut Waiting) {
+    // There is always a task running while a message waits, it holds the permit or lane
+    if let Some(Err(e)) = tasks.join_next().await {
+        error!("Pipeline task failed: {}", e);
+    }
+
+    router.spawn_waiting(tasks, waiting);
+}
@@ -0,0 +1,281 @@
+use std::fmt::Write;
+
+use postoffice::{Config, block::Connection};
+
+use crate::cli::GraphFormat;
+
+enum Shape {
+    Connector,
+    Block,
+    DeadLetter,
+}
+
+struct Node {
+    id: String,
+    shape: Shape,
+    /// Every entry is shown on its own line
+    label: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum EdgeKind {
+    To,
+    OnError,
+    DeadLetter,
+}
+
+struct Edge {
+    from: String,
+    to: String,
+    kind: EdgeKind,
+}
+
+fn connector_id(idx: usize) -> String {
+    format!("connector_{}", idx)
+}
+
+fn block_id(idx: usize) -> String {
+    format!("block_{}", idx)
+}
+
+fn connection_id(connection: &Connection) -> String {
+    match connection {
+        Connection::Block(idx) => block_id(*idx),
+        Connection::Sink(idx) => connector_id(*idx),
+    }
+}
+
+/// Renders the connectors and blocks of `config` and the connections between them
+pub fn render_graph(config: &Config, format: GraphFormat) -> String {
+    let mut nodes = vec![];
+    let mut edges = vec![];
+
+    let mut add_edges = |from: &str, to: &[Connection], kind: EdgeKind| {
+        for connection in to {
+            edges.push(Edge {
+                from: from.to_string(),
+                to: connection_id(connection),
+                kind,
+            });
+        }
+    };
+
+    for (idx, connector) in config.connectors.iter().enumerate() {
+        let id = connector_id(idx);
+
+        nodes.push(Node {
+            id: id.clone(),
+            shape: Shape::Connector,
+            label: vec![
+                format!("Connector {}", idx),
+                connector.kind().to_string(),
+                connector.summary(),
+            ],
+        });
+
+        add_edges(&id, connector.to(), EdgeKind::To);
+
+        if let Some(ref dead_letter) = connector.options().dead_letter {
+            add_edges(&id, dead_letter, EdgeKind::DeadLetter);
+        }
+    }
+
+    for (idx, block) in config.blocks.iter().enumerate() {
+        let id = block_id(idx);
+        let options = block.options();
+
+        let mut label = vec![match options.name {
+            Some(ref name) => format!("Block {} {:?}", idx, name),
+            None => format!("Block {}", idx),
+        }];
+        label.push(block.kind().to_string());
+        label.extend(block.summary());
+
+        if let Some(max_hops) = options.max_hops {
+            label.push(format!("max_hops {}", max_hops));
+        }
+
+        nodes.push(Node {
+            id: id.clone(),
+            shape: Shape::Block,
+            label,
+        });
+
+        add_edges(&id, block.to(), EdgeKind::To);
+        add_edges(&id, &options.on_error, EdgeKind::OnError);
+    }
+
+    if let Some(ref dead_letter) = config.dead_letter {
+        let id = "dead_letter".to_string();
+
+        nodes.push(Node {
+            id: id.clone(),
+            shape: Shape::DeadLetter,
+            label: vec!["Dead letters".to_string()],
+        });
+
+        add_edges(&id, dead_letter, EdgeKind::DeadLetter);
+    }
+
+    match format {
+        GraphFormat::Dot => render_dot(&nodes, &edges),
+        GraphFormat::Mermaid => render_mermaid(&nodes, &edges),
+    }
+}
+
+fn render_dot(nodes: &[Node], edges: &[Edge]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "digraph postoffice {{");
+    let _ = writeln!(out, "  rankdir=LR;");
+
+    for node in nodes {
+        let label = node
+            .label
+            .iter()
+            .map(|line| line.replace('\\', "\\\\").replace('"', "\\\""))
+            .collect::<Vec<_>>()
+            .join("\\n");
+
+        let shape = match node.shape {
+            Shape::Connector => "shape=box, style=rounded",
+            Shape::Block => "shape=box",
+            Shape::DeadLetter => "shape=box, style=dashed",
+        };
+
+        let _ = writeln!(out, "  {} [{}, label=\"{}\"];", node.id, shape, label);
+    }
+
+    for edge in edges {
+        let style = match edge.kind {
+            EdgeKind::To => "",
+            EdgeKind::OnError => " [style=dashed, label=\"on_error\"]",
+            EdgeKind::DeadLetter => " [style=dotted, label=\"dead_letter\"]",
+        };
+
+        let _ = writeln!(out, "  {} -> {}{};", edge.from, edge.to, style);
+    }
+
+    let _ = writeln!(out, "}}");
+
+    return out;
+}
+
+fn render_mermaid(nodes: &[Node], edges: &[Edge]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "flowchart LR");
+
+    for node in nodes {
+        let label = node
+            .label
+            .iter()
+            .map(|line| {
+                // Mermaid escapes characters with entity codes like `#quot;`
+                line.replace('#', "#35;")
+                    .replace('<', "#lt;")
+                    .replace('>', "#gt;")
+                    .replace('"', "#quot;")
+            })
+            .collect::<Vec<_>>()
+            .join("<br/>");
+
+        let _ = match node.shape {
+            Shape::Connector => writeln!(out, "  {}([\"{}\"])", node.id, label),
+            Shape::Block => writeln!(out, "  {}[\"{}\"]", node.id, label),
+            Shape::DeadLetter => writeln!(out, "  {}[/\"{}\"/]", node.id, label),
+        };
+    }
+
+    for edge in edges {
+        let arrow = match edge.kind {
+            EdgeKind::To => "-->",
+            EdgeKind::OnError => "-. on_error .->",
+            EdgeKind::DeadLetter => "-. dead_letter .->",
+        };
+
+        let _ = writeln!(out, "  {} {} {}", edge.from, arrow, edge.to);
+    }
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Has every kind of node and edge, and a block whose name and pattern need escaping
+    const CONFIG: &str = r##"{
+        "connectors": [
+            {
+                "OSCRecv": {
+                    "config": { "interface": "0.0.0.0", "port": 9000 },
+                    "to": [{ "Block": 0 }]
+                }
+            },
+            {
+                "UDPSend": {
+                    "config": { "host": "127.0.0.1", "port": 9001 },
+                    "dead_letter": [{ "Block": 1 }]
+                }
+            }
+        ],
+        "blocks": [
+            {
+                "MatchTopic": {
+                    "config": { "Regex": "^/a#<b>\\d" },
+                    "to": [{ "Sink": 1 }],
+                    "on_error": [{ "Block": 1 }],
+                    "name": "match \"a\""
+                }
+            },
+            { "AddLeadingSlash": { "to": [{ "Sink": 1 }], "max_hops": 2 } }
+        ],
+        "dead_letter": [{ "Block": 1 }]
+    }"##;
+
+    fn render(format: GraphFormat) -> String {
+        let config: Config = serde_json::from_str(CONFIG).unwrap();
+        render_graph(&config, format)
+    }
+
+    #[test]
+    fn renders_dot() {
+        let expected = r#"digraph postoffice {
+  rankdir=LR;
+  connector_0 [shape=box, style=rounded, label="Connector 0\nOSCRecv\n0.0.0.0:9000"];
+  connector_1 [shape=box, style=rounded, label="Connector 1\nUDPSend\n127.0.0.1:9001"];
+  block_0 [shape=box, label="Block 0 \"match \\\"a\\\"\"\nMatchTopic\nRegex \"^/a#<b>\\\\d\""];
+  block_1 [shape=box, label="Block 1\nAddLeadingSlash\nmax_hops 2"];
+  dead_letter [shape=box, style=dashed, label="Dead letters"];
+  connector_0 -> block_0;
+  connector_1 -> block_1 [style=dotted, label="dead_letter"];
+  block_0 -> connector_1;
+  block_0 -> block_1 [style=dashed, label="on_error"];
+  block_1 -> connector_1;
+  dead_letter -> block_1 [style=dotted, label="dead_letter"];
+}
+"#;
+
+        assert_eq!(render(GraphFormat::Dot), expected);
+    }
+
+    #[test]
+    fn renders_mermaid() {
+        let expected = r#"flowchart LR
+  connector_0(["Connector 0<br/>OSCRecv<br/>0.0.0.0:9000"])
+  connector_1(["Connector 1<br/>UDPSend<br/>127.0.0.1:9001"])
+  block_0["Block 0 #quot;match \#quot;a\#quot;#quot;<br/>MatchTopic<br/>Regex #quot;^/a#35;#lt;b#gt;\\d#quot;"]
+  block_1["Block 1<br/>AddLeadingSlash<br/>max_hops 2"]
+  dead_letter[/"Dead letters"/]
+  connector_0 --> block_0
+  connector_1 -. dead_letter .-> block_1
+  block_0 --> connector_1
+  block_0 -. on_error .-> block_1
+  block_1 --> connector_1
+  dead_letter -. dead_letter .-> block_1
+"#;
+
+        assert_eq!(render(GraphFormat::Mermaid), expected);
+    }
+}
@@ -0,0 +1,29 @@
+#![allow(
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type,
+    clippy::upper_case_acronyms
+)]
+
+mod admin;
+pub mod block;
+mod concurrency;
+pub mod config;
+pub mod connector;
+mod engine;
+pub mod lifecycle;
+pub mod message;
+pub mod metrics;
+pub mod pipeline;
+pub mod plugin;
+pub mod queue;
+mod router;
+pub mod shutdown;
+
+pub use block::Block;
+pub use concurrency::{ConcurrencyConfig, SaturationPolicy};
+pub use config::Config;
+pub use connector::embedded::{Input, Output};
+pub use engine::{Postoffice, PostofficeBuilder};
+pub use message::{InternalMessage, InternalMessageData};
+pub use pipeline::Pipeline;
+pub use router::Ordering;
@@ -1,49 +1,103 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
+use serde::Serialize;
 use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::{
+    connector::{ConnectorOptions, NotReadyPolicy},
+    metrics::{Counter, metrics},
+};
 
 pub struct LifeCycleHandler {
     all_ready_rx: mpsc::Receiver<()>,
     pub lifecycle_tx: LifeCycleTX,
+    pub states: ConnectorStates,
 }
 
 impl LifeCycleHandler {
-    pub fn start(connector_idxs: Vec<usize>) -> Self {
+    pub fn start(connector_options: Vec<ConnectorOptions>) -> Self {
         let (tx, mut rx) = mpsc::channel::<LifeCycleMessage>(32);
         let (all_ready_tx, all_ready_rx) = mpsc::channel::<()>(32);
 
-        tokio::spawn(async move {
-            let mut m = HashMap::<usize, bool>::new();
-
-            for idx in connector_idxs {
-                m.insert(idx, false);
-            }
+        let states = ConnectorStates::new(connector_options);
+        let s = states.clone();
 
-            loop {
-                let msg = rx.recv().await.expect("Unable to recv from LifecycleRX");
+        tokio::spawn(async move {
+            // `recv` returns `None` once every connector has stopped during shutdown
+            while let Some(msg) = rx.recv().await {
+                let label = msg.idx().to_string();
+                metrics().inc(
+                    Counter::LifeCycleEvents,
+                    &[("connector", label.as_str()), ("event", msg.name())],
+                );
 
                 match msg {
                     LifeCycleMessage::Ready { idx } => {
-                        println!("[Connector {}] Ready", idx);
+                        info!(connector = idx, "Ready");
 
-                        m.insert(idx, true);
+                        s.update(idx, |state| state.ready = true);
 
-                        if m.iter().all(|(_, state)| *state) {
-                            all_ready_tx
-                                .send(())
-                                .await
-                                .expect("Unable to send all_ready signal");
+                        if s.all_required_ready() {
+                            // The receiver is gone once startup is complete, so reconnects
+                            // don't need to signal anything
+                            let _ = all_ready_tx.send(()).await;
                         }
                     }
                     LifeCycleMessage::Disconnected { idx, err } => {
-                        println!("[Connector {}] Disconnected because of: {}", idx, err);
+                        warn!(connector = idx, "Disconnected because of: {}", err);
+
+                        s.update(idx, |state| {
+                            state.ready = false;
+                            state.last_error = Some(err.to_string());
+                        });
                     }
                     LifeCycleMessage::Failed { idx, err } => {
-                        println!("[Connector {}] Failed because of: {}", idx, err);
+                        warn!(connector = idx, "Failed because of: {}", err);
+
+                        s.update(idx, |state| {
+                            state.failed_count += 1;
+                            state.last_error = Some(err.to_string());
+                        });
+                    }
+                    LifeCycleMessage::Restarting {
+                        idx,
+                        attempt,
+                        delay,
+                        err,
+                    } => {
+                        warn!(
+                            connector = idx,
+                            attempt,
+                            delay_ms = delay.as_millis() as u64,
+                            "Restarting because of: {}",
+                            err
+                        );
+
+                        s.update(idx, |state| {
+                            state.ready = false;
+                            state.failed_count += 1;
+                            state.last_error = Some(err.to_string());
+                        });
                     }
                     LifeCycleMessage::Exited { idx, err } => {
-                        println!("[Connector {}] Exited because of: {}", idx, err);
-                        std::process::exit(1);
+                        error!(connector = idx, "Exited because of: {}", err);
+
+                        s.update(idx, |state| {
+                            state.ready = false;
+                            state.failed_count += 1;
+                            state.last_error = Some(err.to_string());
+                            // A connector that was given up can't block the startup anymore
+                            state.exited = true;
+                        });
+
+                        if s.all_required_ready() {
+                            let _ = all_ready_tx.send(()).await;
+                        }
                     }
                 }
             }
@@ -52,23 +106,209 @@ impl LifeCycleHandler {
         Self {
             all_ready_rx,
             lifecycle_tx: tx,
+            states,
         }
     }
 
-    pub async fn wait_all_ready(mut self: Self) -> anyhow::Result<()> {
-        if self.all_ready_rx.recv().await.is_none() {
-            panic!("Unable to receive all_ready signal")
+    /// Waits until every required connector is ready. Optional connectors that are not ready
+    /// after this point start degraded.
+    pub async fn wait_all_ready(mut self: Self, timeout: Duration) -> anyhow::Result<()> {
+        let ready = tokio::time::timeout(timeout, async {
+            // There might not be any required connector that could send the signal
+            if !self.states.all_required_ready() {
+                self.all_ready_rx.recv().await
+            } else {
+                Some(())
+            }
+        })
+        .await;
+
+        match ready {
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("Unable to receive all_ready signal"),
+            Err(_) => {
+                let missing = self.states.not_ready(true);
+
+                if !missing.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Required connectors {:?} were not ready after {}ms",
+                        missing,
+                        timeout.as_millis()
+                    ));
+                }
+            }
+        }
+
+        for idx in self.states.not_ready(false) {
+            warn!(connector = idx, "Not ready, starting degraded");
         }
 
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectorState {
+    pub idx: usize,
+    pub required: bool,
+    pub not_ready: NotReadyPolicy,
+    pub ready: bool,
+    pub exited: bool,
+    pub last_error: Option<String>,
+    pub failed_count: u64,
+}
+
+/// The state of every connector by its index, as tracked by the [`LifeCycleHandler`]
+#[derive(Debug, Clone)]
+pub struct ConnectorStates(Arc<RwLock<HashMap<usize, ConnectorState>>>);
+
+impl ConnectorStates {
+    fn new(connector_options: Vec<ConnectorOptions>) -> Self {
+        let m = connector_options
+            .into_iter()
+            .enumerate()
+            .map(|(idx, options)| {
+                (
+                    idx,
+                    ConnectorState {
+                        idx,
+                        required: options.required,
+                        not_ready: options.not_ready,
+                        ready: false,
+                        exited: false,
+                        last_error: None,
+                        failed_count: 0,
+                    },
+                )
+            })
+            .collect();
+
+        Self(Arc::new(RwLock::new(m)))
+    }
+
+    fn update(self: &Self, idx: usize, f: impl FnOnce(&mut ConnectorState)) {
+        let mut m = self.0.write().expect("ConnectorStates lock poisoned");
+
+        if let Some(state) = m.get_mut(&idx) {
+            f(state);
+        }
+    }
+
+    fn get(self: &Self, idx: usize) -> Option<ConnectorState> {
+        self.0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .get(&idx)
+            .cloned()
+    }
+
+    /// Returns the state of every connector sorted by index
+    pub fn all(self: &Self) -> Vec<ConnectorState> {
+        let mut states: Vec<ConnectorState> = self
+            .0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+
+        states.sort_by_key(|state| state.idx);
+        states
+    }
+
+    fn all_required_ready(self: &Self) -> bool {
+        self.0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .values()
+            .all(|state| state.ready || state.exited || !state.required)
+    }
+
+    /// Returns `true` if every required connector is ready right now
+    pub fn required_ready(self: &Self) -> bool {
+        self.0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .values()
+            .all(|state| state.ready || !state.required)
+    }
+
+    /// Returns `true` if no required connector was given up
+    pub fn required_running(self: &Self) -> bool {
+        self.0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .values()
+            .all(|state| !state.exited || !state.required)
+    }
+
+    fn not_ready(self: &Self, required: bool) -> Vec<usize> {
+        let mut idxs: Vec<usize> = self
+            .0
+            .read()
+            .expect("ConnectorStates lock poisoned")
+            .iter()
+            .filter(|(_, state)| !state.ready && !state.exited && state.required == required)
+            .map(|(idx, _)| *idx)
+            .collect();
+
+        idxs.sort();
+        idxs
+    }
+
+    /// Returns `false` if a message for this sink should be dropped because it isn't ready
+    pub fn accepts(self: &Self, idx: usize) -> bool {
+        match self.get(idx) {
+            Some(state) => state.ready || state.not_ready == NotReadyPolicy::Buffer,
+            None => false,
+        }
+    }
+}
+
 pub enum LifeCycleMessage {
-    Ready { idx: usize },
-    Disconnected { idx: usize, err: anyhow::Error },
-    Failed { idx: usize, err: anyhow::Error },
-    Exited { idx: usize, err: anyhow::Error },
+    Ready {
+        idx: usize,
+    },
+    Disconnected {
+        idx: usize,
+        err: anyhow::Error,
+    },
+    Failed {
+        idx: usize,
+        err: anyhow::Error,
+    },
+    Restarting {
+        idx: usize,
+        attempt: u32,
+        delay: Duration,
+        err: anyhow::Error,
+    },
+    Exited {
+        idx: usize,
+        err: anyhow::Error,
+    },
+}
+
+impl LifeCycleMessage {
+    pub fn idx(self: &Self) -> usize {
+        match self {
+            LifeCycleMessage::Ready { idx } => *idx,
+            LifeCycleMessage::Disconnected { idx, .. } => *idx,
+            LifeCycleMessage::Failed { idx, .. } => *idx,
+            LifeCycleMessage::Restarting { idx, .. } => *idx,
+            LifeCycleMessage::Exited { idx, .. } => *idx,
+        }
+    }
+
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            LifeCycleMessage::Ready { .. } => "ready",
+            LifeCycleMessage::Disconnected { .. } => "disconnected",
+            LifeCycleMessage::Failed { .. } => "failed",
+            LifeCycleMessage::Restarting { .. } => "restarting",
+            LifeCycleMessage::Exited { .. } => "exited",
+        }
+    }
 }
 
 pub type LifeCycleTX = mpsc::Sender<LifeCycleMessage>;
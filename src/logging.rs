@@ -0,0 +1,31 @@
+use std::io::IsTerminal;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::{Args, LogFormat};
+
+/// Installs the global `tracing` subscriber. `RUST_LOG` takes precedence over `--debug`.
+pub fn init_logging(args: &Args) {
+    let default_filter = if args.debug {
+        "info,postoffice=trace"
+    } else {
+        "info"
+    };
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal());
+
+    match args.log_format {
+        LogFormat::Full => builder.init(),
+        LogFormat::Compact => builder.compact().init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .init(),
+    }
+}
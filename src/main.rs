@@ -1,144 +1,82 @@
-mod block;
+#![allow(
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type,
+    clippy::upper_case_acronyms
+)]
+
+mod bench;
 mod cli;
-mod connector;
-mod lifecycle;
-mod message;
-mod pipeline;
+mod graph;
+mod logging;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use anyhow::Context;
 use clap::Parser;
-use cli::{Args, get_config};
-use tokio::sync::mpsc;
+use cli::{Args, Command, get_config};
+use postoffice::{Pipeline, Postoffice, shutdown::wait_for_signal};
 
-use connector::{ConnectorHandle, make_connector};
-use lifecycle::{LifeCycleHandler, LifeCycleMessage};
-use message::InternalMessage;
-use pipeline::Pipeline;
+use bench::{run_bench, use_fresh_vms};
+use graph::render_graph;
+use logging::init_logging;
+use tracing::trace;
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let config =
-        get_config(&args).expect(format!("Can't read config at \"{}\"", args.file).as_str());
+    init_logging(&args);
+
+    let mut config = get_config(&args)
+        .unwrap_or_else(|e| panic!("Can't read config at \"{}\": {}", args.file, e));
+
+    trace!(?config, "Loaded config");
 
-    if args.debug {
-        println!("{:#?}", config);
+    if let Some(Command::Graph { format }) = args.command {
+        print!("{}", render_graph(&config, format));
+        return;
     }
 
-    let life_cycle_handler = LifeCycleHandler::start(
-        config
-            .connectors
-            .iter()
-            .enumerate()
-            .map(|(idx, _)| idx)
-            .collect(),
-    );
-
-    let (source_tx, mut source_rx) = mpsc::channel::<InternalMessage>(32);
-
-    let pipeline = Arc::new(
-        Pipeline::new(config.blocks, args.ignore_cycles).expect("Unable to create pipeline"),
-    );
-
-    println!("Starting connectors");
-
-    let mut connector_handles: Vec<ConnectorHandle> = vec![];
-
-    for (idx, con) in config.connectors.into_iter().enumerate() {
-        println!("[Connector {}] Starting", idx);
-        let handle = make_connector(
-            idx,
-            source_tx.clone(),
-            con,
-            life_cycle_handler.lifecycle_tx.clone(),
-        )
-        .await;
-
-        match handle {
-            Ok(handle) => {
-                connector_handles.push(handle);
-            }
-            Err(e) => {
-                life_cycle_handler
-                    .lifecycle_tx
-                    .send(LifeCycleMessage::Exited { idx, err: e.into() })
-                    .await
-                    .expect("Failed to send LifeCycleMessage");
-            }
+    if let Some(Command::Bench(ref bench_args)) = args.command {
+        if bench_args.fresh_vm {
+            use_fresh_vms(&mut config.blocks);
         }
+
+        let pipeline = Arc::new(
+            Pipeline::new(config.blocks, args.ignore_cycles, config.max_hops)
+                .unwrap_or_else(|e| exit_with_error(e)),
+        );
+
+        run_bench(pipeline, bench_args)
+            .await
+            .unwrap_or_else(|e| panic!("Benchmark failed: {:#}", e));
+        return;
     }
 
-    let connector_handles = Arc::new(connector_handles);
+    let mut builder = Postoffice::builder(config)
+        .ignore_cycles(args.ignore_cycles)
+        .startup_timeout(Duration::from_millis(args.startup_timeout))
+        .shutdown_timeout(Duration::from_millis(args.shutdown_timeout));
+
+    if let Some(port) = args.admin_port {
+        builder = builder.admin(args.admin_interface.clone(), port);
+    }
 
-    println!("Waiting for connectors");
+    let postoffice = builder.build().unwrap_or_else(|e| exit_with_error(e));
 
-    life_cycle_handler
-        .wait_all_ready()
+    let exit_code = postoffice
+        .run_until(async {
+            wait_for_signal()
+                .await
+                .expect("Unable to listen for shutdown signals")
+        })
         .await
-        .expect("Unable to wait_all_ready");
-
-    println!("Startup complete");
-
-    loop {
-        let incoming = source_rx.recv().await;
-
-        match incoming {
-            Some(incoming) => {
-                if args.debug {
-                    println!("\nIncoming Message {:#?}", incoming);
-                } else {
-                    println!("\nIncoming Message with Topic {:#?}", incoming.topic);
-                }
-
-                let pipeline = pipeline.clone();
-                let connector_handles = connector_handles.clone();
-
-                tokio::spawn(async move {
-                    let handle = connector_handles
-                        .get(incoming.source_connector_idx)
-                        .context(format!(
-                            "Missing connector with index {}",
-                            incoming.source_connector_idx,
-                        ))
-                        .expect("Failed to get connector")
-                        .clone();
-
-                    let mut collector: Vec<(usize, InternalMessage)> = vec![];
-
-                    pipeline
-                        .handle_message_with_connections(&handle.to, incoming, &mut collector)
-                        .await
-                        .expect("Failed to handle message");
-
-                    if args.debug {
-                        println!("Collected messages {:#?}", collector);
-                    } else {
-                        println!("Collected {:#?} messages", collector.len());
-                    }
-
-                    for (sink_idx, message) in collector {
-                        let handle = connector_handles
-                            .get(sink_idx)
-                            .context(format!("Missing sink with index {}", sink_idx))
-                            .expect("Failed to get sink");
-
-                        handle.sink_tx.send(message).await.expect(&format!(
-                            "Failed to send message to sink_rx with idx {}",
-                            sink_idx
-                        ));
-                    }
-                });
-            }
-            None => {
-                // This can only happen if `source_rx.recv()` returns `None` which means
-                // that all `source_tx` channel halfs are closed.
-                panic!(
-                    "source_rx.recv() returned None which means that no work can be done at this point"
-                )
-            }
-        }
-    }
+        .expect("Unable to start postoffice");
+
+    std::process::exit(exit_code);
+}
+
+/// Reports a config that can't be run, e.g. because of cycles, without a panic
+fn exit_with_error(e: anyhow::Error) -> ! {
+    eprintln!("\n[ERROR]: {:#}\n", e);
+    std::process::exit(1);
 }
@@ -2,7 +2,7 @@ use std::vec;
 
 use anyhow::Context;
 use bytes::Bytes;
-use rosc::{OscArray, OscType};
+use rosc::{OscArray, OscMessage, OscPacket, OscType};
 use serde_json::{Number, Value, json};
 
 #[derive(Debug, Clone)]
@@ -10,6 +10,43 @@ pub struct InternalMessage {
     pub source_connector_idx: usize,
     pub topic: String,
     pub data: InternalMessageData,
+    /// Set once the message is routed as a dead letter, so it can't become one again
+    pub dead_letter: bool,
+    /// Number of blocks the message passed so far
+    pub hops: u32,
+}
+
+impl InternalMessage {
+    /// Replaces the body with a JSON object that describes why the message failed.
+    /// `origin` names the place where it failed, e.g. `("block", 3)`.
+    ///
+    /// ```json
+    /// { "error": "...", "block": 3, "topic": "/a/b", "data": ... }
+    /// ```
+    pub fn into_error_message(
+        mut self: Self,
+        err: &anyhow::Error,
+        origin: (&str, usize),
+    ) -> InternalMessage {
+        // Keep as much of the original body as possible
+        let data = match self.data.clone().to_json() {
+            Ok(InternalMessageData::Json(value)) => value,
+            _ => match self.data.to_string() {
+                Ok(InternalMessageData::String(value)) => Value::String(value),
+                _ => Value::Null,
+            },
+        };
+
+        let mut body = serde_json::Map::new();
+        body.insert("error".to_string(), Value::String(format!("{:#}", err)));
+        body.insert(origin.0.to_string(), Value::Number(Number::from(origin.1)));
+        body.insert("topic".to_string(), Value::String(self.topic.clone()));
+        body.insert("data".to_string(), data);
+
+        self.data = InternalMessageData::Json(Value::Object(body));
+
+        return self;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,6 +59,51 @@ pub enum InternalMessageData {
 }
 
 impl InternalMessageData {
+    /// Names the variant like the types of `ConvertBody`, e.g. for the `data_type` of scripts
+    pub fn type_name(self: &Self) -> &'static str {
+        match self {
+            Self::Empty => "Empty",
+            Self::String(_) => "String",
+            Self::Binary(_) => "Binary",
+            Self::Json(_) => "JSON",
+            Self::OSC(_) => "OSC",
+        }
+    }
+
+    /// Encodes the body as bytes, tagged with its type: `0` Empty, `1` String, `2` Binary,
+    /// `3` JSON and `4` OSC. JSON is encoded as text and OSC arguments as an OSC message with
+    /// the address `/`.
+    pub fn encode(self: &Self) -> anyhow::Result<(u8, Vec<u8>)> {
+        match self {
+            Self::Empty => Ok((0, vec![])),
+            Self::String(value) => Ok((1, value.as_bytes().to_vec())),
+            Self::Binary(bytes) => Ok((2, bytes.to_vec())),
+            Self::Json(value) => Ok((3, serde_json::to_vec(value)?)),
+            Self::OSC(args) => Ok((
+                4,
+                rosc::encoder::encode(&OscPacket::Message(OscMessage {
+                    addr: "/".to_string(),
+                    args: args.clone(),
+                }))?,
+            )),
+        }
+    }
+
+    /// Decodes a body that was encoded by `encode`
+    pub fn decode(tag: u8, data: &[u8]) -> anyhow::Result<Self> {
+        match tag {
+            0 => Ok(Self::Empty),
+            1 => Ok(Self::String(String::from_utf8(data.to_vec())?)),
+            2 => Ok(Self::Binary(Bytes::copy_from_slice(data))),
+            3 => Ok(Self::Json(serde_json::from_slice(data)?)),
+            4 => match rosc::decoder::decode_udp(data)?.1 {
+                OscPacket::Message(message) => Ok(Self::OSC(message.args)),
+                OscPacket::Bundle(_) => Err(anyhow::Error::msg("Expected OSC message, got bundle")),
+            },
+            tag => Err(anyhow::Error::msg(format!("Unknown body type {}", tag))),
+        }
+    }
+
     pub fn to_empty(self: Self) -> anyhow::Result<Self> {
         Ok(Self::Empty)
     }
@@ -108,7 +190,7 @@ impl InternalMessageData {
         match value {
             Value::Array(values) => Ok(values
                 .into_iter()
-                .map(|value| Self::json_to_osc(value))
+                .map(Self::json_to_osc)
                 .collect::<anyhow::Result<Vec<_>>>()?),
             _ => Err(anyhow::Error::msg(
                 "Can only convert JSON Array to OSC at the top level",
@@ -121,7 +203,7 @@ impl InternalMessageData {
             Value::Array(values) => Ok(OscType::Array(OscArray {
                 content: values
                     .into_iter()
-                    .map(|value| Self::json_to_osc(value))
+                    .map(Self::json_to_osc)
                     .collect::<anyhow::Result<Vec<_>>>()?,
             })),
             Value::Null => Ok(OscType::Nil),
@@ -141,7 +223,7 @@ impl InternalMessageData {
     fn osc_args_to_json(args: Vec<OscType>) -> anyhow::Result<Value> {
         return Ok(Value::Array(
             args.into_iter()
-                .map(|value| Self::osc_to_json(value))
+                .map(Self::osc_to_json)
                 .collect::<anyhow::Result<Vec<_>>>()?,
         ));
     }
@@ -171,3 +253,60 @@ impl InternalMessageData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: InternalMessageData) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 2,
+            topic: "/a/b".to_string(),
+            data,
+            dead_letter: false,
+            hops: 3,
+        }
+    }
+
+    fn error_body(data: InternalMessageData) -> Value {
+        let err = anyhow::Error::msg("inner").context("outer");
+        let message = message(data).into_error_message(&err, ("block", 4));
+
+        // Only the body changes
+        assert_eq!(message.topic, "/a/b");
+        assert_eq!(message.source_connector_idx, 2);
+        assert_eq!(message.hops, 3);
+
+        let InternalMessageData::Json(body) = message.data else {
+            panic!("Expected a JSON body, got {:?}", message.data);
+        };
+        body
+    }
+
+    #[test]
+    fn error_message_describes_the_error() {
+        let body = error_body(InternalMessageData::Json(json!({ "x": 1 })));
+
+        assert_eq!(
+            body,
+            json!({ "error": "outer: inner", "block": 4, "topic": "/a/b", "data": { "x": 1 } })
+        );
+    }
+
+    #[test]
+    fn error_message_keeps_the_body() {
+        let body = error_body(InternalMessageData::OSC(vec![
+            OscType::Int(1),
+            OscType::String("a".to_string()),
+        ]));
+        assert_eq!(body["data"], json!([1, "a"]));
+
+        // Strings that aren't JSON stay strings
+        let body = error_body(InternalMessageData::String("not json".to_string()));
+        assert_eq!(body["data"], json!("not json"));
+
+        // Neither JSON nor a string
+        let body = error_body(InternalMessageData::OSC(vec![OscType::Inf]));
+        assert_eq!(body["data"], Value::Null);
+    }
+}
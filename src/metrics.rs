@@ -0,0 +1,426 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Returns the global metrics registry
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Counter {
+    SourceMessages,
+    BlockMessagesIn,
+    BlockMessagesOut,
+    BlockMessagesDropped,
+    BlockErrors,
+    SinkMessages,
+    ConversionErrors,
+    LifeCycleEvents,
+    QueueDropped,
+    QueueSpilled,
+    ConcurrencyDropped,
+    HopLimitExceeded,
+}
+
+impl Counter {
+    const ALL: [Counter; 12] = [
+        Counter::SourceMessages,
+        Counter::BlockMessagesIn,
+        Counter::BlockMessagesOut,
+        Counter::BlockMessagesDropped,
+        Counter::BlockErrors,
+        Counter::SinkMessages,
+        Counter::ConversionErrors,
+        Counter::LifeCycleEvents,
+        Counter::QueueDropped,
+        Counter::QueueSpilled,
+        Counter::ConcurrencyDropped,
+        Counter::HopLimitExceeded,
+    ];
+
+    fn name(self: &Self) -> &'static str {
+        match self {
+            Counter::SourceMessages => "postoffice_source_messages_total",
+            Counter::BlockMessagesIn => "postoffice_block_messages_in_total",
+            Counter::BlockMessagesOut => "postoffice_block_messages_out_total",
+            Counter::BlockMessagesDropped => "postoffice_block_messages_dropped_total",
+            Counter::BlockErrors => "postoffice_block_errors_total",
+            Counter::SinkMessages => "postoffice_sink_messages_total",
+            Counter::ConversionErrors => "postoffice_conversion_errors_total",
+            Counter::LifeCycleEvents => "postoffice_lifecycle_events_total",
+            Counter::QueueDropped => "postoffice_queue_dropped_total",
+            Counter::QueueSpilled => "postoffice_queue_spilled_total",
+            Counter::ConcurrencyDropped => "postoffice_concurrency_dropped_total",
+            Counter::HopLimitExceeded => "postoffice_hop_limit_exceeded_total",
+        }
+    }
+
+    fn help(self: &Self) -> &'static str {
+        match self {
+            Counter::SourceMessages => "Messages received by a source connector",
+            Counter::BlockMessagesIn => "Messages passed into a block",
+            Counter::BlockMessagesOut => "Messages returned by a block",
+            Counter::BlockMessagesDropped => "Messages dropped by a block",
+            Counter::BlockErrors => "Messages for which a block returned an error",
+            Counter::SinkMessages => "Messages delivered by a sink connector",
+            Counter::ConversionErrors => "Messages a sink connector failed to convert",
+            Counter::LifeCycleEvents => "LifeCycle events reported by a connector",
+            Counter::QueueDropped => "Messages dropped because a queue was full",
+            Counter::QueueSpilled => "Messages written to disk because a queue was full",
+            Counter::ConcurrencyDropped => {
+                "Messages from a source dropped because a concurrency limit was reached"
+            }
+            Counter::HopLimitExceeded => "Messages dropped because they exceeded the hop limit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Gauge {
+    QueueDepth,
+}
+
+impl Gauge {
+    const ALL: [Gauge; 1] = [Gauge::QueueDepth];
+
+    fn name(self: &Self) -> &'static str {
+        match self {
+            Gauge::QueueDepth => "postoffice_queue_depth",
+        }
+    }
+
+    fn help(self: &Self) -> &'static str {
+        match self {
+            Gauge::QueueDepth => "Messages waiting in a queue, including spilled ones",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Histogram {
+    BlockLatency,
+}
+
+impl Histogram {
+    const ALL: [Histogram; 1] = [Histogram::BlockLatency];
+
+    /// Upper bounds of the buckets in seconds
+    const BUCKETS: [f64; 12] = [
+        0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0,
+    ];
+
+    fn name(self: &Self) -> &'static str {
+        match self {
+            Histogram::BlockLatency => "postoffice_block_latency_seconds",
+        }
+    }
+
+    fn help(self: &Self) -> &'static str {
+        match self {
+            Histogram::BlockLatency => "Time a block took to handle a message",
+        }
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// A counter with fixed labels, see `Metrics::counter`. Clones share the value.
+#[derive(Debug, Clone, Default)]
+pub struct CounterSeries(Arc<AtomicU64>);
+
+impl CounterSeries {
+    pub fn inc(self: &Self) {
+        self.add(1);
+    }
+
+    pub fn add(self: &Self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(self: &Self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A gauge with fixed labels, see `Metrics::gauge`. Holds the bits of an `f64`.
+#[derive(Debug, Clone, Default)]
+pub struct GaugeSeries(Arc<AtomicU64>);
+
+impl GaugeSeries {
+    pub fn set(self: &Self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(self: &Self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistogramValue {
+    buckets: [AtomicU64; Histogram::BUCKETS.len()],
+    /// Sum of all observations in nanoseconds
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+/// A histogram with fixed labels, see `Metrics::histogram`
+#[derive(Debug, Clone, Default)]
+pub struct HistogramSeries(Arc<HistogramValue>);
+
+impl HistogramSeries {
+    pub fn observe(self: &Self, duration: Duration) {
+        let value = &self.0;
+        let secs = duration.as_secs_f64();
+
+        for (idx, bound) in Histogram::BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                value.buckets[idx].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        value.sum.fetch_add(
+            duration.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        value.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Every series by its labels. The locks are only taken to register a series and to render
+/// them, the values are updated through the series without a lock.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<(Counter, Labels), CounterSeries>>,
+    gauges: Mutex<BTreeMap<(Gauge, Labels), GaugeSeries>>,
+    histograms: Mutex<BTreeMap<(Histogram, Labels), HistogramSeries>>,
+}
+
+impl Metrics {
+    /// Returns the series of `counter` with `labels` and registers it if it doesn't exist yet.
+    /// Keep the series for values that change with every message, e.g. per block.
+    pub fn counter(
+        self: &Self,
+        counter: Counter,
+        labels: &[(&'static str, &str)],
+    ) -> CounterSeries {
+        let mut counters = self.counters.lock().expect("Metrics lock poisoned");
+
+        return counters
+            .entry((counter, to_labels(labels)))
+            .or_default()
+            .clone();
+    }
+
+    pub fn gauge(self: &Self, gauge: Gauge, labels: &[(&'static str, &str)]) -> GaugeSeries {
+        let mut gauges = self.gauges.lock().expect("Metrics lock poisoned");
+
+        return gauges
+            .entry((gauge, to_labels(labels)))
+            .or_default()
+            .clone();
+    }
+
+    pub fn histogram(
+        self: &Self,
+        histogram: Histogram,
+        labels: &[(&'static str, &str)],
+    ) -> HistogramSeries {
+        let mut histograms = self.histograms.lock().expect("Metrics lock poisoned");
+
+        return histograms
+            .entry((histogram, to_labels(labels)))
+            .or_default()
+            .clone();
+    }
+
+    /// Increments a counter that rarely changes, e.g. on lifecycle events
+    pub fn inc(self: &Self, counter: Counter, labels: &[(&'static str, &str)]) {
+        self.counter(counter, labels).inc();
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(self: &Self) -> String {
+        let mut out = String::new();
+
+        let counters = self.counters.lock().expect("Metrics lock poisoned").clone();
+
+        for counter in Counter::ALL {
+            let _ = writeln!(out, "# HELP {} {}", counter.name(), counter.help());
+            let _ = writeln!(out, "# TYPE {} counter", counter.name());
+
+            for ((_, labels), series) in counters.iter().filter(|((c, _), _)| *c == counter) {
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    counter.name(),
+                    format_labels(labels, None),
+                    series.get()
+                );
+            }
+        }
+
+        let gauges = self.gauges.lock().expect("Metrics lock poisoned").clone();
+
+        for gauge in Gauge::ALL {
+            let _ = writeln!(out, "# HELP {} {}", gauge.name(), gauge.help());
+            let _ = writeln!(out, "# TYPE {} gauge", gauge.name());
+
+            for ((_, labels), series) in gauges.iter().filter(|((g, _), _)| *g == gauge) {
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    gauge.name(),
+                    format_labels(labels, None),
+                    series.get()
+                );
+            }
+        }
+
+        let histograms = self
+            .histograms
+            .lock()
+            .expect("Metrics lock poisoned")
+            .clone();
+
+        for histogram in Histogram::ALL {
+            let _ = writeln!(out, "# HELP {} {}", histogram.name(), histogram.help());
+            let _ = writeln!(out, "# TYPE {} histogram", histogram.name());
+
+            for ((_, labels), series) in histograms.iter().filter(|((h, _), _)| *h == histogram) {
+                let value = &series.0;
+                let count = value.count.load(Ordering::Relaxed);
+
+                for (idx, bound) in Histogram::BUCKETS.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{} {}",
+                        histogram.name(),
+                        format_labels(labels, Some(&bound.to_string())),
+                        value.buckets[idx].load(Ordering::Relaxed)
+                    );
+                }
+
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    histogram.name(),
+                    format_labels(labels, Some("+Inf")),
+                    count
+                );
+                let _ = writeln!(
+                    out,
+                    "{}_sum{} {}",
+                    histogram.name(),
+                    format_labels(labels, None),
+                    value.sum.load(Ordering::Relaxed) as f64 / 1e9
+                );
+                let _ = writeln!(
+                    out,
+                    "{}_count{} {}",
+                    histogram.name(),
+                    format_labels(labels, None),
+                    count
+                );
+            }
+        }
+
+        return out;
+    }
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut parts: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+
+    if let Some(le) = le {
+        parts.push(format!("le=\"{}\"", le));
+    }
+
+    if parts.is_empty() {
+        return String::new();
+    }
+
+    return format!("{{{}}}", parts.join(","));
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+
+        let sent = metrics.counter(Counter::SinkMessages, &[("connector", "1")]);
+        sent.inc();
+        sent.add(2);
+        // Registering a series again returns the same value
+        metrics
+            .counter(Counter::SinkMessages, &[("connector", "1")])
+            .inc();
+
+        metrics
+            .gauge(Gauge::QueueDepth, &[("queue", "sink"), ("connector", "1")])
+            .set(3.0);
+
+        let latency = metrics.histogram(Histogram::BlockLatency, &[("block", "0")]);
+        latency.observe(Duration::from_millis(2));
+        latency.observe(Duration::from_millis(200));
+
+        metrics.inc(
+            Counter::LifeCycleEvents,
+            &[("connector", "a\"b\\c\nd"), ("event", "ready")],
+        );
+
+        let out = metrics.render();
+        let lines: Vec<&str> = out.lines().collect();
+
+        for line in [
+            "# HELP postoffice_sink_messages_total Messages delivered by a sink connector",
+            "# TYPE postoffice_sink_messages_total counter",
+            "postoffice_sink_messages_total{connector=\"1\"} 4",
+            "# TYPE postoffice_queue_depth gauge",
+            "postoffice_queue_depth{queue=\"sink\",connector=\"1\"} 3",
+            "# TYPE postoffice_block_latency_seconds histogram",
+            "postoffice_block_latency_seconds_bucket{block=\"0\",le=\"0.001\"} 0",
+            "postoffice_block_latency_seconds_bucket{block=\"0\",le=\"0.005\"} 1",
+            "postoffice_block_latency_seconds_bucket{block=\"0\",le=\"0.5\"} 2",
+            "postoffice_block_latency_seconds_bucket{block=\"0\",le=\"+Inf\"} 2",
+            "postoffice_block_latency_seconds_sum{block=\"0\"} 0.202",
+            "postoffice_block_latency_seconds_count{block=\"0\"} 2",
+            "postoffice_lifecycle_events_total{connector=\"a\\\"b\\\\c\\nd\",event=\"ready\"} 1",
+        ] {
+            assert!(lines.contains(&line), "Missing {:?} in\n{}", line, out);
+        }
+
+        // Every metric is described, even without series
+        assert!(lines.contains(&"# TYPE postoffice_hop_limit_exceeded_total counter"));
+        assert!(!out.contains("postoffice_hop_limit_exceeded_total{"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::HashSet;
+
+/// `find_cycles` stops after this many cycles, their number can grow exponentially
+pub const MAX_CYCLES: usize = 100;
+
+/// Finds every elementary cycle in a graph, given as the outgoing edges of every node by its
+/// index, with Johnson's algorithm.
+///
+/// Every cycle is reported once, as a path that starts and ends with its node with the lowest
+/// index. Cycles are sorted by that node. Nodes for which `skip` returns `true` are left out of
+/// the graph, so cycles through them aren't reported. At most `MAX_CYCLES` cycles are returned.
+pub fn find_cycles(edges: &[Vec<usize>], skip: impl Fn(usize) -> bool) -> Vec<Vec<usize>> {
+    let edges: Vec<Vec<usize>> = edges
+        .iter()
+        .enumerate()
+        .map(|(node, to)| {
+            if skip(node) {
+                return vec![];
+            }
+
+            let mut to: Vec<usize> = to
+                .iter()
+                .copied()
+                .filter(|to| *to < edges.len() && !skip(*to))
+                .collect();
+
+            // A block can be connected to another one more than once, e.g. by `to` and `on_error`
+            to.sort();
+            to.dedup();
+
+            to
+        })
+        .collect();
+
+    let mut state = Johnson {
+        edges: vec![],
+        start: 0,
+        blocked: vec![false; edges.len()],
+        blocked_by: vec![HashSet::new(); edges.len()],
+        stack: vec![],
+        cycles: vec![],
+    };
+
+    for start in 0..edges.len() {
+        // Only the nodes from `start` on, the cycles through lower nodes were already found
+        let sub_edges: Vec<Vec<usize>> = edges
+            .iter()
+            .enumerate()
+            .map(|(node, to)| match node >= start {
+                true => to.iter().copied().filter(|to| *to >= start).collect(),
+                false => vec![],
+            })
+            .collect();
+
+        let Some(component) = strongly_connected_components(&sub_edges)
+            .into_iter()
+            .find(|component| component.contains(&start))
+        else {
+            continue;
+        };
+
+        let members: HashSet<usize> = component.into_iter().collect();
+
+        state.edges = sub_edges
+            .into_iter()
+            .enumerate()
+            .map(|(node, to)| match members.contains(&node) {
+                true => to.into_iter().filter(|to| members.contains(to)).collect(),
+                false => vec![],
+            })
+            .collect();
+        state.start = start;
+
+        for node in members {
+            state.blocked[node] = false;
+            state.blocked_by[node].clear();
+        }
+
+        state.circuit(start);
+
+        if state.cycles.len() >= MAX_CYCLES {
+            state.cycles.truncate(MAX_CYCLES);
+            break;
+        }
+    }
+
+    return state.cycles;
+}
+
+struct Johnson {
+    /// The edges of the strongly connected component that is searched
+    edges: Vec<Vec<usize>>,
+    /// The node with the lowest index of the component, every cycle starts there
+    start: usize,
+    blocked: Vec<bool>,
+    /// Nodes that are unblocked together with the node, because they only lead to it
+    blocked_by: Vec<HashSet<usize>>,
+    stack: Vec<usize>,
+    cycles: Vec<Vec<usize>>,
+}
+
+impl Johnson {
+    /// Returns whether a cycle back to `start` was found from `node`
+    fn circuit(self: &mut Self, node: usize) -> bool {
+        let mut found = false;
+
+        self.stack.push(node);
+        self.blocked[node] = true;
+
+        for to in self.edges[node].clone() {
+            if self.cycles.len() >= MAX_CYCLES {
+                break;
+            }
+
+            if to == self.start {
+                let mut cycle = self.stack.clone();
+                cycle.push(self.start);
+                self.cycles.push(cycle);
+
+                found = true;
+            } else if !self.blocked[to] && self.circuit(to) {
+                found = true;
+            }
+        }
+
+        if found {
+            self.unblock(node);
+        } else {
+            for to in self.edges[node].clone() {
+                self.blocked_by[to].insert(node);
+            }
+        }
+
+        self.stack.pop();
+
+        return found;
+    }
+
+    fn unblock(self: &mut Self, node: usize) {
+        self.blocked[node] = false;
+
+        for other in std::mem::take(&mut self.blocked_by[node]) {
+            if self.blocked[other] {
+                self.unblock(other);
+            }
+        }
+    }
+}
+
+/// Tarjan's algorithm
+fn strongly_connected_components(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct State<'a> {
+        edges: &'a [Vec<usize>],
+        next_index: usize,
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        components: Vec<Vec<usize>>,
+    }
+
+    fn visit(state: &mut State, node: usize) {
+        state.index[node] = Some(state.next_index);
+        state.low_link[node] = state.next_index;
+        state.next_index += 1;
+        state.stack.push(node);
+        state.on_stack[node] = true;
+
+        for to in state.edges[node].iter().copied() {
+            match state.index[to] {
+                None => {
+                    visit(state, to);
+                    state.low_link[node] = state.low_link[node].min(state.low_link[to]);
+                }
+                Some(index) if state.on_stack[to] => {
+                    state.low_link[node] = state.low_link[node].min(index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        if Some(state.low_link[node]) == state.index[node] {
+            let mut component = vec![];
+
+            while let Some(member) = state.stack.pop() {
+                state.on_stack[member] = false;
+                component.push(member);
+
+                if member == node {
+                    break;
+                }
+            }
+
+            state.components.push(component);
+        }
+    }
+
+    let mut state = State {
+        edges,
+        next_index: 0,
+        index: vec![None; edges.len()],
+        low_link: vec![0; edges.len()],
+        on_stack: vec![false; edges.len()],
+        stack: vec![],
+        components: vec![],
+    };
+
+    for node in 0..edges.len() {
+        if state.index[node].is_none() {
+            visit(&mut state, node);
+        }
+    }
+
+    return state.components;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_rotations_once() {
+        let edges = vec![vec![1], vec![2], vec![0]];
+
+        assert_eq!(find_cycles(&edges, |_| false), [vec![0, 1, 2, 0]]);
+    }
+
+    #[test]
+    fn reports_every_cycle_of_a_component() {
+        let edges = vec![vec![1], vec![0, 2], vec![0, 1]];
+
+        assert_eq!(
+            find_cycles(&edges, |_| false),
+            [vec![0, 1, 0], vec![0, 1, 2, 0], vec![1, 2, 1]]
+        );
+    }
+
+    #[test]
+    fn reports_self_loops() {
+        let edges = vec![vec![1], vec![1, 1]];
+
+        assert_eq!(find_cycles(&edges, |_| false), [vec![1, 1]]);
+    }
+
+    #[test]
+    fn ignores_acyclic_graphs() {
+        let edges = vec![vec![1, 2], vec![2], vec![], vec![5]];
+
+        assert!(find_cycles(&edges, |_| false).is_empty());
+    }
+
+    #[test]
+    fn skips_loop_safe_nodes() {
+        let edges = vec![vec![1, 2], vec![0], vec![0]];
+
+        assert_eq!(find_cycles(&edges, |node| node == 1), [vec![0, 2, 0]]);
+        assert!(find_cycles(&edges, |node| node == 0).is_empty());
+    }
+
+    #[test]
+    fn stops_at_max_cycles() {
+        // Every node is connected to every node, which has more than 400 cycles
+        let edges: Vec<Vec<usize>> = (0..6).map(|_| (0..6).collect()).collect();
+
+        assert_eq!(find_cycles(&edges, |_| false).len(), MAX_CYCLES);
+    }
+}
@@ -1,62 +1,123 @@
+mod cycles;
+
+use std::time::Instant;
+
 use anyhow::Context;
+use tracing::{Instrument, debug, debug_span, error, warn};
+
+use cycles::{MAX_CYCLES, find_cycles};
 
 use crate::{
     block::{BlockConfig, BlockHandle, Connection, make_block},
     message::InternalMessage,
+    metrics::{Counter, CounterSeries, Histogram, HistogramSeries, metrics},
 };
 
+/// The global hop limit if cycles are ignored and the config doesn't set one
+const IGNORED_CYCLES_MAX_HOPS: u32 = 64;
+
+/// The metrics of a block, registered once instead of for every message
+struct BlockMetrics {
+    messages_in: CounterSeries,
+    messages_out: CounterSeries,
+    dropped: CounterSeries,
+    errors: CounterSeries,
+    hop_limit_exceeded: CounterSeries,
+    latency: HistogramSeries,
+}
+
+impl BlockMetrics {
+    fn new(idx: usize) -> Self {
+        let label = idx.to_string();
+        let labels = [("block", label.as_str())];
+
+        Self {
+            messages_in: metrics().counter(Counter::BlockMessagesIn, &labels),
+            messages_out: metrics().counter(Counter::BlockMessagesOut, &labels),
+            dropped: metrics().counter(Counter::BlockMessagesDropped, &labels),
+            errors: metrics().counter(Counter::BlockErrors, &labels),
+            hop_limit_exceeded: metrics().counter(Counter::HopLimitExceeded, &labels),
+            latency: metrics().histogram(Histogram::BlockLatency, &labels),
+        }
+    }
+}
+
 pub struct Pipeline {
     blocks: Vec<BlockHandle>,
+    /// The metrics of every block by its index
+    metrics: Vec<BlockMetrics>,
+    /// Messages that passed more blocks than this are dropped, unless the block has its own limit
+    max_hops: Option<u32>,
 }
 
 impl Pipeline {
-    pub fn new(block_config: Vec<BlockConfig>, ignore_cycles: bool) -> anyhow::Result<Self> {
+    /// Fails if the blocks contain a cycle that isn't loop-safe, unless `ignore_cycles` is set.
+    /// Without cycles a message can only pass a limited number of blocks, so the global
+    /// `max_hops` only defaults to a limit if cycles are ignored.
+    pub fn new(
+        block_config: Vec<BlockConfig>,
+        ignore_cycles: bool,
+        max_hops: Option<u32>,
+    ) -> anyhow::Result<Self> {
         let mut blocks = vec![];
         for config in block_config {
             blocks.push(make_block(config)?);
         }
 
-        let pipeline = Self { blocks };
+        let max_hops = match ignore_cycles {
+            true => max_hops.or(Some(IGNORED_CYCLES_MAX_HOPS)),
+            false => max_hops,
+        };
+
+        let metrics = (0..blocks.len()).map(BlockMetrics::new).collect();
+
+        let pipeline = Self {
+            blocks,
+            metrics,
+            max_hops,
+        };
 
         let cycles = pipeline.get_cycles();
 
-        if cycles.len() > 0 {
-            let path = cycles
+        if !cycles.is_empty() {
+            let mut path = cycles
                 .iter()
-                .map(|path| {
-                    path.iter()
-                        .map(|entry| format!("Block {}", entry))
-                        .collect::<Vec<_>>()
-                        .join(" -> ")
-                })
+                .map(|cycle| pipeline.describe_cycle(cycle))
                 .collect::<Vec<_>>()
                 .join("\n");
 
+            if cycles.len() >= MAX_CYCLES {
+                path.push_str(&format!("\nOnly the first {} cycles are shown", MAX_CYCLES));
+            }
+
             if ignore_cycles {
-                println!(
-                    "
-[WARN]: Detected cycles in config:
-{}
-Ignoring cycles because of --ignore-cycles
-",
+                warn!(
+                    "Detected cycles in config:\n{}\nIgnoring cycles because of --ignore-cycles",
                     path
                 );
             } else {
-                panic!(
-                    "
-[WARN]: Detected cycles in config:
+                return Err(anyhow::Error::msg(format!(
+                    "Detected cycles in config:
 {}
 Not all invariants are covered - the config may still be valid
-Run with --ignore-cycles to ignore cycles
-",
+Set `max_hops` on a block of each cycle or run with --ignore-cycles to ignore cycles",
                     path
-                );
+                )));
             }
         }
 
         return Ok(pipeline);
     }
 
+    /// Lets every block clean up, e.g. to save its state
+    pub async fn shutdown(self: &Self) {
+        for (idx, handle) in self.blocks.iter().enumerate() {
+            if let Err(e) = handle.block.shutdown().await {
+                error!(block = %handle.describe(idx), "Failed to shut down block: {:#}", e);
+            }
+        }
+    }
+
     pub async fn handle_message_with_connections(
         self: &Self,
         to: &Vec<Connection>,
@@ -80,23 +141,96 @@ Run with --ignore-cycles to ignore cycles
     pub async fn handle_message(
         self: &Self,
         block_idx: usize,
-        message: InternalMessage,
+        mut message: InternalMessage,
         collector: &mut Vec<(usize, InternalMessage)>,
     ) -> anyhow::Result<()> {
         let handle = self
             .blocks
             .get(block_idx)
             .context(format!("Missing block with index {}", block_idx))?;
+        let series = &self.metrics[block_idx];
+
+        message.hops += 1;
+
+        if handle
+            .options
+            .max_hops
+            .or(self.max_hops)
+            .is_some_and(|max_hops| message.hops > max_hops)
+        {
+            series.hop_limit_exceeded.inc();
+
+            warn!(
+                block = block_idx,
+                hops = message.hops,
+                "Hop limit exceeded, dropping message"
+            );
+
+            return Ok(());
+        }
+
+        series.messages_in.inc();
+
+        // The original message is only needed if it has to be routed to `on_error`
+        let original = if handle.options.on_error.is_empty() {
+            None
+        } else {
+            Some(message.clone())
+        };
+
+        let start = Instant::now();
+        let next_messages = handle
+            .block
+            .exec(message)
+            .instrument(debug_span!("block", block = block_idx))
+            .await;
+        series.latency.observe(start.elapsed());
+
+        let next_messages = match next_messages {
+            Ok(next_messages) => next_messages,
+            Err(err) => {
+                series.errors.inc();
+
+                match original {
+                    Some(original) => {
+                        warn!(
+                            block = block_idx,
+                            "Block failed, routing to on_error: {:#}", err
+                        );
+
+                        let error_message = original.into_error_message(&err, ("block", block_idx));
+
+                        return self
+                            .handle_message_with_connections(
+                                &handle.options.on_error,
+                                error_message,
+                                collector,
+                            )
+                            .await;
+                    }
+                    None => {
+                        error!(
+                            block = block_idx,
+                            "Block failed, dropping message: {:#}", err
+                        );
+
+                        return Ok(());
+                    }
+                }
+            }
+        };
 
-        let next_messages = handle.block.exec(message).await?;
+        series.messages_out.add(next_messages.len() as u64);
 
-        if next_messages.len() == 0 {
-            println!("Block {:#?} dropped all messages", block_idx);
+        if next_messages.is_empty() {
+            series.dropped.inc();
+
+            debug!(block = block_idx, "Block dropped all messages");
         } else if next_messages.len() > 1 {
-            println!(
-                "Block {:#?} created {:#?} new messages",
-                block_idx,
-                next_messages.len() - 1
+            debug!(
+                block = block_idx,
+                count = next_messages.len() - 1,
+                "Block created new messages"
             );
         }
 
@@ -108,64 +242,179 @@ Run with --ignore-cycles to ignore cycles
         return Ok(());
     }
 
+    /// Returns every cycle that doesn't pass a block with its own `max_hops`. These blocks are
+    /// loop-safe because messages can only pass them a limited number of times.
     fn get_cycles(self: &Self) -> Vec<Vec<usize>> {
-        return self
+        let edges: Vec<Vec<usize>> = self
             .blocks
             .iter()
-            .enumerate()
-            .filter_map(|(block_idx, _)| self.get_cycles_from_block(block_idx))
+            .map(|block| {
+                block
+                    .connections()
+                    .filter_map(|to| match to {
+                        Connection::Block(idx) => Some(*idx),
+                        Connection::Sink(_) => None,
+                    })
+                    .collect()
+            })
             .collect();
+
+        return find_cycles(&edges, |idx| self.blocks[idx].options.max_hops.is_some());
     }
 
-    fn get_cycles_from_block(self: &Self, block_idx: usize) -> Option<Vec<usize>> {
-        let mut path = vec![block_idx];
+    fn describe_cycle(self: &Self, cycle: &[usize]) -> String {
+        return cycle
+            .iter()
+            .map(|idx| self.blocks[*idx].describe(*idx))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+    }
+}
 
-        let block = self
-            .blocks
-            .get(block_idx)
-            .expect(&format!("Failed to get block with idx {}", block_idx));
+#[cfg(test)]
+mod tests {
+    use rosc::OscType;
+    use serde_json::json;
 
-        for to in &block.to {
-            match to {
-                Connection::Block(block_idx) => {
-                    if self.traverse_to_check_cycles(&mut path, *block_idx) {
-                        return Some(path);
-                    }
-                }
-                Connection::Sink(_) => {}
-            }
-        }
+    use super::*;
+    use crate::{block::BlockOptions, message::InternalMessageData};
 
-        return None;
+    /// A block that sends every message to itself and to sink 0
+    fn self_loop(max_hops: Option<u32>) -> Vec<BlockConfig> {
+        vec![BlockConfig::AddLeadingSlash {
+            to: vec![Connection::Block(0), Connection::Sink(0)],
+            options: BlockOptions {
+                max_hops,
+                ..Default::default()
+            },
+        }]
     }
 
-    fn traverse_to_check_cycles(self: &Self, path: &mut Vec<usize>, block_idx: usize) -> bool {
-        if path.contains(&block_idx) {
-            // The block was already visited -> cycle
-            return true;
-        } else {
-            // Add self
-            path.push(block_idx);
-
-            let block = self
-                .blocks
-                .get(block_idx)
-                .expect(&format!("Failed to get block with idx {}", block_idx));
-            for to in &block.to {
-                match to {
-                    Connection::Block(block_idx) => {
-                        if self.traverse_to_check_cycles(path, *block_idx) {
-                            return true;
-                        }
-                    }
-                    Connection::Sink(_) => {}
+    /// Sends a message into the loop and returns how many copies reached the sink
+    async fn run_loop(pipeline: &Pipeline) -> usize {
+        let message = InternalMessage {
+            source_connector_idx: 0,
+            topic: "/loop".to_string(),
+            data: InternalMessageData::String("data".to_string()),
+            dead_letter: false,
+            hops: 0,
+        };
+
+        let mut collector = vec![];
+        pipeline
+            .handle_message(0, message, &mut collector)
+            .await
+            .unwrap();
+
+        assert!(collector.iter().all(|(sink, _)| *sink == 0));
+        collector.len()
+    }
+
+    fn hop_limit_exceeded() -> u64 {
+        metrics()
+            .counter(Counter::HopLimitExceeded, &[("block", "0")])
+            .get()
+    }
+
+    #[test]
+    fn cycles_need_a_hop_limit() {
+        assert!(Pipeline::new(self_loop(None), false, None).is_err());
+        assert!(Pipeline::new(self_loop(None), false, Some(5)).is_err());
+        assert!(Pipeline::new(self_loop(Some(5)), false, None).is_ok());
+    }
+
+    /// The only test that exceeds the hop limit, so the counter isn't changed concurrently
+    #[tokio::test]
+    async fn drops_messages_at_hop_limit() {
+        let before = hop_limit_exceeded();
+
+        // The block limit makes the cycle loop-safe
+        let pipeline = Pipeline::new(self_loop(Some(3)), false, None).unwrap();
+        assert_eq!(run_loop(&pipeline).await, 3);
+        assert_eq!(hop_limit_exceeded(), before + 1);
+
+        // The block limit overrides the global one
+        let pipeline = Pipeline::new(self_loop(Some(2)), true, Some(5)).unwrap();
+        assert_eq!(run_loop(&pipeline).await, 2);
+
+        let pipeline = Pipeline::new(self_loop(None), true, Some(5)).unwrap();
+        assert_eq!(run_loop(&pipeline).await, 5);
+
+        let pipeline = Pipeline::new(self_loop(None), true, None).unwrap();
+        assert_eq!(run_loop(&pipeline).await, IGNORED_CYCLES_MAX_HOPS as usize);
+
+        assert_eq!(hop_limit_exceeded(), before + 4);
+    }
+
+    /// Block 0 fails for OSC bodies and sends them to `on_error`, block 1 adds a slash
+    fn failing_block(on_error: serde_json::Value) -> Pipeline {
+        let blocks = serde_json::from_value(json!([
+            {
+                "ConvertBody": {
+                    "config": "Binary",
+                    "to": [{ "Sink": 0 }],
+                    "on_error": on_error,
                 }
-            }
+            },
+            { "AddLeadingSlash": { "to": [{ "Sink": 1 }] } },
+        ]))
+        .unwrap();
 
-            // Remove self
-            path.pop();
+        Pipeline::new(blocks, false, None).unwrap()
+    }
 
-            return false;
+    fn osc_message() -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 0,
+            topic: "fader/1".to_string(),
+            data: InternalMessageData::OSC(vec![OscType::Int(1)]),
+            dead_letter: false,
+            hops: 0,
         }
     }
+
+    #[tokio::test]
+    async fn failed_messages_go_to_on_error() {
+        let pipeline = failing_block(json!([{ "Block": 1 }]));
+
+        let mut collector = vec![];
+        pipeline
+            .handle_message(0, osc_message(), &mut collector)
+            .await
+            .unwrap();
+
+        let [(sink, ref message)] = collector[..] else {
+            panic!("Expected one message, got {:?}", collector);
+        };
+        assert_eq!(sink, 1);
+        // The error message passed block 1 like any other message
+        assert_eq!(message.topic, "/fader/1");
+        assert_eq!(message.hops, 2);
+
+        let InternalMessageData::Json(ref body) = message.data else {
+            panic!("Expected a JSON body, got {:?}", message.data);
+        };
+        assert_eq!(
+            *body,
+            json!({
+                "error": "Can't convert OSC to binary",
+                "block": 0,
+                "topic": "fader/1",
+                "data": [1],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_messages_without_on_error_are_dropped() {
+        let pipeline = failing_block(json!([]));
+
+        let mut collector = vec![];
+        pipeline
+            .handle_message(0, osc_message(), &mut collector)
+            .await
+            .unwrap();
+
+        assert!(collector.is_empty());
+    }
 }
@@ -0,0 +1,385 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer, de::Error};
+
+use crate::{
+    block::{Block, BlockOptions, Connection},
+    connector::{ConnectorContext, ConnectorOptions, SinkRX},
+};
+
+static PLUGINS: LazyLock<Plugins> = LazyLock::new(Plugins::default);
+
+/// Returns the global plugin registry
+pub fn plugins() -> &'static Plugins {
+    &PLUGINS
+}
+
+/// Creates the blocks of a type that isn't built into postoffice
+pub trait BlockPlugin: Send + Sync {
+    /// Creates a block from the `config` of its entry in the config file, `null` if it has none
+    fn make_block(self: &Self, config: serde_json::Value) -> anyhow::Result<Box<dyn Block>>;
+}
+
+impl<F> BlockPlugin for F
+where
+    F: Fn(serde_json::Value) -> anyhow::Result<Box<dyn Block>> + Send + Sync,
+{
+    fn make_block(self: &Self, config: serde_json::Value) -> anyhow::Result<Box<dyn Block>> {
+        self(config)
+    }
+}
+
+/// Starts the connectors of a type that isn't built into postoffice
+#[async_trait]
+pub trait ConnectorPlugin: Send + Sync {
+    /// Runs a connector like the built-in `make_*_connector` functions do. A source sends its
+    /// messages to `ctx.source_tx`, a sink delivers the messages of `sink_rx`. Both report their
+    /// state through `ctx.lifecycle_tx` and hold on to `ctx.shutdown` until they stopped.
+    ///
+    /// The future should only complete once the connector stopped. An `Err` is handled by the
+    /// `supervision` policy of the connector, also after it was ready.
+    async fn make_connector(
+        self: &Self,
+        ctx: ConnectorContext,
+        config: serde_json::Value,
+        sink_rx: SinkRX,
+    ) -> anyhow::Result<()>;
+}
+
+/// Block and connector types by the name they are used with in the config file. Plugins have to
+/// be registered before the config is loaded, entries of an unknown type fail to deserialize.
+#[derive(Default)]
+pub struct Plugins {
+    blocks: RwLock<HashMap<String, Arc<dyn BlockPlugin>>>,
+    connectors: RwLock<HashMap<String, Arc<dyn ConnectorPlugin>>>,
+}
+
+impl Plugins {
+    /// Registers a block type. A plugin replaces the built-in type with the same name.
+    pub fn register_block(
+        self: &Self,
+        kind: &str,
+        plugin: impl BlockPlugin + 'static,
+    ) -> anyhow::Result<()> {
+        let mut blocks = self.blocks.write().expect("Plugins lock poisoned");
+
+        if blocks.contains_key(kind) {
+            return Err(anyhow::Error::msg(format!(
+                "Block type {:?} is already registered",
+                kind
+            )));
+        }
+
+        blocks.insert(kind.to_string(), Arc::new(plugin));
+
+        return Ok(());
+    }
+
+    /// Registers a connector type. A plugin replaces the built-in type with the same name.
+    pub fn register_connector(
+        self: &Self,
+        kind: &str,
+        plugin: impl ConnectorPlugin + 'static,
+    ) -> anyhow::Result<()> {
+        let mut connectors = self.connectors.write().expect("Plugins lock poisoned");
+
+        if connectors.contains_key(kind) {
+            return Err(anyhow::Error::msg(format!(
+                "Connector type {:?} is already registered",
+                kind
+            )));
+        }
+
+        connectors.insert(kind.to_string(), Arc::new(plugin));
+
+        return Ok(());
+    }
+
+    pub fn has_block(self: &Self, kind: &str) -> bool {
+        self.blocks
+            .read()
+            .expect("Plugins lock poisoned")
+            .contains_key(kind)
+    }
+
+    pub fn has_connector(self: &Self, kind: &str) -> bool {
+        self.connectors
+            .read()
+            .expect("Plugins lock poisoned")
+            .contains_key(kind)
+    }
+
+    pub fn block(self: &Self, kind: &str) -> anyhow::Result<Arc<dyn BlockPlugin>> {
+        self.blocks
+            .read()
+            .expect("Plugins lock poisoned")
+            .get(kind)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!("No plugin is registered for block type {:?}", kind))
+            })
+    }
+
+    pub fn connector(self: &Self, kind: &str) -> anyhow::Result<Arc<dyn ConnectorPlugin>> {
+        self.connectors
+            .read()
+            .expect("Plugins lock poisoned")
+            .get(kind)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::Error::msg(format!(
+                    "No plugin is registered for connector type {:?}",
+                    kind
+                ))
+            })
+    }
+}
+
+/// A block of a plugin type, e.g. `{ "MyBlock": { "to": [...], "config": ... } }`
+#[derive(Debug)]
+pub struct PluginBlockConfig {
+    pub kind: String,
+    pub to: Vec<Connection>,
+    pub config: serde_json::Value,
+    pub options: BlockOptions,
+}
+
+#[derive(Deserialize)]
+struct PluginBlockEntry {
+    to: Vec<Connection>,
+    #[serde(default)]
+    config: serde_json::Value,
+    #[serde(flatten)]
+    options: BlockOptions,
+}
+
+impl<'de> Deserialize<'de> for PluginBlockConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, entry) = single_entry::<D, PluginBlockEntry>(deserializer)?;
+
+        return Ok(PluginBlockConfig {
+            kind,
+            to: entry.to,
+            config: entry.config,
+            options: entry.options,
+        });
+    }
+}
+
+/// A connector of a plugin type. It is a source if it has `to`, like the built-in connectors.
+#[derive(Debug, Clone)]
+pub struct PluginConnectorConfig {
+    pub kind: String,
+    pub config: serde_json::Value,
+    pub to: Option<Vec<Connection>>,
+    pub options: ConnectorOptions,
+}
+
+#[derive(Deserialize)]
+struct PluginConnectorEntry {
+    #[serde(default)]
+    config: serde_json::Value,
+    to: Option<Vec<Connection>>,
+    #[serde(flatten)]
+    options: ConnectorOptions,
+}
+
+impl<'de> Deserialize<'de> for PluginConnectorConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (kind, entry) = single_entry::<D, PluginConnectorEntry>(deserializer)?;
+
+        return Ok(PluginConnectorConfig {
+            kind,
+            config: entry.config,
+            to: entry.to,
+            options: entry.options,
+        });
+    }
+}
+
+/// Returns the type of a block or connector entry, the only key of its object
+pub fn plugin_kind(value: &serde_json::Value) -> Option<&str> {
+    match value.as_object() {
+        Some(entry) if entry.len() == 1 => entry.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
+/// Reads an object with a single key, the type, like serde does for the built-in types
+fn single_entry<'de, D, T>(deserializer: D) -> Result<(String, T), D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let mut entries = BTreeMap::<String, T>::deserialize(deserializer)?;
+
+    if entries.len() != 1 {
+        return Err(D::Error::custom(format!(
+            "expected an object with a single type, got {} keys",
+            entries.len()
+        )));
+    }
+
+    return Ok(entries.pop_first().expect("entries has one element"));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{block::BlockConfig, connector::ConnectorConfig, message::InternalMessage};
+
+    struct Echo;
+
+    #[async_trait]
+    impl Block for Echo {
+        async fn exec(
+            self: &Self,
+            message: InternalMessage,
+        ) -> anyhow::Result<Vec<InternalMessage>> {
+            Ok(vec![message])
+        }
+    }
+
+    struct Idle;
+
+    #[async_trait]
+    impl ConnectorPlugin for Idle {
+        async fn make_connector(
+            self: &Self,
+            _ctx: ConnectorContext,
+            _config: serde_json::Value,
+            _sink_rx: SinkRX,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn register_echo(kind: &str) -> anyhow::Result<()> {
+        plugins().register_block(kind, |_| Ok(Box::new(Echo) as Box<dyn Block>))
+    }
+
+    #[test]
+    fn registers_block_plugins() {
+        register_echo("PluginTestBlock").unwrap();
+        assert!(plugins().has_block("PluginTestBlock"));
+        assert!(register_echo("PluginTestBlock").is_err());
+
+        let config: BlockConfig = serde_json::from_value(json!({
+            "PluginTestBlock": {
+                "to": [{ "Sink": 0 }],
+                "config": { "x": 1 },
+                "max_hops": 3,
+            }
+        }))
+        .unwrap();
+
+        let BlockConfig::Plugin(config) = config else {
+            panic!("Expected a plugin block, got {:?}", config);
+        };
+        assert_eq!(config.kind, "PluginTestBlock");
+        assert!(matches!(config.to[..], [Connection::Sink(0)]));
+        assert_eq!(config.config, json!({ "x": 1 }));
+        assert_eq!(config.options.max_hops, Some(3));
+
+        assert!(
+            plugins()
+                .block(&config.kind)
+                .unwrap()
+                .make_block(config.config)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn registers_connector_plugins() {
+        plugins()
+            .register_connector("PluginTestConnector", Idle)
+            .unwrap();
+        assert!(plugins().has_connector("PluginTestConnector"));
+        assert!(
+            plugins()
+                .register_connector("PluginTestConnector", Idle)
+                .is_err()
+        );
+
+        let source: ConnectorConfig = serde_json::from_value(json!({
+            "PluginTestConnector": { "to": [{ "Block": 0 }], "required": false }
+        }))
+        .unwrap();
+
+        let ConnectorConfig::Plugin(source) = source else {
+            panic!("Expected a plugin connector, got {:?}", source);
+        };
+        assert_eq!(source.kind, "PluginTestConnector");
+        assert!(matches!(source.to.as_deref(), Some([Connection::Block(0)])));
+        assert_eq!(source.config, serde_json::Value::Null);
+        assert!(!source.options.required);
+
+        // Without `to` it is a sink
+        let sink: ConnectorConfig = serde_json::from_value(json!({
+            "PluginTestConnector": { "config": "sink" }
+        }))
+        .unwrap();
+
+        let ConnectorConfig::Plugin(sink) = sink else {
+            panic!("Expected a plugin connector, got {:?}", sink);
+        };
+        assert!(sink.to.is_none());
+        assert_eq!(sink.config, json!("sink"));
+    }
+
+    #[test]
+    fn entries_need_a_single_type() {
+        let entry = json!({ "to": [] });
+
+        for value in [
+            json!({}),
+            json!({ "PluginTestA": entry, "PluginTestB": entry }),
+        ] {
+            let err = PluginBlockConfig::deserialize(value).unwrap_err();
+            assert!(err.to_string().contains("single type"), "{}", err);
+        }
+
+        let config = PluginBlockConfig::deserialize(json!({ "PluginTestA": entry })).unwrap();
+        assert_eq!(config.kind, "PluginTestA");
+        assert_eq!(config.config, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn plugins_replace_built_in_types() {
+        // No other test uses these built-in types, they are replaced for the whole process
+        register_echo("RemoveLeadingSlash").unwrap();
+        plugins().register_connector("OSCSend", Idle).unwrap();
+
+        let block: BlockConfig =
+            serde_json::from_value(json!({ "RemoveLeadingSlash": { "to": [] } })).unwrap();
+        assert!(
+            matches!(block, BlockConfig::Plugin(ref config) if config.kind == "RemoveLeadingSlash")
+        );
+
+        // The built-in OSCSend would need a host and port
+        let connector: ConnectorConfig =
+            serde_json::from_value(json!({ "OSCSend": { "config": null } })).unwrap();
+        assert!(
+            matches!(connector, ConnectorConfig::Plugin(ref config) if config.kind == "OSCSend")
+        );
+    }
+
+    #[test]
+    fn unknown_types_fail() {
+        let entry = json!({ "PluginTestUnknown": { "to": [] } });
+
+        assert!(serde_json::from_value::<BlockConfig>(entry.clone()).is_err());
+        assert!(serde_json::from_value::<ConnectorConfig>(entry).is_err());
+
+        let err = plugins().block("PluginTestUnknown").err().unwrap();
+        assert!(err.to_string().contains("PluginTestUnknown"));
+        assert!(plugins().connector("PluginTestUnknown").is_err());
+    }
+}
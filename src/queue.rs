@@ -0,0 +1,833 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::{
+    message::{InternalMessage, InternalMessageData},
+    metrics::{Counter, CounterSeries, Gauge, GaugeSeries, metrics},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QueueConfig {
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+fn default_capacity() -> usize {
+    32
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_capacity(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// What happens to a message that is sent to a full queue
+#[derive(Debug, Clone, Default, Deserialize)]
+pub enum OverflowPolicy {
+    /// Wait until there is space in the queue
+    #[default]
+    Block,
+    /// Drop the oldest message in the queue to make space
+    DropOldest,
+    /// Drop the message that is sent
+    DropNewest,
+    /// Append the message to the file at the given path until there is space in the queue
+    Spill(String),
+}
+
+/// Identifies a queue in logs and metrics
+#[derive(Debug, Clone)]
+pub struct QueueName {
+    pub queue: &'static str,
+    pub connector: Option<usize>,
+}
+
+impl QueueName {
+    fn labels(self: &Self) -> Vec<(&'static str, String)> {
+        let mut labels = vec![("queue", self.queue.to_string())];
+
+        if let Some(idx) = self.connector {
+            labels.push(("connector", idx.to_string()));
+        }
+
+        labels
+    }
+}
+
+/// The metrics of a queue, registered once instead of for every message
+struct QueueMetrics {
+    depth: GaugeSeries,
+    dropped: CounterSeries,
+    spilled: CounterSeries,
+}
+
+impl QueueMetrics {
+    fn new(name: &QueueName) -> Self {
+        let labels = name.labels();
+        let labels: Vec<(&'static str, &str)> = labels
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+
+        Self {
+            depth: metrics().gauge(Gauge::QueueDepth, &labels),
+            dropped: metrics().counter(Counter::QueueDropped, &labels),
+            spilled: metrics().counter(Counter::QueueSpilled, &labels),
+        }
+    }
+}
+
+/// Returned by `QueueTX::send` once the receiving side was closed, hands back the message
+#[derive(Debug)]
+pub struct QueueClosed(pub InternalMessage);
+
+struct State {
+    buffer: VecDeque<InternalMessage>,
+    /// Messages in the spill file, including those that are still being written
+    spilled: usize,
+    senders: usize,
+    receivers: usize,
+    closed: bool,
+    full: bool,
+}
+
+impl State {
+    fn len(self: &Self) -> usize {
+        self.buffer.len() + self.spilled
+    }
+
+    fn is_spilling(self: &Self) -> bool {
+        self.spilled > 0
+    }
+}
+
+struct Shared {
+    name: QueueName,
+    metrics: QueueMetrics,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    state: Mutex<State>,
+    /// Only used on the blocking thread pool, so file IO doesn't hold up the runtime. It is
+    /// locked before `state` if both are needed.
+    spill: Option<Mutex<SpillFile>>,
+    /// Notified when a message was added or the last sender was dropped
+    item: Notify,
+    /// Notified when a message was removed or the receiver was closed
+    space: Notify,
+}
+
+impl Shared {
+    fn lock(self: &Self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Queue lock poisoned")
+    }
+
+    fn spill_file(self: &Self) -> std::sync::MutexGuard<'_, SpillFile> {
+        self.spill
+            .as_ref()
+            .expect("Spill queue without spill file")
+            .lock()
+            .expect("Spill file lock poisoned")
+    }
+
+    /// Appends a message that `state.spilled` already counts to the spill file
+    fn spill(self: &Self, message: InternalMessage) {
+        let res = self.spill_file().push(&message);
+
+        let mut state = self.lock();
+
+        match res {
+            Ok(_) => self.metrics.spilled.inc(),
+            Err(e) => {
+                error!(
+                    queue = self.name.queue,
+                    connector = self.name.connector,
+                    "Failed to spill message, dropping it: {:#}",
+                    e
+                );
+                state.spilled -= 1;
+                self.metrics.dropped.inc();
+            }
+        }
+
+        self.report(&mut state);
+        self.item.notify_one();
+    }
+
+    /// Moves up to `capacity` spilled messages back into memory, oldest first. Returns how many
+    /// messages were removed from the spill file, including the ones that couldn't be read.
+    fn unspill(self: &Self) -> usize {
+        let mut spill = self.spill_file();
+        let before = spill.count;
+        let mut messages = vec![];
+
+        while messages.len() < self.capacity {
+            match spill.pop() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(e) => {
+                    error!(
+                        queue = self.name.queue,
+                        connector = self.name.connector,
+                        "Failed to read spilled message: {:#}",
+                        e
+                    );
+                    self.metrics.dropped.inc();
+                }
+            }
+        }
+
+        // Still holds the spill file, so the messages of the next call are queued after these
+        let removed = before - spill.count;
+        let mut state = self.lock();
+        state.spilled -= removed;
+        state.buffer.extend(messages);
+
+        return removed;
+    }
+
+    fn report(self: &Self, state: &mut State) {
+        let len = state.len();
+        self.metrics.depth.set(len as f64);
+
+        let full = len >= self.capacity;
+
+        if full && !state.full {
+            warn!(
+                queue = self.name.queue,
+                connector = self.name.connector,
+                depth = len,
+                policy = ?self.overflow,
+                "Queue is full"
+            );
+        } else if !full && state.full {
+            info!(
+                queue = self.name.queue,
+                connector = self.name.connector,
+                depth = len,
+                "Queue has space again"
+            );
+        }
+
+        state.full = full;
+    }
+}
+
+/// Creates a bounded queue that handles overflows according to `config.overflow`.
+///
+/// It behaves like a `tokio::sync::mpsc` channel: `recv` returns `None` once all senders are
+/// dropped and the queue is empty, or once the queue was closed and is empty. The queue is closed
+/// once every `QueueRX` was dropped.
+pub fn queue(name: QueueName, config: &QueueConfig) -> anyhow::Result<(QueueTX, QueueRX)> {
+    let spill = match config.overflow {
+        OverflowPolicy::Spill(ref path) => Some(SpillFile::open(PathBuf::from(path))?),
+        _ => None,
+    };
+
+    if let Some(ref spill) = spill
+        && spill.count > 0
+    {
+        info!(
+            queue = name.queue,
+            connector = name.connector,
+            count = spill.count,
+            "Recovered spilled messages"
+        );
+    }
+
+    let shared = Arc::new(Shared {
+        metrics: QueueMetrics::new(&name),
+        name,
+        capacity: config.capacity.max(1),
+        overflow: config.overflow.clone(),
+        state: Mutex::new(State {
+            buffer: VecDeque::new(),
+            spilled: spill.as_ref().map_or(0, |spill| spill.count),
+            senders: 1,
+            receivers: 1,
+            closed: false,
+            full: false,
+        }),
+        spill: spill.map(Mutex::new),
+        item: Notify::new(),
+        space: Notify::new(),
+    });
+
+    return Ok((
+        QueueTX {
+            shared: shared.clone(),
+        },
+        QueueRX { shared },
+    ));
+}
+
+pub struct QueueTX {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for QueueTX {
+    fn fmt(self: &Self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueTX")
+            .field("name", &self.shared.name)
+            .finish()
+    }
+}
+
+impl Clone for QueueTX {
+    fn clone(self: &Self) -> Self {
+        self.shared.lock().senders += 1;
+
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for QueueTX {
+    fn drop(self: &mut Self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+
+        if state.senders == 0 {
+            self.shared.item.notify_one();
+        }
+    }
+}
+
+impl QueueTX {
+    pub async fn send(self: &Self, message: InternalMessage) -> Result<(), QueueClosed> {
+        let shared = &self.shared;
+
+        loop {
+            // Created before the check, so no wakeup between the check and the wait is lost
+            let space = shared.space.notified();
+
+            let spill = {
+                let mut state = shared.lock();
+
+                if state.closed {
+                    return Err(QueueClosed(message));
+                }
+
+                if !state.is_spilling() && state.buffer.len() < shared.capacity {
+                    state.buffer.push_back(message);
+                    shared.report(&mut state);
+                    shared.item.notify_one();
+                    return Ok(());
+                }
+
+                match shared.overflow {
+                    OverflowPolicy::Block => false,
+                    OverflowPolicy::DropNewest => {
+                        shared.metrics.dropped.inc();
+                        return Ok(());
+                    }
+                    OverflowPolicy::DropOldest => {
+                        state.buffer.pop_front();
+                        state.buffer.push_back(message);
+                        shared.metrics.dropped.inc();
+                        shared.item.notify_one();
+                        return Ok(());
+                    }
+                    OverflowPolicy::Spill(_) => {
+                        // Counted right away, so later messages are spilled after this one
+                        state.spilled += 1;
+                        true
+                    }
+                }
+            };
+
+            if spill {
+                // The message is spilled even if `send` is cancelled meanwhile
+                let shared = shared.clone();
+                tokio::task::spawn_blocking(move || shared.spill(message))
+                    .await
+                    .expect("Failed to spill message");
+                return Ok(());
+            }
+
+            space.await;
+        }
+    }
+}
+
+pub struct QueueRX {
+    shared: Arc<Shared>,
+}
+
+impl QueueRX {
+    pub async fn recv(self: &mut Self) -> Option<InternalMessage> {
+        let shared = &self.shared;
+
+        loop {
+            let item = shared.item.notified();
+
+            let spilling = {
+                let mut state = shared.lock();
+
+                // Messages in memory are older than the spilled ones
+                if let Some(message) = state.buffer.pop_front() {
+                    shared.report(&mut state);
+                    shared.space.notify_waiters();
+                    return Some(message);
+                }
+
+                if !state.is_spilling() && (state.closed || state.senders == 0) {
+                    return None;
+                }
+
+                state.is_spilling()
+            };
+
+            if spilling {
+                // The messages are queued even if `recv` is cancelled while they are read
+                let shared = shared.clone();
+                let removed = tokio::task::spawn_blocking(move || shared.unspill())
+                    .await
+                    .expect("Failed to read spilled messages");
+
+                // Otherwise the spilled messages are still being written
+                if removed > 0 {
+                    continue;
+                }
+            }
+
+            item.await;
+        }
+    }
+
+    /// Rejects every new message. Messages that are already queued can still be received.
+    pub fn close(self: &mut Self) {
+        self.shared.lock().closed = true;
+        self.shared.space.notify_waiters();
+    }
+}
+
+/// Keeps the queue open, e.g. while a sink restarts. Only one receiver should call `recv`.
+impl Clone for QueueRX {
+    fn clone(self: &Self) -> Self {
+        self.shared.lock().receivers += 1;
+
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for QueueRX {
+    fn drop(self: &mut Self) {
+        let mut state = self.shared.lock();
+        state.receivers -= 1;
+
+        if state.receivers == 0 {
+            state.closed = true;
+            self.shared.space.notify_waiters();
+
+            // Spilled messages stay in the spill file for the next start
+            if !state.buffer.is_empty() {
+                warn!(
+                    queue = self.shared.name.queue,
+                    connector = self.shared.name.connector,
+                    count = state.buffer.len(),
+                    "Queue closed, dropping queued messages"
+                );
+            }
+        }
+    }
+}
+
+/// Messages that don't fit into the queue, stored as length prefixed records
+struct SpillFile {
+    file: File,
+    read_pos: u64,
+    count: usize,
+}
+
+impl SpillFile {
+    fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("Failed to open spill file {:?}", path))?;
+
+        // Count the records that are left over from a previous run
+        let mut count = 0;
+        let mut pos = 0;
+        let total = file.metadata()?.len();
+
+        while pos + 4 <= total {
+            file.seek(SeekFrom::Start(pos))?;
+
+            let mut len = [0u8; 4];
+            file.read_exact(&mut len)?;
+
+            let end = pos + 4 + u32::from_le_bytes(len) as u64;
+            if end > total {
+                break;
+            }
+
+            pos = end;
+            count += 1;
+        }
+
+        // A crash while a message was spilled leaves an incomplete record at the end
+        if pos < total {
+            warn!(
+                path = ?path,
+                bytes = total - pos,
+                "Removing incomplete record from spill file"
+            );
+            file.set_len(pos)?;
+        }
+
+        Ok(Self {
+            file,
+            read_pos: 0,
+            count,
+        })
+    }
+
+    fn push(self: &mut Self, message: &InternalMessage) -> anyhow::Result<()> {
+        let record = encode_message(message)?;
+
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.file.write_all(&record)?;
+        self.file.flush()?;
+
+        self.count += 1;
+
+        Ok(())
+    }
+
+    fn pop(self: &mut Self) -> anyhow::Result<Option<InternalMessage>> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+
+        let record = match self.read_record() {
+            Ok(record) => record,
+            Err(e) => {
+                // The position of the next record is unknown, so every remaining record is lost.
+                // Retrying would fail on the same record forever.
+                let count = self.count;
+                self.count = 0;
+                self.read_pos = 0;
+                self.file.set_len(0)?;
+
+                return Err(e.context(format!("Dropped {} spilled messages", count)));
+            }
+        };
+
+        self.count -= 1;
+
+        if self.count == 0 {
+            // Everything was read, so the file can start over
+            self.file.set_len(0)?;
+            self.read_pos = 0;
+        }
+
+        return decode_message(&record)
+            .context("Dropped a spilled message")
+            .map(Some);
+    }
+
+    fn read_record(self: &mut Self) -> anyhow::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(self.read_pos))?;
+
+        let mut len = [0u8; 4];
+        self.file.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+
+        let mut record = vec![0u8; len];
+        self.file.read_exact(&mut record)?;
+
+        self.read_pos += 4 + len as u64;
+
+        return Ok(record);
+    }
+}
+
+fn encode_message(message: &InternalMessage) -> anyhow::Result<Vec<u8>> {
+    let (tag, data) = message.data.encode()?;
+
+    let topic = message.topic.as_bytes();
+
+    let mut record = Vec::with_capacity(18 + topic.len() + data.len());
+    record.push(tag);
+    record.push(message.dead_letter as u8);
+    record.extend_from_slice(&(message.source_connector_idx as u64).to_le_bytes());
+    record.extend_from_slice(&message.hops.to_le_bytes());
+    record.extend_from_slice(&(topic.len() as u32).to_le_bytes());
+    record.extend_from_slice(topic);
+    record.extend_from_slice(&data);
+
+    Ok(record)
+}
+
+fn decode_message(record: &[u8]) -> anyhow::Result<InternalMessage> {
+    let invalid = || anyhow::Error::msg("Invalid spill record");
+
+    let tag = *record.first().ok_or_else(invalid)?;
+    let dead_letter = *record.get(1).ok_or_else(invalid)? != 0;
+    let source_connector_idx =
+        u64::from_le_bytes(record.get(2..10).ok_or_else(invalid)?.try_into()?) as usize;
+    let hops = u32::from_le_bytes(record.get(10..14).ok_or_else(invalid)?.try_into()?);
+    let topic_len =
+        u32::from_le_bytes(record.get(14..18).ok_or_else(invalid)?.try_into()?) as usize;
+    let topic = String::from_utf8(record.get(18..18 + topic_len).ok_or_else(invalid)?.to_vec())?;
+    let data = record.get(18 + topic_len..).ok_or_else(invalid)?;
+
+    let data = InternalMessageData::decode(tag, data)?;
+
+    Ok(InternalMessage {
+        source_connector_idx,
+        topic,
+        data,
+        dead_letter,
+        hops,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn message(topic: &str) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: 0,
+            topic: topic.to_string(),
+            data: InternalMessageData::String("data".to_string()),
+            dead_letter: false,
+            hops: 0,
+        }
+    }
+
+    fn config(capacity: usize, overflow: OverflowPolicy) -> QueueConfig {
+        QueueConfig { capacity, overflow }
+    }
+
+    fn test_queue(config: QueueConfig) -> (QueueTX, QueueRX) {
+        let name = QueueName {
+            queue: "test",
+            connector: None,
+        };
+
+        queue(name, &config).expect("Failed to create queue")
+    }
+
+    /// A spill file in the temp dir that doesn't exist yet
+    fn spill_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "postoffice-test-{}-{}.spill",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn spill(path: &Path) -> OverflowPolicy {
+        OverflowPolicy::Spill(path.to_string_lossy().to_string())
+    }
+
+    async fn topics(rx: &mut QueueRX) -> Vec<String> {
+        let mut topics = vec![];
+        while let Some(message) = rx.recv().await {
+            topics.push(message.topic);
+        }
+        topics
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_space() {
+        let (tx, mut rx) = test_queue(config(2, OverflowPolicy::Block));
+
+        tx.send(message("/a")).await.unwrap();
+        tx.send(message("/b")).await.unwrap();
+
+        let full = tokio::time::timeout(Duration::from_millis(50), tx.send(message("/c"))).await;
+        assert!(full.is_err(), "send should wait while the queue is full");
+
+        assert_eq!(rx.recv().await.unwrap().topic, "/a");
+        tx.send(message("/c")).await.unwrap();
+
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/b", "/c"]);
+    }
+
+    #[tokio::test]
+    async fn clone_keeps_queue_open() {
+        let (tx, rx) = test_queue(config(2, OverflowPolicy::Block));
+        let mut clone = rx.clone();
+
+        drop(rx);
+        tx.send(message("/a")).await.unwrap();
+        assert_eq!(clone.recv().await.unwrap().topic, "/a");
+
+        drop(clone);
+        assert!(tx.send(message("/b")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() {
+        let (tx, mut rx) = test_queue(config(2, OverflowPolicy::DropOldest));
+
+        for topic in ["/a", "/b", "/c"] {
+            tx.send(message(topic)).await.unwrap();
+        }
+
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/b", "/c"]);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_oldest() {
+        let (tx, mut rx) = test_queue(config(2, OverflowPolicy::DropNewest));
+
+        for topic in ["/a", "/b", "/c"] {
+            tx.send(message(topic)).await.unwrap();
+        }
+
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/a", "/b"]);
+    }
+
+    #[tokio::test]
+    async fn spill_keeps_order() {
+        let path = spill_path("round-trip");
+        let (tx, mut rx) = test_queue(config(1, spill(&path)));
+
+        for topic in ["/a", "/b", "/c", "/d"] {
+            tx.send(message(topic)).await.unwrap();
+        }
+
+        assert_eq!(rx.recv().await.unwrap().topic, "/a");
+        tx.send(message("/e")).await.unwrap();
+
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/b", "/c", "/d", "/e"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_recv_keeps_spilled_messages() {
+        let path = spill_path("cancelled");
+        let (tx, mut rx) = test_queue(config(1, spill(&path)));
+
+        for topic in ["/a", "/b", "/c"] {
+            tx.send(message(topic)).await.unwrap();
+        }
+
+        let mut received = vec![rx.recv().await.unwrap().topic];
+
+        // Gives up while the spilled messages are read
+        if let Ok(Some(message)) = tokio::time::timeout(Duration::ZERO, rx.recv()).await {
+            received.push(message.topic);
+        }
+
+        drop(tx);
+        received.extend(topics(&mut rx).await);
+        assert_eq!(received, ["/a", "/b", "/c"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spill_recovers_after_restart() {
+        let path = spill_path("restart");
+
+        {
+            let (tx, _rx) = test_queue(config(1, spill(&path)));
+
+            for topic in ["/a", "/b", "/c"] {
+                tx.send(message(topic)).await.unwrap();
+            }
+        }
+
+        // Only the message in memory is lost
+        let (tx, mut rx) = test_queue(config(1, spill(&path)));
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/b", "/c"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spill_removes_truncated_record() {
+        let path = spill_path("truncated");
+
+        let mut spill_file = SpillFile::open(path.clone()).unwrap();
+        spill_file.push(&message("/a")).unwrap();
+        drop(spill_file);
+
+        // A record that claims 100 bytes but only has 2
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[100, 0, 0, 0, b'a', b'b']).unwrap();
+        drop(file);
+
+        let (tx, mut rx) = test_queue(config(1, spill(&path)));
+        drop(tx);
+        assert_eq!(topics(&mut rx).await, ["/a"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn spill_only_has_truncated_record() {
+        let path = spill_path("only-truncated");
+        std::fs::write(&path, [100, 0, 0, 0, b'a', b'b']).unwrap();
+
+        let (tx, mut rx) = test_queue(config(1, spill(&path)));
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn spill_drops_rest_after_read_error() {
+        let path = spill_path("read-error");
+
+        let mut spill_file = SpillFile::open(path.clone()).unwrap();
+        spill_file.push(&message("/a")).unwrap();
+        spill_file.push(&message("/b")).unwrap();
+
+        // The file shrinks behind the back of the queue
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(3)
+            .unwrap();
+
+        assert!(spill_file.pop().is_err());
+        assert_eq!(spill_file.count, 0);
+        assert!(spill_file.pop().unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,753 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tracing::{Instrument, debug, error, info_span, trace, warn};
+
+use crate::{
+    block::Connection,
+    concurrency::{Admission, LimitReached, Limits, SaturationPolicy},
+    connector::ConnectorHandles,
+    lifecycle::ConnectorStates,
+    message::InternalMessage,
+    metrics::{Counter, CounterSeries, metrics},
+    pipeline::Pipeline,
+    queue::QueueClosed,
+};
+
+/// Which incoming messages are processed one after another
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub enum Ordering {
+    /// Every message is processed as soon as it arrives
+    #[default]
+    None,
+    /// Messages from the same source connector are processed in the order they arrived
+    PerSource,
+    /// Messages with the same topic are processed in the order they arrived
+    PerTopic,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum OrderingKey {
+    Source(usize),
+    Topic(String),
+}
+
+/// The ordering keys that a task is running for
+type Lanes = Arc<Mutex<HashSet<OrderingKey>>>;
+
+/// Messages from sources that wait for a concurrency limit or for the task of the previous
+/// message with the same ordering key. Holds at most `capacity` messages.
+pub struct Waiting {
+    messages: VecDeque<InternalMessage>,
+    capacity: usize,
+}
+
+impl Waiting {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn is_full(self: &Self) -> bool {
+        self.messages.len() >= self.capacity
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// Runs messages through the pipeline and hands the results to the sinks
+#[derive(Clone)]
+pub struct Router {
+    pub pipeline: Arc<Pipeline>,
+    pub connector_handles: ConnectorHandles,
+    pub connector_states: ConnectorStates,
+    /// The dead letter connections of every connector by its index
+    pub dead_letter: Arc<Vec<Option<Vec<Connection>>>>,
+    pub ordering: Ordering,
+    pub limits: Limits,
+    lanes: Lanes,
+    /// The metrics of every source by its connector index
+    sources: Arc<Vec<SourceMetrics>>,
+}
+
+/// The metrics of a source, registered once instead of for every message
+struct SourceMetrics {
+    messages: CounterSeries,
+    concurrency_dropped: CounterSeries,
+}
+
+impl SourceMetrics {
+    fn new(idx: usize) -> Self {
+        let label = idx.to_string();
+        let labels = [("connector", label.as_str())];
+
+        Self {
+            messages: metrics().counter(Counter::SourceMessages, &labels),
+            concurrency_dropped: metrics().counter(Counter::ConcurrencyDropped, &labels),
+        }
+    }
+}
+
+impl Router {
+    pub fn new(
+        pipeline: Arc<Pipeline>,
+        connector_handles: ConnectorHandles,
+        connector_states: ConnectorStates,
+        dead_letter: Arc<Vec<Option<Vec<Connection>>>>,
+        ordering: Ordering,
+        limits: Limits,
+    ) -> Self {
+        // There is a dead letter entry for every connector
+        let sources = (0..dead_letter.len()).map(SourceMetrics::new).collect();
+
+        Self {
+            pipeline,
+            connector_handles,
+            connector_states,
+            dead_letter,
+            ordering,
+            limits,
+            lanes: Arc::default(),
+            sources: Arc::new(sources),
+        }
+    }
+
+    /// Adds a message from a source connector to `waiting` and spawns the tasks of every waiting
+    /// message that can run now
+    pub fn accept_incoming(
+        self: &Self,
+        tasks: &mut JoinSet<()>,
+        waiting: &mut Waiting,
+        incoming: InternalMessage,
+    ) {
+        waiting.messages.push_back(incoming);
+        self.spawn_waiting(tasks, waiting);
+    }
+
+    /// Spawns the tasks of the waiting messages whose ordering key is free and whose source is
+    /// below its concurrency limit, in the order they arrived. Depending on `saturated`, messages
+    /// that reach a limit are dropped or keep waiting.
+    ///
+    /// Waiting messages only become ready once a task finished, so call it after every task.
+    pub fn spawn_waiting(self: &Self, tasks: &mut JoinSet<()>, waiting: &mut Waiting) {
+        // A message must not overtake a waiting message with the same source or key
+        let mut blocked_sources = HashSet::new();
+        let mut blocked_keys = HashSet::new();
+        let mut rest = VecDeque::new();
+
+        while let Some(incoming) = waiting.messages.pop_front() {
+            let idx = incoming.source_connector_idx;
+            let key = self.ordering_key(&incoming);
+
+            if blocked_sources.contains(&idx)
+                || key.as_ref().is_some_and(|k| blocked_keys.contains(k))
+            {
+                rest.push_back(incoming);
+                continue;
+            }
+
+            let lane = match key.clone() {
+                Some(key) => match self.try_enter_lane(key.clone()) {
+                    Some(lane) => Some(lane),
+                    None => {
+                        blocked_keys.insert(key);
+                        rest.push_back(incoming);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let limit = match self.limits.try_admit(idx) {
+                Ok(admission) => {
+                    self.spawn_incoming(tasks, incoming, admission, lane);
+                    continue;
+                }
+                Err(limit) => limit,
+            };
+
+            if self.limits.saturated == SaturationPolicy::Drop {
+                if let Some(source) = self.sources.get(idx) {
+                    source.concurrency_dropped.inc();
+                }
+                debug!(connector = idx, topic = %incoming.topic, "Concurrency limit reached, dropping message");
+                continue;
+            }
+
+            debug!(connector = idx, topic = %incoming.topic, "Concurrency limit reached, waiting");
+
+            if let Some(key) = key {
+                blocked_keys.insert(key);
+            }
+            rest.push_back(incoming);
+
+            match limit {
+                LimitReached::Global => {
+                    rest.append(&mut waiting.messages);
+                    break;
+                }
+                LimitReached::Source => {
+                    blocked_sources.insert(idx);
+                }
+            }
+        }
+
+        waiting.messages = rest;
+    }
+
+    /// Spawns a task that routes a message from a source connector. The task holds `admission`
+    /// and the `lane` of its ordering key until it finished.
+    fn spawn_incoming(
+        self: &Self,
+        tasks: &mut JoinSet<()>,
+        incoming: InternalMessage,
+        admission: Admission,
+        lane: Option<LaneGuard>,
+    ) {
+        if let Some(source) = self.sources.get(incoming.source_connector_idx) {
+            source.messages.inc();
+        }
+
+        let span = info_span!(
+            "message",
+            connector = incoming.source_connector_idx,
+            topic = %incoming.topic
+        );
+
+        span.in_scope(|| {
+            debug!("Incoming message");
+            trace!(message = ?incoming, "Incoming message content");
+        });
+
+        let router = self.clone();
+
+        tasks.spawn(
+            async move {
+                let _admission = admission;
+                let _lane = lane;
+
+                let Some(handle) = router.connector_handles.get(incoming.source_connector_idx)
+                else {
+                    warn!("Source not running, dropping message");
+                    return;
+                };
+
+                router.route(&handle.to, incoming).await;
+            }
+            .instrument(span),
+        );
+    }
+
+    fn ordering_key(self: &Self, incoming: &InternalMessage) -> Option<OrderingKey> {
+        match self.ordering {
+            Ordering::None => None,
+            Ordering::PerSource => Some(OrderingKey::Source(incoming.source_connector_idx)),
+            Ordering::PerTopic => Some(OrderingKey::Topic(incoming.topic.clone())),
+        }
+    }
+
+    /// Returns `None` if a task is still running for `key`
+    fn try_enter_lane(self: &Self, key: OrderingKey) -> Option<LaneGuard> {
+        let mut lanes = self.lanes.lock().expect("Router lock poisoned");
+
+        if !lanes.insert(key.clone()) {
+            return None;
+        }
+
+        Some(LaneGuard {
+            key,
+            lanes: self.lanes.clone(),
+        })
+    }
+
+    /// Spawns a task that routes a message that the sink with index `sink_idx` failed to deliver
+    pub fn spawn_dead_letter(
+        self: &Self,
+        tasks: &mut JoinSet<()>,
+        sink_idx: usize,
+        message: InternalMessage,
+    ) {
+        let span = info_span!("dead_letter", sink = sink_idx, topic = %message.topic);
+
+        let Some((to, message)) = span.in_scope(|| self.dead_letter(sink_idx, message)) else {
+            return;
+        };
+
+        let router = self.clone();
+
+        tasks.spawn(async move { router.route(&to, message).await }.instrument(span));
+    }
+
+    /// Returns the dead letter connections of a sink and marks the message as a dead letter.
+    /// Logs and returns `None` if the message has to be dropped.
+    fn dead_letter(
+        self: &Self,
+        sink_idx: usize,
+        mut message: InternalMessage,
+    ) -> Option<(Vec<Connection>, InternalMessage)> {
+        if message.dead_letter {
+            error!(message = ?message, "Failed to deliver dead letter, dropping message");
+            return None;
+        }
+
+        let Some(Some(to)) = self.dead_letter.get(sink_idx).cloned() else {
+            warn!(message = ?message, "No dead letter connection, dropping message");
+            return None;
+        };
+
+        message.dead_letter = true;
+
+        return Some((to, message));
+    }
+
+    async fn route(self: &Self, to: &Vec<Connection>, message: InternalMessage) {
+        let mut collector: Vec<(usize, InternalMessage)> = vec![];
+
+        if let Err(e) = self
+            .pipeline
+            .handle_message_with_connections(to, message, &mut collector)
+            .await
+        {
+            error!("Failed to handle message: {:#}", e);
+            return;
+        }
+
+        debug!(count = collector.len(), "Collected messages");
+        trace!(messages = ?collector, "Collected messages content");
+
+        for (sink_idx, message) in collector {
+            let Some(handle) = self.connector_handles.get(sink_idx) else {
+                warn!(sink = sink_idx, "Sink doesn't exist, dropping message");
+                continue;
+            };
+
+            if !self.connector_states.accepts(sink_idx) {
+                debug!(sink = sink_idx, "Sink not ready, dropping message");
+                continue;
+            }
+
+            // The sink stopped reading, e.g. the `Output` of an embedded sink was dropped or the
+            // connector is a source only
+            if let Err(QueueClosed(message)) = handle.sink_tx.send(message).await {
+                let span = info_span!("dead_letter", sink = sink_idx, topic = %message.topic);
+
+                span.in_scope(|| warn!(sink = sink_idx, "Sink queue is closed"));
+
+                let err = anyhow::Error::msg("Sink queue is closed");
+                let message = message.into_error_message(&err, ("connector", sink_idx));
+
+                if let Some((to, message)) = span.in_scope(|| self.dead_letter(sink_idx, message)) {
+                    Box::pin(self.route(&to, message)).instrument(span).await;
+                }
+            }
+        }
+    }
+}
+
+/// Lets the next message with the same key run once it is dropped
+struct LaneGuard {
+    key: OrderingKey,
+    lanes: Lanes,
+}
+
+impl Drop for LaneGuard {
+    fn drop(self: &mut Self) {
+        self.lanes
+            .lock()
+            .expect("Router lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::{
+        block::{Block, BlockConfig, BlockOptions},
+        concurrency::ConcurrencyConfig,
+        connector::{ConnectorHandle, ConnectorOptions},
+        lifecycle::LifeCycleHandler,
+        message::InternalMessageData,
+        plugin::{PluginBlockConfig, plugins},
+        queue::{QueueConfig, QueueName, queue},
+    };
+
+    /// Records the source and topic of every message it starts and holds it until a permit is
+    /// added
+    #[derive(Clone)]
+    struct Gate {
+        permits: Arc<Semaphore>,
+        started: Arc<Mutex<Vec<(usize, String)>>>,
+    }
+
+    #[async_trait]
+    impl Block for Gate {
+        async fn exec(
+            self: &Self,
+            message: InternalMessage,
+        ) -> anyhow::Result<Vec<InternalMessage>> {
+            self.started
+                .lock()
+                .unwrap()
+                .push((message.source_connector_idx, message.topic));
+            self.permits.acquire().await?.forget();
+
+            Ok(vec![])
+        }
+    }
+
+    impl Gate {
+        fn started(self: &Self) -> Vec<String> {
+            self.started_from()
+                .into_iter()
+                .map(|(_, topic)| topic)
+                .collect()
+        }
+
+        fn started_from(self: &Self) -> Vec<(usize, String)> {
+            self.started.lock().unwrap().clone()
+        }
+    }
+
+    /// A router whose two sources send their messages to a [`Gate`] registered as `kind`
+    fn router(kind: &str, ordering: Ordering, concurrency: ConcurrencyConfig) -> (Router, Gate) {
+        let gate = Gate {
+            permits: Arc::new(Semaphore::new(0)),
+            started: Arc::default(),
+        };
+        let g = gate.clone();
+
+        plugins()
+            .register_block(kind, move |_| Ok(Box::new(g.clone()) as Box<dyn Block>))
+            .expect("Failed to register block");
+
+        let pipeline = Pipeline::new(
+            vec![BlockConfig::Plugin(PluginBlockConfig {
+                kind: kind.to_string(),
+                to: vec![],
+                config: serde_json::Value::Null,
+                options: BlockOptions::default(),
+            })],
+            false,
+            None,
+        )
+        .expect("Failed to create pipeline");
+
+        let handles = ConnectorHandles::new(2);
+        for idx in 0..2 {
+            let (sink_tx, _) = queue(
+                QueueName {
+                    queue: "sink",
+                    connector: Some(idx),
+                },
+                &QueueConfig::default(),
+            )
+            .unwrap();
+
+            handles.set(
+                idx,
+                ConnectorHandle {
+                    to: vec![Connection::Block(0)],
+                    sink_tx,
+                },
+            );
+        }
+
+        let states = LifeCycleHandler::start(vec![ConnectorOptions::default(); 2]).states;
+
+        let router = Router::new(
+            Arc::new(pipeline),
+            handles,
+            states,
+            Arc::new(vec![None, None]),
+            ordering,
+            Limits::new(&concurrency, vec![None, None]),
+        );
+
+        (router, gate)
+    }
+
+    fn message(source: usize, topic: &str) -> InternalMessage {
+        InternalMessage {
+            source_connector_idx: source,
+            topic: topic.to_string(),
+            data: InternalMessageData::String("data".to_string()),
+            dead_letter: false,
+            hops: 0,
+        }
+    }
+
+    fn concurrency(
+        max_tasks: Option<usize>,
+        per_source: Option<usize>,
+        saturated: SaturationPolicy,
+    ) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            max_tasks,
+            max_tasks_per_source: per_source,
+            saturated,
+        }
+    }
+
+    /// Lets the spawned tasks run until they wait for the gate
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    /// Lets one task through the gate and spawns the waiting messages once it finished
+    async fn release_one(
+        router: &Router,
+        gate: &Gate,
+        tasks: &mut JoinSet<()>,
+        waiting: &mut Waiting,
+    ) {
+        gate.permits.add_permits(1);
+        tasks.join_next().await.unwrap().unwrap();
+        router.spawn_waiting(tasks, waiting);
+        settle().await;
+    }
+
+    fn accept(
+        router: &Router,
+        tasks: &mut JoinSet<()>,
+        waiting: &mut Waiting,
+        messages: &[(usize, &str)],
+    ) {
+        for (source, topic) in messages {
+            router.accept_incoming(tasks, waiting, message(*source, topic));
+        }
+    }
+
+    #[tokio::test]
+    async fn source_limit_doesnt_hold_up_other_sources() {
+        let (router, gate) = router(
+            "RouterTestSourceLimit",
+            Ordering::None,
+            concurrency(None, Some(1), SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "a1"), (0, "a2"), (1, "b1"), (1, "b2")],
+        );
+        settle().await;
+
+        assert_eq!(gate.started(), ["a1", "b1"]);
+
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+
+        assert_eq!(gate.started(), ["a1", "b1", "a2", "b2"]);
+        assert!(waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn global_limit_waits_in_arrival_order() {
+        let (router, gate) = router(
+            "RouterTestGlobalLimit",
+            Ordering::None,
+            concurrency(Some(1), None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "a1"), (1, "b1"), (0, "a2")],
+        );
+        settle().await;
+
+        assert_eq!(gate.started(), ["a1"]);
+
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+
+        assert_eq!(gate.started(), ["a1", "b1", "a2"]);
+        assert!(waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_policy_drops_at_limit() {
+        let (router, gate) = router(
+            "RouterTestDrop",
+            Ordering::None,
+            concurrency(Some(2), Some(1), SaturationPolicy::Drop),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "a1"), (0, "a2"), (1, "b1"), (1, "b2")],
+        );
+        settle().await;
+
+        // `a2` reached the source limit, `b2` the global one
+        assert_eq!(gate.started(), ["a1", "b1"]);
+        assert!(waiting.is_empty());
+        assert_eq!(tasks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn lane_waiters_dont_hold_permits() {
+        let (router, gate) = router(
+            "RouterTestLanePermits",
+            Ordering::PerTopic,
+            concurrency(Some(2), None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "/x"), (0, "/x"), (0, "/x"), (1, "/y")],
+        );
+        settle().await;
+
+        // The messages that wait for `/x` leave the second permit to `/y`
+        assert_eq!(gate.started(), ["/x", "/y"]);
+        assert_eq!(tasks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn full_waiting_list() {
+        let (router, gate) = router(
+            "RouterTestWaitingFull",
+            Ordering::None,
+            concurrency(Some(1), None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(2);
+
+        accept(&router, &mut tasks, &mut waiting, &[(0, "a1"), (0, "a2")]);
+        assert!(!waiting.is_full());
+
+        accept(&router, &mut tasks, &mut waiting, &[(1, "b1")]);
+        assert!(waiting.is_full());
+
+        settle().await;
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+
+        assert!(!waiting.is_full());
+    }
+
+    fn lanes(router: &Router) -> usize {
+        router.lanes.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn per_source_keeps_order() {
+        let (router, gate) = router(
+            "RouterTestPerSource",
+            Ordering::PerSource,
+            concurrency(None, None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "a1"), (0, "a2"), (0, "a3"), (1, "b1")],
+        );
+        settle().await;
+
+        // Different sources run at the same time
+        assert_eq!(gate.started(), ["a1", "b1"]);
+        assert_eq!(lanes(&router), 2);
+
+        for _ in 0..3 {
+            release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        }
+
+        assert_eq!(gate.started(), ["a1", "b1", "a2", "a3"]);
+
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        assert!(tasks.is_empty());
+        assert_eq!(lanes(&router), 0);
+    }
+
+    #[tokio::test]
+    async fn per_topic_keeps_order() {
+        let (router, gate) = router(
+            "RouterTestPerTopic",
+            Ordering::PerTopic,
+            concurrency(None, None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(
+            &router,
+            &mut tasks,
+            &mut waiting,
+            &[(0, "/x"), (1, "/x"), (1, "/y"), (0, "/x")],
+        );
+        settle().await;
+
+        // Different topics run at the same time, even from the same source
+        assert_eq!(
+            gate.started_from(),
+            [(0, "/x".to_string()), (1, "/y".to_string())]
+        );
+
+        for _ in 0..3 {
+            release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        }
+
+        let x: Vec<usize> = gate
+            .started_from()
+            .into_iter()
+            .filter(|(_, topic)| topic == "/x")
+            .map(|(source, _)| source)
+            .collect();
+        assert_eq!(x, [0, 1, 0]);
+
+        release_one(&router, &gate, &mut tasks, &mut waiting).await;
+        assert!(tasks.is_empty());
+        assert_eq!(lanes(&router), 0);
+    }
+
+    #[tokio::test]
+    async fn aborted_tasks_leave_their_lane() {
+        let (router, _gate) = router(
+            "RouterTestLaneAbort",
+            Ordering::PerTopic,
+            concurrency(None, None, SaturationPolicy::Wait),
+        );
+        let mut tasks = JoinSet::new();
+        let mut waiting = Waiting::new(32);
+
+        accept(&router, &mut tasks, &mut waiting, &[(0, "/x"), (0, "/y")]);
+        settle().await;
+        assert_eq!(lanes(&router), 2);
+
+        // Like the shutdown timeout does
+        tasks.shutdown().await;
+        assert_eq!(lanes(&router), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, watch};
+
+/// Coordinates a graceful shutdown between `main` and all connectors.
+///
+/// Every connector task holds a [`Shutdown`]. Once the shutdown is triggered, sources stop
+/// receiving new messages. Sinks keep running until their `sink_rx` is closed, so everything
+/// that is still in flight can be flushed. Because every [`Shutdown`] holds a `complete_tx`,
+/// [`ShutdownHandler::wait_complete`] returns as soon as all of them are dropped.
+pub struct ShutdownHandler {
+    notify_tx: Arc<watch::Sender<Option<i32>>>,
+    complete_tx: mpsc::Sender<()>,
+    complete_rx: mpsc::Receiver<()>,
+}
+
+impl ShutdownHandler {
+    pub fn new() -> Self {
+        let (notify_tx, _) = watch::channel::<Option<i32>>(None);
+        let (complete_tx, complete_rx) = mpsc::channel::<()>(1);
+
+        Self {
+            notify_tx: Arc::new(notify_tx),
+            complete_tx,
+            complete_rx,
+        }
+    }
+
+    pub fn subscribe(self: &Self) -> Shutdown {
+        Shutdown {
+            notify_tx: self.notify_tx.clone(),
+            notify_rx: self.notify_tx.subscribe(),
+            _complete_tx: self.complete_tx.clone(),
+        }
+    }
+
+    /// Waits until every [`Shutdown`] handed out by [`ShutdownHandler::subscribe`] is dropped.
+    pub async fn wait_complete(mut self: Self) {
+        drop(self.complete_tx);
+
+        // `recv` only returns `None` once all `complete_tx` halfs are dropped
+        let _ = self.complete_rx.recv().await;
+    }
+}
+
+impl Default for ShutdownHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    notify_tx: Arc<watch::Sender<Option<i32>>>,
+    notify_rx: watch::Receiver<Option<i32>>,
+    _complete_tx: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Triggers the shutdown. The first exit code wins.
+    pub fn trigger(self: &Self, exit_code: i32) {
+        self.notify_tx.send_if_modified(|state| {
+            if state.is_none() {
+                *state = Some(exit_code);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn is_shutdown(self: &Self) -> bool {
+        self.notify_rx.borrow().is_some()
+    }
+
+    pub fn exit_code(self: &Self) -> i32 {
+        self.notify_rx.borrow().unwrap_or(0)
+    }
+
+    /// Resolves once the shutdown was triggered. Resolves immediately if it already was.
+    pub async fn recv(self: &mut Self) {
+        // This can only fail if the sender was dropped, which can't happen because we hold it
+        let _ = self.notify_rx.wait_for(|state| state.is_some()).await;
+    }
+}
+
+/// Resolves once SIGINT or SIGTERM (on unix) is received.
+pub async fn wait_for_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    return Ok(());
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use postoffice::{Config, InternalMessageData, Postoffice, block::Connection};
+use tokio::sync::oneshot;
+
+/// Routes every message through an AddLeadingSlash block to sink 1
+const CONFIG: &str = r#"{
+    "connectors": [],
+    "blocks": [{ "AddLeadingSlash": { "to": [{ "Sink": 1 }] } }]
+}"#;
+
+#[tokio::test]
+async fn routes_from_input_to_output_until_stopped() {
+    let config: Config = serde_json::from_str(CONFIG).unwrap();
+
+    let mut builder = Postoffice::builder(config).shutdown_timeout(Duration::from_secs(1));
+    let input = builder.input(vec![Connection::Block(0)]);
+    let mut output = builder.output();
+    assert_eq!((input.idx, output.idx), (0, 1));
+
+    let postoffice = builder.build().unwrap();
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let running = tokio::spawn(postoffice.run_until(async {
+        stop_rx.await.ok();
+    }));
+
+    input
+        .send("fader/1", InternalMessageData::String("0.5".to_string()))
+        .await
+        .unwrap();
+
+    let message = tokio::time::timeout(Duration::from_secs(5), output.recv())
+        .await
+        .expect("No message received")
+        .unwrap();
+    assert_eq!(message.topic, "/fader/1");
+    assert_eq!(message.source_connector_idx, 0);
+    assert!(matches!(message.data, InternalMessageData::String(ref data) if data == "0.5"));
+
+    stop_tx.send(()).unwrap();
+
+    let exit_code = tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("run_until didn't return after stop")
+        .unwrap()
+        .unwrap();
+    assert_eq!(exit_code, 0);
+
+    assert!(output.recv().await.is_none());
+    assert!(
+        input
+            .send("fader/1", InternalMessageData::String("0.5".to_string()))
+            .await
+            .is_err()
+    );
+}